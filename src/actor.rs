@@ -2,7 +2,7 @@ use std::borrow::Borrow;
 use std::cell::RefCell;
 use std::collections::BTreeSet;
 
-use crate::config::Config;
+use crate::config::{Config, Fault};
 use crate::db::{Db, DbCache, DbStore};
 use crate::path::Path;
 use crate::planner::{Act, Op};
@@ -12,22 +12,52 @@ pub struct Actor<'a, T> {
     config: Config,
     crashed: bool,
     unlinks: BTreeSet<String>,
+    fault: Option<Fault>,
+    puts: Option<Vec<Option<T>>>,
 }
 
 impl<T> Actor<'_, T>
 where
     T: Clone,
 {
-    pub fn new(store: &RefCell<DbStore<T>>, config: Config) -> Actor<T> {
+    pub fn new(store: &RefCell<DbStore<T>>, config: Config) -> Actor<'_, T> {
         Actor {
             cache: DbCache::new(store),
             config,
             crashed: false,
             unlinks: BTreeSet::new(),
+            fault: None,
+            puts: None,
         }
     }
 
+    // Keeps what every update passed to put() returns from now on, in the
+    // order they run, so that a plan can be saved with the values its puts
+    // computed in place of the functions that computed them.
+    pub fn record_puts(&mut self) {
+        self.puts = Some(Vec::new());
+    }
+
+    pub fn take_puts(&mut self) -> Vec<Option<T>> {
+        self.puts.take().unwrap_or_default()
+    }
+
+    // A fault act is not sent anywhere, but applies its fault to everything
+    // the next act sends to the store: a dropped change is taken as written
+    // without reaching the store, and a duplicated one is sent again with the
+    // revs it was first sent with, as a client retrying after a lost response
+    // would.
     pub fn dispatch(&mut self, act: &Act<T>) {
+        if let Op::Fault(fault) = act.op {
+            self.fault = Some(fault);
+            return;
+        }
+        self.cache.inject(self.fault.take());
+        self.perform(act);
+        self.cache.inject(None);
+    }
+
+    fn perform(&mut self, act: &Act<T>) {
         match &act.op {
             Op::Get => {
                 self.get(&act.path);
@@ -47,6 +77,13 @@ where
             Op::Unlink(name) => {
                 self.unlink(&act.path, name);
             }
+            Op::Transaction(acts) => {
+                self.transaction(acts);
+            }
+            Op::WriteMany(writes) => {
+                self.write_many(writes);
+            }
+            Op::Fault(_) => {}
         }
     }
 
@@ -66,7 +103,12 @@ where
         F: Fn(Option<T>) -> Option<T>,
     {
         if !self.crashed {
-            if let Some(value) = update(self.get(path)) {
+            let value = update(self.get(path));
+
+            if let Some(puts) = &mut self.puts {
+                puts.push(value.clone());
+            }
+            if let Some(value) = value {
                 self.write(path, Db::Doc(value));
             }
         }
@@ -93,6 +135,43 @@ where
         }
     }
 
+    // The acts only stage their writes in the cache, which sends them all
+    // with one write_many() at the end, so either every write is applied or,
+    // if any key has changed since the client read it, none are. A transaction
+    // built inside another is part of the outer one.
+    pub fn transaction(&mut self, acts: &[Act<T>]) {
+        if self.cache.in_transaction() {
+            for act in acts {
+                self.perform(act);
+            }
+            return;
+        }
+        self.cache.begin();
+
+        for act in acts {
+            self.perform(act);
+        }
+        if self.crashed {
+            self.cache.rollback();
+        } else if !self.cache.commit() {
+            self.crashed = true;
+        }
+    }
+
+    pub fn write_many(&mut self, writes: &[(Path, Option<T>)]) {
+        if self.crashed {
+            return;
+        }
+        let entries: Vec<_> = writes
+            .iter()
+            .map(|(path, value)| (path.clone(), value.clone().map(Db::Doc)))
+            .collect();
+
+        if !self.cache.write_many(&entries) {
+            self.crashed = true;
+        }
+    }
+
     pub fn list<'a, P>(&mut self, path: &'a P) -> Option<BTreeSet<String>>
     where
         Path: Borrow<P>,
@@ -139,6 +218,8 @@ where
 mod tests {
     use super::*;
 
+    use crate::planner::Planner;
+
     fn x_path() -> Path {
         Path::from("/path/x.json")
     }
@@ -252,6 +333,100 @@ mod tests {
         assert_eq!(rec, Some((2, Some(Db::Doc(vec!['z'])))));
     }
 
+    #[test]
+    fn writes_several_documents_together() {
+        let store = make_store();
+        let mut actor = Actor::new(&store, Config::new());
+
+        actor.get(&x_path());
+        actor.get(&y_path());
+        actor.write_many(&[(x_path(), Some(vec!['z'])), (y_path(), None)]);
+
+        assert_eq!(store.borrow().seq, 6);
+        assert_eq!(
+            store.borrow().read(&x_path()),
+            Some((2, Some(Db::Doc(vec!['z']))))
+        );
+        assert_eq!(store.borrow().read(&y_path()), Some((2, None)));
+    }
+
+    #[test]
+    fn writes_none_of_a_batch_with_a_conflicting_document() {
+        let store = make_store();
+        let mut actor = Actor::new(&store, Config::new());
+
+        actor.get(&x_path());
+        actor.get(&y_path());
+
+        store
+            .borrow_mut()
+            .write(y_path(), Some(1), Db::Doc(vec!['q']));
+
+        actor.write_many(&[(x_path(), Some(vec!['z'])), (y_path(), None)]);
+
+        assert_eq!(
+            store.borrow().read(&x_path()),
+            Some((1, Some(Db::Doc(vec!['a', 'b']))))
+        );
+        assert_eq!(actor.get(&x_path()), None);
+    }
+
+    fn plan_transaction(planner: &mut Planner<Vec<char>>) {
+        planner.client("A").transaction(|tx| {
+            tx.update(y_path().full(), |_| Some(vec!['q']));
+            tx.update(x_path().full(), |_| Some(vec!['z']));
+        });
+    }
+
+    #[test]
+    fn applies_a_transaction_as_one_change() {
+        let store = make_store();
+        let mut actor = Actor::new(&store, Config::new());
+
+        let mut planner = Planner::new(Config::new());
+        plan_transaction(&mut planner);
+
+        for act in planner.orderings().next().unwrap() {
+            actor.dispatch(act);
+        }
+
+        let store = store.borrow();
+        assert_eq!(store.seq, 6);
+        assert_eq!(store.read(&x_path()), Some((2, Some(Db::Doc(vec!['z'])))));
+        assert_eq!(store.read(&y_path()), Some((2, Some(Db::Doc(vec!['q'])))));
+    }
+
+    #[test]
+    fn writes_nothing_from_a_transaction_with_a_conflicting_write() {
+        let store = make_store();
+        let mut actor = Actor::new(&store, Config::new());
+
+        actor.get(&x_path());
+
+        store
+            .borrow_mut()
+            .write(x_path(), Some(1), Db::Doc(vec!['p']));
+
+        let mut planner = Planner::new(Config::new());
+        plan_transaction(&mut planner);
+
+        for act in planner.orderings().next().unwrap() {
+            actor.dispatch(act);
+        }
+
+        let store = store.borrow();
+        assert_eq!(store.seq, 6);
+        assert_eq!(store.read(&x_path()), Some((2, Some(Db::Doc(vec!['p'])))));
+        assert_eq!(
+            store.read(&y_path()),
+            Some((1, Some(Db::Doc(vec!['c', 'd', 'e']))))
+        );
+        assert_eq!(
+            store.read("/path/to/"),
+            Some((1, Some(Db::dir_from(&["y.json"]))))
+        );
+    }
+
     #[test]
     fn creates_links() {
         let store = make_store();
@@ -359,6 +534,36 @@ mod tests {
         assert_eq!(store.borrow().read("/path/x.json"), Some((2, None)));
     }
 
+    #[test]
+    fn takes_a_dropped_put_as_written_without_reaching_the_store() {
+        let mut planner = Planner::new(Config::new().inject(Fault::Drop));
+        planner
+            .client("A")
+            .update(x_path().full(), |_| Some(vec!['z']));
+
+        let plan = planner
+            .orderings()
+            .find(|plan| {
+                let pair = plan
+                    .windows(2)
+                    .find(|pair| matches!(pair[0].op, Op::Fault(_)));
+                pair.is_some_and(|pair| matches!(pair[1].op, Op::Put(_)))
+            })
+            .unwrap();
+
+        let store = make_store();
+        let mut actor = Actor::new(&store, Config::new());
+
+        for act in plan {
+            actor.dispatch(act);
+        }
+        assert_eq!(actor.get(&x_path()), Some(vec!['z']));
+        assert_eq!(
+            store.borrow().read(&x_path()),
+            Some((1, Some(Db::Doc(vec!['a', 'b']))))
+        );
+    }
+
     #[test]
     fn does_not_decide_to_remove_directories_by_default() {
         let store = make_store();
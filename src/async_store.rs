@@ -0,0 +1,96 @@
+use std::borrow::Borrow;
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use crate::config::Config;
+use crate::store::{Rev, Store};
+
+#[derive(Clone)]
+pub struct AsyncStore<K, V> {
+    store: Arc<Mutex<Store<K, V>>>,
+}
+
+impl<K, V> AsyncStore<K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    pub fn new(config: Config) -> AsyncStore<K, V> {
+        AsyncStore::from(Store::new(config))
+    }
+
+    pub async fn read<Q>(&self, key: &Q) -> Option<(Rev, Option<V>)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.store.lock().await.read(key)
+    }
+
+    pub async fn write(&self, key: K, rev: Option<Rev>, value: V) -> Option<Rev> {
+        self.store.lock().await.write(key, rev, value)
+    }
+
+    pub async fn remove(&self, key: K, rev: Option<Rev>) -> Option<Rev> {
+        self.store.lock().await.remove(key, rev)
+    }
+
+    pub async fn seq(&self) -> Rev {
+        self.store.lock().await.seq
+    }
+}
+
+impl<K, V> From<Store<K, V>> for AsyncStore<K, V> {
+    fn from(store: Store<K, V>) -> AsyncStore<K, V> {
+        AsyncStore {
+            store: Arc::new(Mutex::new(store)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stores_a_new_value() {
+        let store: AsyncStore<String, _> = AsyncStore::new(Config::new());
+
+        assert_eq!(store.write("x".into(), None, 'a').await, Some(1));
+        assert_eq!(store.read("x").await, Some((1, Some('a'))));
+        assert_eq!(store.remove("x".into(), Some(1)).await, Some(2));
+        assert_eq!(store.read("x").await, Some((2, None)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn allows_only_one_of_two_racing_writes_to_succeed() {
+        let store: AsyncStore<String, _> = AsyncStore::new(Config::new());
+        store.write("x".into(), None, 'a').await;
+
+        let barrier = Arc::new(tokio::sync::Barrier::new(2));
+
+        let tasks: Vec<_> = ['b', 'c']
+            .into_iter()
+            .map(|value| {
+                let store = store.clone();
+                let barrier = barrier.clone();
+
+                tokio::spawn(async move {
+                    let (rev, _) = store.read("x").await.unwrap();
+                    barrier.wait().await;
+                    store.write("x".into(), Some(rev), value).await
+                })
+            })
+            .collect();
+
+        let mut results = Vec::new();
+        for task in tasks {
+            results.push(task.await.unwrap());
+        }
+
+        let wins: Vec<_> = results.iter().flatten().collect();
+        assert_eq!(wins, [&2]);
+        assert_eq!(store.seq().await, 2);
+    }
+}
@@ -1,16 +1,22 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Update {
     ReadsBeforeLinks,
     GetBeforePut,
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Remove {
     UnlinkReverseSequential,
     UnlinkParallel,
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Cas {
     Strict,
     MatchRev,
@@ -18,12 +24,43 @@ pub enum Cas {
     Lax,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Count {
+    Total,
+    MaxIndex,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Rule {
+    DirExists,
+    DirLinked,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Fault {
+    Drop,
+    Duplicate,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Config {
     pub update: Update,
     pub remove: Remove,
     pub skip_links: bool,
     pub store: Cas,
+    pub max_steps: Option<usize>,
+    pub explain_failure: bool,
+    pub shrink_failure: bool,
+    pub fine_grained: bool,
+    pub partial_order: bool,
+    pub count: Count,
+    pub clients: usize,
+    pub rules: Vec<Rule>,
+    pub faults: Vec<Fault>,
 }
 
 impl Default for Config {
@@ -33,6 +70,15 @@ impl Default for Config {
             remove: Remove::UnlinkReverseSequential,
             skip_links: false,
             store: Cas::Strict,
+            max_steps: None,
+            explain_failure: false,
+            shrink_failure: false,
+            fine_grained: false,
+            partial_order: false,
+            count: Count::Total,
+            clients: 2,
+            rules: vec![Rule::DirExists, Rule::DirLinked],
+            faults: Vec::new(),
         }
     }
 }
@@ -61,4 +107,58 @@ impl Config {
         self.store = mode;
         self
     }
+
+    pub fn max_steps(mut self, steps: usize) -> Config {
+        self.max_steps = Some(steps);
+        self
+    }
+
+    pub fn explain_failure(mut self, mode: bool) -> Config {
+        self.explain_failure = mode;
+        self
+    }
+
+    pub fn shrink_failure(mut self, mode: bool) -> Config {
+        self.shrink_failure = mode;
+        self
+    }
+
+    pub fn fine_grained(mut self, mode: bool) -> Config {
+        self.fine_grained = mode;
+        self
+    }
+
+    pub fn partial_order(mut self, mode: bool) -> Config {
+        self.partial_order = mode;
+        self
+    }
+
+    pub fn count(mut self, mode: Count) -> Config {
+        self.count = mode;
+        self
+    }
+
+    pub fn clients(mut self, n: usize) -> Config {
+        self.clients = n;
+        self
+    }
+
+    pub fn enable(mut self, rule: Rule) -> Config {
+        if !self.rules.contains(&rule) {
+            self.rules.push(rule);
+        }
+        self
+    }
+
+    pub fn disable(mut self, rule: Rule) -> Config {
+        self.rules.retain(|r| *r != rule);
+        self
+    }
+
+    pub fn inject(mut self, fault: Fault) -> Config {
+        if !self.faults.contains(&fault) {
+            self.faults.push(fault);
+        }
+        self
+    }
 }
@@ -1,10 +1,17 @@
 use std::cell::RefCell;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::Arc;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::config::Rule;
 use crate::path::Path;
 use crate::store::{Cache, Rev, Store};
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Db<T> {
     Doc(T),
     Dir(BTreeSet<String>),
@@ -20,24 +27,244 @@ impl<T> Db<T> {
 pub type DbCache<'a, T> = Cache<'a, Path, Db<T>>;
 pub type DbStore<T> = Store<Path, Db<T>>;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiffKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TreeDiff {
+    pub name: String,
+    pub change: Option<DiffKind>,
+    pub children: Vec<TreeDiff>,
+}
+
+impl TreeDiff {
+    fn new(name: &str) -> TreeDiff {
+        TreeDiff {
+            name: name.to_string(),
+            change: None,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.change.is_none() && self.children.is_empty()
+    }
+
+    fn insert(&mut self, path: &Path, change: DiffKind) {
+        let mut node = self;
+
+        for (_, name) in path.links() {
+            let i = match node.children.iter().position(|c| c.name == name) {
+                Some(i) => i,
+                None => {
+                    node.children.push(TreeDiff::new(name));
+                    node.children.len() - 1
+                }
+            };
+            node = &mut node.children[i];
+        }
+        node.change = Some(change);
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        let marker = match self.change {
+            Some(DiffKind::Added) => '+',
+            Some(DiffKind::Removed) => '-',
+            Some(DiffKind::Changed) => '~',
+            None => ' ',
+        };
+        let indent = depth * 2;
+        writeln!(f, "{} {:indent$}{}", marker, "", self.name)?;
+
+        for child in &self.children {
+            child.write(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TreeDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl<T> DbStore<T>
+where
+    T: Clone + PartialEq,
+{
+    pub fn tree_diff(&self, other: &DbStore<T>) -> TreeDiff {
+        let mut diff = TreeDiff::new("/");
+        let keys: BTreeSet<&Path> = self.keys().chain(other.keys()).collect();
+
+        for key in keys {
+            let change = match (self.get(key), other.get(key)) {
+                (None, Some(_)) => DiffKind::Added,
+                (Some(_), None) => DiffKind::Removed,
+                (Some(a), Some(b)) if a != b => DiffKind::Changed,
+                _ => continue,
+            };
+            diff.insert(key, change);
+        }
+        diff
+    }
+}
+
+pub type AliasRule<T> = Arc<dyn Fn(&DbStore<T>) -> Vec<String> + Send + Sync>;
+
+pub fn alias_rule<T, F>(normalize: F) -> AliasRule<T>
+where
+    T: Clone + PartialEq + 'static,
+    F: Fn(&str) -> String + Send + Sync + 'static,
+{
+    Arc::new(move |store: &DbStore<T>| {
+        let mut groups: BTreeMap<String, Vec<&Path>> = BTreeMap::new();
+
+        for path in store.keys() {
+            if path.is_doc() && store.get(path).is_some() {
+                groups.entry(normalize(path.full())).or_default().push(path);
+            }
+        }
+
+        let mut errors = Vec::new();
+
+        for paths in groups.values() {
+            let first = paths[0];
+
+            for path in &paths[1..] {
+                if store.get(first) != store.get(*path) {
+                    errors.push(format!(
+                        "alias divergence: {} and {} hold different values",
+                        first, path
+                    ));
+                }
+            }
+        }
+        errors
+    })
+}
+
+pub trait Invariant<T>: Send + Sync {
+    fn check(&self, store: &DbStore<T>) -> Result<(), Vec<String>>;
+}
+
+// The built-in invariant: every live doc is reachable through its parent dirs,
+// subject to which of the rules are enabled.
+pub struct DirLinks {
+    rules: Vec<Rule>,
+}
+
+impl DirLinks {
+    pub fn new(rules: &[Rule]) -> DirLinks {
+        DirLinks {
+            rules: rules.to_vec(),
+        }
+    }
+
+    fn check_doc<T>(&self, store: &DbStore<T>, doc: &Path, errors: &mut Vec<String>)
+    where
+        T: Clone,
+    {
+        for (dir, name) in doc.links() {
+            if let Some(Db::Dir(entries)) = store.get(dir) {
+                if self.rules.contains(&Rule::DirLinked) && !entries.contains(name) {
+                    errors.push(format!(
+                        "dir '{}' does not include name '{}', required by doc '{}'",
+                        dir, name, doc
+                    ));
+                }
+            } else if self.rules.contains(&Rule::DirExists) {
+                errors.push(format!(
+                    "dir '{}', required by doc '{}', is missing",
+                    dir, doc
+                ));
+            }
+        }
+    }
+}
+
+impl Default for DirLinks {
+    fn default() -> DirLinks {
+        DirLinks::new(&[Rule::DirExists, Rule::DirLinked])
+    }
+}
+
+impl<T> Invariant<T> for DirLinks
+where
+    T: Clone,
+{
+    fn check(&self, store: &DbStore<T>) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        for path in store.keys() {
+            if path.is_doc() && store.get(path).is_some() {
+                self.check_doc(store, path, &mut errors);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+// Names the rule a Checker error breaks, so that errors can be tallied by
+// rule. Errors from scenario invariants and hooks are free-form, so they are
+// all named "other".
+pub fn rule_name(error: &str) -> &'static str {
+    if error.starts_with("alias divergence:") {
+        "alias_divergence"
+    } else if error.starts_with("dir '") && error.contains("' does not include name '") {
+        "dir_linked"
+    } else if error.starts_with("dir '") && error.ends_with(", is missing") {
+        "dir_exists"
+    } else {
+        "other"
+    }
+}
+
 pub struct Checker<'a, T> {
     store: &'a RefCell<DbStore<T>>,
     seq: Rev,
     errors: Vec<String>,
+    aliases: Option<AliasRule<T>>,
+    links: DirLinks,
+    invariants: Vec<Arc<dyn Invariant<T>>>,
 }
 
 impl<T> Checker<'_, T>
 where
     T: Clone,
 {
-    pub fn new(store: &RefCell<DbStore<T>>) -> Checker<T> {
+    pub fn new(store: &RefCell<DbStore<T>>) -> Checker<'_, T> {
         Checker {
             store,
             seq: 0,
             errors: Vec::new(),
+            aliases: None,
+            links: DirLinks::default(),
+            invariants: Vec::new(),
         }
     }
 
+    pub fn check_rules(&mut self, rules: &[Rule]) {
+        self.links = DirLinks::new(rules);
+    }
+
+    pub fn check_invariant(&mut self, invariant: Arc<dyn Invariant<T>>) {
+        self.invariants.push(invariant);
+    }
+
+    pub fn check_aliases(&mut self, rule: AliasRule<T>) {
+        self.aliases = Some(rule);
+    }
+
     pub fn check(&mut self) -> Result<(), Vec<String>> {
         let store = self.store.borrow();
 
@@ -46,12 +273,19 @@ where
         }
         self.errors = Vec::new();
 
-        for path in store.keys() {
-            if path.is_doc() && store.get(path).is_some() {
-                self.check_doc(path);
+        let links: &(dyn Invariant<T> + 'static) = &self.links;
+        let invariants = self.invariants.iter().map(|invariant| invariant.as_ref());
+
+        for invariant in std::iter::once(links).chain(invariants) {
+            if let Err(errors) = invariant.check(&store) {
+                self.errors.extend(errors);
             }
         }
 
+        if let Some(rule) = &self.aliases {
+            self.errors.extend(rule(&store));
+        }
+
         if self.errors.is_empty() {
             self.seq = store.seq;
             Ok(())
@@ -59,24 +293,6 @@ where
             Err(self.errors.clone())
         }
     }
-
-    fn check_doc(&mut self, doc: &Path) {
-        for (dir, name) in doc.links() {
-            if let Some(Db::Dir(entries)) = self.store.borrow().get(dir) {
-                if !entries.contains(name) {
-                    self.errors.push(format!(
-                        "dir '{}' does not include name '{}', required by doc '{}'",
-                        dir, name, doc
-                    ));
-                }
-            } else {
-                self.errors.push(format!(
-                    "dir '{}', required by doc '{}', is missing",
-                    dir, doc
-                ));
-            }
-        }
-    }
 }
 
 #[cfg(test)]
@@ -121,6 +337,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn names_the_rule_each_error_breaks() {
+        let mut store = make_store();
+        store.write("/path/to/".into(), Some(1), Db::dir_from(&[]));
+        store.remove("/path/".into(), Some(1));
+
+        let store_cell = RefCell::new(store);
+        let mut checker = Checker::new(&store_cell);
+        let errors = checker.check().unwrap_err();
+
+        let rules: Vec<_> = errors.iter().map(|error| rule_name(error)).collect();
+        assert_eq!(rules, ["dir_exists", "dir_linked"]);
+
+        assert_eq!(rule_name("alias divergence: /a and /b"), "alias_divergence");
+        assert_eq!(rule_name("'/x' was written"), "other");
+    }
+
     #[test]
     fn complains_if_a_parent_dir_is_deleted() {
         let mut store = make_store();
@@ -186,6 +419,174 @@ mod tests {
         );
     }
 
+    #[test]
+    fn does_not_complain_about_unlinked_dirs_if_the_rule_is_disabled() {
+        let mut store = make_store();
+        store.write("/path/".into(), Some(1), Db::dir_from(&[]));
+        store.write("/other/y.json".into(), None, Db::Doc('b'));
+
+        let store_cell = RefCell::new(store);
+        let mut checker = Checker::new(&store_cell);
+        checker.check_rules(&Config::new().disable(Rule::DirLinked).rules);
+
+        assert_eq!(
+            checker.check(),
+            Err(vec![String::from(
+                "dir '/other/', required by doc '/other/y.json', is missing"
+            )])
+        );
+    }
+
+    #[test]
+    fn does_not_complain_about_missing_dirs_if_the_rule_is_disabled() {
+        let mut store = make_store();
+        store.write("/path/".into(), Some(1), Db::dir_from(&[]));
+        store.write("/other/y.json".into(), None, Db::Doc('b'));
+
+        let store_cell = RefCell::new(store);
+        let mut checker = Checker::new(&store_cell);
+        checker.check_rules(&Config::new().disable(Rule::DirExists).rules);
+
+        assert_eq!(
+            checker.check(),
+            Err(vec![
+                String::from(
+                    "dir '/' does not include name 'other/', required by doc '/other/y.json'"
+                ),
+                String::from(
+                    "dir '/path/' does not include name 'to/', required by doc '/path/to/x.json'"
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn diffs_a_document_moved_between_folders_as_a_tree() {
+        let mut before = DbStore::new(Config::new());
+        before.write("/".into(), None, Db::dir_from(&["path/"]));
+        before.write("/path/".into(), None, Db::dir_from(&["a/", "b/"]));
+        before.write("/path/a/".into(), None, Db::dir_from(&["x.json"]));
+        before.write("/path/b/".into(), None, Db::dir_from(&[]));
+        before.write("/path/a/x.json".into(), None, Db::Doc('a'));
+
+        let mut after = before.clone();
+        after.write("/path/b/".into(), Some(1), Db::dir_from(&["x.json"]));
+        after.write("/path/b/x.json".into(), None, Db::Doc('a'));
+        after.remove("/path/a/x.json".into(), Some(1));
+        after.write("/path/a/".into(), Some(1), Db::dir_from(&[]));
+
+        let diff = before.tree_diff(&after);
+
+        assert_eq!(
+            diff.to_string(),
+            [
+                "  /",
+                "    path/",
+                "~     a/",
+                "-       x.json",
+                "~     b/",
+                "+       x.json",
+                "",
+            ]
+            .join("\n")
+        );
+        assert!(after.tree_diff(&after).is_empty());
+    }
+
+    fn make_aliased_store(value: char) -> DbStore<char> {
+        let mut store = make_store();
+        let listing = Db::dir_from(&["X.json", "x.json"]);
+
+        store.write("/path/to/".into(), Some(1), listing);
+        store.write("/path/to/X.json".into(), None, Db::Doc(value));
+
+        store
+    }
+
+    #[test]
+    fn complains_if_aliases_of_a_doc_hold_different_values() {
+        let store = make_aliased_store('b');
+
+        let store_cell = RefCell::new(store);
+        let mut checker = Checker::new(&store_cell);
+        checker.check_aliases(alias_rule(|key| key.to_lowercase()));
+
+        assert_eq!(
+            checker.check(),
+            Err(vec![String::from(
+                "alias divergence: /path/to/X.json and /path/to/x.json hold different values"
+            )])
+        );
+    }
+
+    #[test]
+    fn does_not_complain_if_aliases_of_a_doc_agree() {
+        let store = make_aliased_store('a');
+
+        let store_cell = RefCell::new(store);
+        let mut checker = Checker::new(&store_cell);
+        checker.check_aliases(alias_rule(|key| key.to_lowercase()));
+
+        assert_eq!(checker.check(), Ok(()));
+    }
+
+    #[test]
+    fn does_not_complain_about_aliases_without_a_rule() {
+        let store = make_aliased_store('b');
+
+        let store_cell = RefCell::new(store);
+        let mut checker = Checker::new(&store_cell);
+
+        assert_eq!(checker.check(), Ok(()));
+    }
+
+    struct NoDocHolds(char);
+
+    impl Invariant<char> for NoDocHolds {
+        fn check(&self, store: &DbStore<char>) -> Result<(), Vec<String>> {
+            let errors: Vec<_> = store
+                .keys()
+                .filter(|path| store.get(*path) == Some(&Db::Doc(self.0)))
+                .map(|path| format!("doc '{}' holds '{}'", path, self.0))
+                .collect();
+
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(errors)
+            }
+        }
+    }
+
+    #[test]
+    fn aggregates_errors_from_registered_invariants_with_the_built_in_checks() {
+        let mut store = make_store();
+        store.write("/".into(), Some(1), Db::dir_from(&[]));
+
+        let store_cell = RefCell::new(store);
+        let mut checker = Checker::new(&store_cell);
+        checker.check_invariant(Arc::new(NoDocHolds('a')));
+
+        assert_eq!(
+            checker.check(),
+            Err(vec![
+                String::from(
+                    "dir '/' does not include name 'path/', required by doc '/path/to/x.json'"
+                ),
+                String::from("doc '/path/to/x.json' holds 'a'"),
+            ])
+        );
+    }
+
+    #[test]
+    fn passes_when_registered_invariants_hold() {
+        let store_cell = RefCell::new(make_store());
+        let mut checker = Checker::new(&store_cell);
+        checker.check_invariant(Arc::new(NoDocHolds('b')));
+
+        assert_eq!(checker.check(), Ok(()));
+    }
+
     #[test]
     fn does_not_complain_if_an_ancestor_of_a_deleted_doc_is_unlinked() {
         let mut store = make_store();
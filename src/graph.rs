@@ -1,3 +1,6 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
 pub type Id = usize;
 
 #[derive(Debug, Default)]
@@ -29,21 +32,145 @@ impl<T> Graph<T> {
         node_id
     }
 
+    pub fn merge(&mut self, other: Graph<T>) {
+        let offset = self.nodes.len();
+
+        for node in other.nodes {
+            self.nodes.push(Node {
+                id: node.id + offset,
+                deps: node.deps.iter().map(|dep| dep + offset).collect(),
+                value: node.value,
+            });
+        }
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.nodes.iter().map(|node| &node.value)
+    }
+
+    pub fn deps(&self, id: Id) -> &[Id] {
+        &self.nodes[id - 1].deps
+    }
+
+    // The node and every node that depends on it, directly or through others.
+    // Dependencies always point back to earlier nodes, so one pass forward
+    // from the node finds them all.
+    pub fn dependents(&self, id: Id) -> BTreeSet<Id> {
+        let mut found = BTreeSet::from([id]);
+
+        for node in &self.nodes[id..] {
+            if node.deps.iter().any(|dep| found.contains(dep)) {
+                found.insert(node.id);
+            }
+        }
+        found
+    }
+
+    // Nodes can only depend on nodes that were added before them, so the order
+    // of insertion is always one of the valid orderings.
+    pub fn into_values(self) -> Vec<T> {
+        self.nodes.into_iter().map(|node| node.value).collect()
+    }
+
     pub fn orderings(&self) -> impl Iterator<Item = Vec<&T>> {
+        self.orderings_where(|_, _| true)
+    }
+
+    // Like orderings(), but leaves out every ordering in which some node is
+    // directly followed by one that `follows` rejects. Prefixes are cut as soon
+    // as they break the rule, so the orderings skipped are never generated.
+    pub fn orderings_where<F>(&self, follows: F) -> impl Iterator<Item = Vec<&T>>
+    where
+        F: Fn(&T, &T) -> bool,
+    {
         let nodes: Vec<_> = self
             .nodes
             .iter()
             .map(|node| (node.id, node.deps.clone()))
             .collect();
 
-        permute(nodes).map(|order| {
+        let mut rejected = BTreeSet::new();
+
+        for a in &self.nodes {
+            for b in &self.nodes {
+                if a.id != b.id && !follows(&a.value, &b.value) {
+                    rejected.insert((a.id, b.id));
+                }
+            }
+        }
+
+        permute(nodes, None, Arc::new(rejected)).map(|order| {
             let values = order.iter().rev().map(|id| &self.nodes[id - 1].value);
             values.collect()
         })
     }
+
+    // Counts saturate at usize::MAX, which still leaves ordering_at() exact for
+    // every index below that.
+    pub fn count_orderings(&self) -> usize {
+        let ids: Vec<_> = self.nodes.iter().map(|node| node.id).collect();
+        self.count_from(&ids, &mut BTreeMap::new())
+    }
+
+    // Finds the ordering at the same position as in orderings() by walking down
+    // from the full set of nodes, skipping each subtree of orderings that
+    // starts with an earlier node by its size rather than enumerating it.
+    pub fn ordering_at(&self, mut index: usize) -> Option<Vec<&T>> {
+        let mut counts = BTreeMap::new();
+        let mut remaining: Vec<_> = self.nodes.iter().map(|node| node.id).collect();
+        let mut order = Vec::new();
+
+        while !remaining.is_empty() {
+            let mut next = None;
+
+            for id in self.available(&remaining) {
+                let rest = without(&remaining, id);
+                let count = self.count_from(&rest, &mut counts);
+
+                if index < count {
+                    next = Some((id, rest));
+                    break;
+                }
+                index -= count;
+            }
+            let (id, rest) = next?;
+            order.push(&self.nodes[id - 1].value);
+            remaining = rest;
+        }
+        (index == 0).then_some(order)
+    }
+
+    fn count_from(&self, remaining: &[Id], counts: &mut BTreeMap<Vec<Id>, usize>) -> usize {
+        if remaining.is_empty() {
+            return 1;
+        }
+        if let Some(count) = counts.get(remaining) {
+            return *count;
+        }
+        let count = self.available(remaining).fold(0, |count: usize, id| {
+            count.saturating_add(self.count_from(&without(remaining, id), counts))
+        });
+        counts.insert(remaining.to_vec(), count);
+        count
+    }
+
+    fn available<'a>(&'a self, remaining: &'a [Id]) -> impl Iterator<Item = Id> + 'a {
+        remaining.iter().cloned().filter(move |id| {
+            let deps = &self.nodes[id - 1].deps;
+            deps.iter().all(|dep| !remaining.contains(dep))
+        })
+    }
+}
+
+fn without(ids: &[Id], id: Id) -> Vec<Id> {
+    ids.iter().cloned().filter(|i| *i != id).collect()
 }
 
-fn permute(nodes: Vec<(Id, Vec<Id>)>) -> Box<dyn Iterator<Item = Vec<Id>> + Send> {
+fn permute(
+    nodes: Vec<(Id, Vec<Id>)>,
+    prev: Option<Id>,
+    rejected: Arc<BTreeSet<(Id, Id)>>,
+) -> Box<dyn Iterator<Item = Vec<Id>> + Send> {
     if nodes.is_empty() {
         return Box::new([Vec::new()].into_iter());
     }
@@ -52,6 +179,7 @@ fn permute(nodes: Vec<(Id, Vec<Id>)>) -> Box<dyn Iterator<Item = Vec<Id>> + Send
         .iter()
         .filter(|(_, deps)| deps.is_empty())
         .map(|(node_id, _)| *node_id)
+        .filter(|node_id| prev.is_none_or(|prev| !rejected.contains(&(prev, *node_id))))
         .collect();
 
     let states = available.into_iter().flat_map(move |action| {
@@ -64,7 +192,7 @@ fn permute(nodes: Vec<(Id, Vec<Id>)>) -> Box<dyn Iterator<Item = Vec<Id>> + Send
             })
             .collect();
 
-        permute(remaining).map(move |mut others| {
+        permute(remaining, Some(action), rejected.clone()).map(move |mut others| {
             others.push(action);
             others
         })
@@ -74,6 +202,7 @@ fn permute(nodes: Vec<(Id, Vec<Id>)>) -> Box<dyn Iterator<Item = Vec<Id>> + Send
 }
 
 #[cfg(test)]
+#[allow(clippy::useless_vec)]
 pub mod tests {
     use super::*;
     use std::collections::{HashMap, HashSet};
@@ -383,6 +512,51 @@ pub mod tests {
         assert_eq!(unique.len(), orderings.len());
     }
 
+    #[test]
+    fn finds_every_node_that_depends_on_a_node() {
+        let graph = example_graph();
+
+        assert_eq!(graph.dependents(1), BTreeSet::from([1, 4, 7, 8]));
+        assert_eq!(graph.dependents(2), BTreeSet::from([2, 5, 6, 7, 8]));
+        assert_eq!(graph.dependents(8), BTreeSet::from([8]));
+    }
+
+    #[test]
+    fn counts_orderings_without_enumerating_them() {
+        let graph = example_graph();
+        assert_eq!(graph.count_orderings(), 150);
+
+        let empty: Graph<char> = Graph::new();
+        assert_eq!(empty.count_orderings(), 1);
+    }
+
+    #[test]
+    fn finds_each_ordering_by_its_position() {
+        let graph = example_graph();
+
+        for (i, order) in graph.orderings().enumerate() {
+            assert_eq!(graph.ordering_at(i), Some(order));
+        }
+        assert_eq!(graph.ordering_at(150), None);
+    }
+
+    #[test]
+    fn skips_orderings_with_rejected_neighbours() {
+        let graph = example_graph();
+        let follows = |a: &usize, b: &usize| a % 2 == b % 2 || a < b;
+
+        let expected: Vec<_> = graph
+            .orderings()
+            .filter(|order| order.windows(2).all(|pair| follows(pair[0], pair[1])))
+            .collect();
+
+        let pruned: Vec<_> = graph.orderings_where(follows).collect();
+
+        assert!(!pruned.is_empty());
+        assert!(pruned.len() < 150);
+        assert_eq!(pruned, expected);
+    }
+
     #[test]
     fn keeps_sequential_nodes_in_order() {
         let graph = example_graph();
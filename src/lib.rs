@@ -1,8 +1,12 @@
 pub mod actor;
+#[cfg(feature = "async")]
+pub mod async_store;
 pub mod config;
 pub mod db;
 pub mod graph;
 pub mod path;
 pub mod planner;
+pub mod report;
 pub mod runner;
 pub mod store;
+mod tree;
@@ -1,9 +1,17 @@
 use std::borrow::Borrow;
 use std::fmt;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 const SEP: char = '/';
 
 #[derive(Clone, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(from = "String", into = "String")
+)]
 pub struct Path {
     original: String,
     parts: Vec<(String, String)>,
@@ -57,6 +65,18 @@ impl From<&str> for Path {
     }
 }
 
+impl From<String> for Path {
+    fn from(value: String) -> Path {
+        Path::new(&value)
+    }
+}
+
+impl From<Path> for String {
+    fn from(value: Path) -> String {
+        value.original
+    }
+}
+
 fn parse(path: &str) -> Vec<(String, String)> {
     let mut parts: Vec<_> = path.split(SEP).map(|s| s.to_string()).collect();
     let len = parts.len();
@@ -75,6 +95,18 @@ fn parse(path: &str) -> Vec<(String, String)> {
         .collect()
 }
 
+pub fn path_validator(key: &str) -> Result<(), String> {
+    if key.is_empty() {
+        Err(String::from("key is empty"))
+    } else if !key.starts_with(SEP) {
+        Err(format!("key '{}' does not begin with '{}'", key, SEP))
+    } else if key.contains("//") {
+        Err(format!("key '{}' contains an empty segment", key))
+    } else {
+        Ok(())
+    }
+}
+
 impl Path {
     pub fn new(name: &str) -> Path {
         Path {
@@ -126,6 +158,21 @@ mod tests {
         assert!(!path.is_valid());
     }
 
+    #[test]
+    fn validates_well_formed_keys() {
+        assert_eq!(path_validator("/"), Ok(()));
+        assert_eq!(path_validator("/path/"), Ok(()));
+        assert_eq!(path_validator("/path/to/x.json"), Ok(()));
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        assert!(path_validator("").is_err());
+        assert!(path_validator("a").is_err());
+        assert!(path_validator("//a").is_err());
+        assert!(path_validator("/path//x.json").is_err());
+    }
+
     #[test]
     fn is_a_dir_if_it_ends_with_a_slash() {
         let path = Path::from("/foo/");
@@ -1,7 +1,12 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
+use std::sync::Arc;
 
-use crate::config::{Config, Remove, Update};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, Fault, Remove, Update};
+use crate::db::Invariant;
 use crate::graph::{Graph, Id};
 use crate::path::Path;
 
@@ -20,22 +25,122 @@ impl<T> Act<T> {
             op,
         }
     }
-}
 
-impl<T> fmt::Debug for Act<T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Act<{}: ", self.client_id)?;
+    // The keys an act touches in the store, each with whether it writes to it.
+    pub fn footprint(&self) -> Vec<(&Path, bool)> {
+        match &self.op {
+            Op::Get | Op::List => vec![(&self.path, false)],
+            Op::Put(_) | Op::Rm | Op::Link(_) | Op::Unlink(_) => vec![(&self.path, true)],
+            Op::Transaction(acts) => acts.iter().flat_map(|act| act.footprint()).collect(),
+            Op::WriteMany(writes) => writes.iter().map(|(path, _)| (path, true)).collect(),
+            Op::Fault(_) => Vec::new(),
+        }
+    }
 
+    pub fn is_read_only(&self) -> bool {
+        self.footprint().iter().all(|(_, write)| !write)
+    }
+
+    pub fn describe_op(&self) -> String {
         match &self.op {
-            Op::Get => write!(f, "get('{}')", self.path)?,
-            Op::Put(_) => write!(f, "put('{}')", self.path)?,
-            Op::Rm => write!(f, "rm('{}')", self.path)?,
-            Op::List => write!(f, "list('{}')", self.path)?,
-            Op::Link(name) => write!(f, "link('{}', '{}')", self.path, name)?,
-            Op::Unlink(name) => write!(f, "unlink('{}', '{}')", self.path, name)?,
+            Op::Get => format!("get('{}')", self.path),
+            Op::Put(_) => format!("put('{}')", self.path),
+            Op::Rm => format!("rm('{}')", self.path),
+            Op::List => format!("list('{}')", self.path),
+            Op::Link(name) => format!("link('{}', '{}')", self.path, name),
+            Op::Unlink(name) => format!("unlink('{}', '{}')", self.path, name),
+            Op::Transaction(acts) => format!("transaction({:?})", acts),
+            Op::WriteMany(writes) => {
+                let paths: Vec<_> = writes.iter().map(|(path, _)| path.full()).collect();
+                format!("write_many({:?})", paths)
+            }
+            Op::Fault(Fault::Drop) => String::from("drop_next()"),
+            Op::Fault(Fault::Duplicate) => String::from("duplicate_next()"),
+        }
+    }
+}
+
+// An act as it ran in one execution, which can be serialized: each put holds
+// the value its update returned there rather than the update itself.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedAct<T> {
+    pub client_id: String,
+    pub path: Path,
+    pub op: SavedOp<T>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SavedOp<T> {
+    Get,
+    Put(Option<T>),
+    Rm,
+    List,
+    Link(String),
+    Unlink(String),
+    Transaction(Vec<SavedAct<T>>),
+    WriteMany(Vec<(Path, Option<T>)>),
+    Fault(Fault),
+}
+
+#[cfg(feature = "serde")]
+impl<T> Act<T>
+where
+    T: Clone,
+{
+    // Takes the value of each put, in the order the client ran them, from
+    // `puts`. A put with no value left never ran, since the client had
+    // stopped, so it is saved as writing nothing.
+    pub fn save(&self, puts: &mut dyn Iterator<Item = Option<T>>) -> SavedAct<T> {
+        let op = match &self.op {
+            Op::Get => SavedOp::Get,
+            Op::Put(_) => SavedOp::Put(puts.next().flatten()),
+            Op::Rm => SavedOp::Rm,
+            Op::List => SavedOp::List,
+            Op::Link(name) => SavedOp::Link(name.clone()),
+            Op::Unlink(name) => SavedOp::Unlink(name.clone()),
+            Op::Transaction(acts) => {
+                SavedOp::Transaction(acts.iter().map(|act| act.save(puts)).collect())
+            }
+            Op::WriteMany(writes) => SavedOp::WriteMany(writes.clone()),
+            Op::Fault(fault) => SavedOp::Fault(*fault),
+        };
+        SavedAct {
+            client_id: self.client_id.clone(),
+            path: self.path.clone(),
+            op,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T> SavedAct<T>
+where
+    T: Clone + Sync + 'static,
+{
+    pub fn to_act(&self) -> Act<T> {
+        let op = match &self.op {
+            SavedOp::Get => Op::Get,
+            SavedOp::Put(value) => {
+                let value = value.clone();
+                Op::Put(Box::new(move |_| value.clone()))
+            }
+            SavedOp::Rm => Op::Rm,
+            SavedOp::List => Op::List,
+            SavedOp::Link(name) => Op::Link(name.clone()),
+            SavedOp::Unlink(name) => Op::Unlink(name.clone()),
+            SavedOp::Transaction(acts) => Op::Transaction(acts.iter().map(Self::to_act).collect()),
+            SavedOp::WriteMany(writes) => Op::WriteMany(writes.clone()),
+            SavedOp::Fault(fault) => Op::Fault(*fault),
         };
+        Act::new(&self.client_id, self.path.clone(), op)
+    }
+}
 
-        write!(f, ">")
+impl<T> fmt::Debug for Act<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Act<{}: {}>", self.client_id, self.describe_op())
     }
 }
 
@@ -46,6 +151,9 @@ pub enum Op<T> {
     List,
     Link(String),
     Unlink(String),
+    Transaction(Vec<Act<T>>),
+    WriteMany(Vec<(Path, Option<T>)>),
+    Fault(Fault),
 }
 
 impl<T> PartialEq for Op<T> {
@@ -57,6 +165,16 @@ impl<T> PartialEq for Op<T> {
             (Op::List, Op::List) => true,
             (Op::Link(a), Op::Link(b)) if a == b => true,
             (Op::Unlink(a), Op::Unlink(b)) if a == b => true,
+            (Op::Transaction(a), Op::Transaction(b)) => {
+                a.len() == b.len()
+                    && a.iter().zip(b).all(|(x, y)| {
+                        x.client_id == y.client_id && x.path == y.path && x.op == y.op
+                    })
+            }
+            (Op::WriteMany(a), Op::WriteMany(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.0 == y.0)
+            }
+            (Op::Fault(a), Op::Fault(b)) => a == b,
             _ => false,
         }
     }
@@ -66,29 +184,293 @@ pub struct Planner<T> {
     graph: Graph<Act<T>>,
     config: Config,
     clients: BTreeSet<String>,
+    window: Option<usize>,
+    reduce: bool,
+    invariants: Vec<Arc<dyn Invariant<T>>>,
+    faults: Vec<Act<T>>,
 }
 
 impl<T> Planner<T> {
     pub fn new(config: Config) -> Planner<T> {
         Planner {
             graph: Graph::new(),
+            reduce: config.partial_order,
             config,
             clients: BTreeSet::new(),
+            window: None,
+            invariants: Vec::new(),
+            faults: Vec::new(),
         }
     }
 
-    pub fn client(&mut self, id: &str) -> Client<T> {
-        self.clients.insert(id.to_string());
+    // Each client gets one act per injected fault, which is never part of the
+    // graph and only appears in the plans with_faults() makes.
+    pub fn client(&mut self, id: &str) -> Client<'_, T> {
+        if self.clients.insert(id.to_string()) {
+            for fault in &self.config.faults {
+                let act = Act::new(id, Path::from("/"), Op::Fault(*fault));
+                self.faults.push(act);
+            }
+        }
         Client::new(&mut self.graph, id, self.config.clone())
     }
 
+    pub fn each_client<F>(&mut self, script: F)
+    where
+        F: Fn(&mut Client<T>),
+    {
+        for i in 0..self.config.clients {
+            script(&mut self.client(&format!("c{}", i)));
+        }
+    }
+
+    pub fn include<F>(&mut self, fragment: &F) -> Result<(), String>
+    where
+        F: Fn(&mut Planner<T>),
+    {
+        let mut other = Planner::new(self.config.clone());
+        fragment(&mut other);
+
+        if let Some(id) = other.clients.intersection(&self.clients).next() {
+            return Err(format!("client '{}' is already defined", id));
+        }
+        self.clients.append(&mut other.clients);
+        self.graph.merge(other.graph);
+        self.invariants.append(&mut other.invariants);
+        self.faults.append(&mut other.faults);
+
+        Ok(())
+    }
+
     pub fn clients(&self) -> impl Iterator<Item = &str> {
         self.clients.iter().map(|s| s.as_ref())
     }
 
+    // Fairness filters whole orderings after the graph has generated them,
+    // dropping any in which a client waits more than `window` steps while
+    // others run. With partial-order reduction on, only the orderings the
+    // reduction keeps are filtered, so an equivalent fair ordering it pruned
+    // is not tried in place of an unfair one. The planner has no bound on
+    // context switches yet, so there is nothing for fairness to compose with:
+    // every ordering the graph generates is subject to the window alone.
+    pub fn fair(&mut self, window: usize) {
+        self.window = Some(window);
+    }
+
+    // Invariants are checked after every act, alongside the built-in checks on
+    // dir links, and their errors are reported together.
+    pub fn invariant<I>(&mut self, invariant: I)
+    where
+        I: Invariant<T> + 'static,
+    {
+        self.invariants.push(Arc::new(invariant));
+    }
+
+    pub fn invariants(&self) -> &[Arc<dyn Invariant<T>>] {
+        &self.invariants
+    }
+
+    // The partial-order reduction only keeps one ordering of each class that
+    // ends in the same state, so it is turned off by anything checked between
+    // acts that the built-in checks do not cover: a registered invariant here,
+    // or alias rules and after_each hooks, which the runner opts out for.
+    pub fn keep_all_orderings(&mut self) {
+        self.reduce = false;
+    }
+
+    pub fn is_reduced(&self) -> bool {
+        self.reduce && self.invariants.is_empty()
+    }
+
+    // Acts are identified by their position in the graph, which is stable as
+    // long as the scenario builds the same plan, so a failing ordering can be
+    // stored as indices and rebuilt later without serializing the acts.
+    pub fn act_index(&self, act: &Act<T>) -> Option<usize> {
+        self.acts().position(|a| std::ptr::eq(a, act))
+    }
+
+    pub fn act_at(&self, index: usize) -> Option<&Act<T>> {
+        self.acts().nth(index)
+    }
+
+    // The act and every act that can only run after it. Dropping all of them
+    // from an ordering leaves an ordering of what remains, which dropping the
+    // act alone might not. A fault act has nothing depending on it.
+    pub fn dependents<'a>(&'a self, act: &'a Act<T>) -> Vec<&'a Act<T>> {
+        let Some(i) = self.graph.values().position(|a| std::ptr::eq(a, act)) else {
+            return vec![act];
+        };
+        let ids = self.graph.dependents(i + 1);
+
+        self.graph
+            .values()
+            .enumerate()
+            .filter(|(i, _)| ids.contains(&(i + 1)))
+            .map(|(_, act)| act)
+            .collect()
+    }
+
+    // A hash of every act the planner built, numbered as act_index() numbers
+    // them, and of the dependencies between them, so that act indices saved
+    // from one build of a scenario can be checked against another. The
+    // functions passed to update() cannot be hashed, so a change to what an
+    // update computes goes unnoticed. FNV-1a is used rather than the std
+    // hasher, whose output may change between Rust releases.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut add = |text: String| {
+            for byte in text.bytes().chain([0]) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+
+        for (i, act) in self.graph.values().enumerate() {
+            add(format!("{:?} {:?}", act, self.graph.deps(i + 1)));
+        }
+        for act in &self.faults {
+            add(format!("{:?}", act));
+        }
+        hash
+    }
+
+    fn acts(&self) -> impl Iterator<Item = &Act<T>> {
+        self.graph.values().chain(&self.faults)
+    }
+
+    // Orderings come out in the same sequence every time for a given scenario
+    // and config, so an index into them identifies one plan across runs.
+    // Without a fairness window or partial-order reduction every ordering of
+    // the graph is kept, so the plan is found directly; otherwise the
+    // filtered orderings before it have to be enumerated.
+    pub fn ordering_at(&self, index: usize) -> Option<Vec<&Act<T>>> {
+        if self.is_filtered() {
+            return self.orderings().nth(index);
+        }
+        let variants = self.fault_variants();
+        let plan = self.graph.ordering_at(index / variants)?;
+
+        with_faults(plan, &self.faults)
+            .into_iter()
+            .nth(index % variants)
+    }
+
+    pub fn ordering_count(&self) -> usize {
+        if self.is_filtered() {
+            return self.orderings().count();
+        }
+        let count = self.graph.count_orderings();
+        count.saturating_mul(self.fault_variants())
+    }
+
+    pub fn is_filtered(&self) -> bool {
+        self.window.is_some() || self.is_reduced()
+    }
+
+    fn fault_variants(&self) -> usize {
+        let writes = self
+            .graph
+            .values()
+            .filter(|act| !act.is_read_only())
+            .count();
+        1 + writes * self.config.faults.len()
+    }
+
+    // Acts are only ordered by the dependencies the client methods declare, so
+    // any two acts without a path between them in the graph are concurrent,
+    // even within one client, and every order of them is produced here.
     pub fn orderings(&self) -> impl Iterator<Item = Vec<&Act<T>>> {
-        self.graph.orderings()
+        let window = self.window;
+        let reduce = self.is_reduced();
+        let faults = &self.faults;
+
+        self.graph
+            .orderings_where(move |a, b| !reduce || in_reduced_order(a, b))
+            .filter(move |plan| window.is_none_or(|window| is_fair(plan, window)))
+            .flat_map(move |plan| with_faults(plan, faults))
+    }
+}
+
+// A plan is fair if, while a client still has acts left to run, no other
+// client takes more than `window` steps before it next makes progress.
+fn is_fair<T>(plan: &[&Act<T>], window: usize) -> bool {
+    let mut remaining: BTreeMap<&str, usize> = BTreeMap::new();
+    for act in plan {
+        *remaining.entry(&act.client_id).or_default() += 1;
+    }
+
+    let mut waits: BTreeMap<&str, BTreeMap<&str, usize>> = BTreeMap::new();
+
+    for act in plan {
+        let id = act.client_id.as_str();
+        waits.remove(id);
+
+        for (client, count) in &remaining {
+            if *client == id || *count == 0 {
+                continue;
+            }
+            let steps = waits.entry(*client).or_default().entry(id).or_default();
+            *steps += 1;
+
+            if *steps > window {
+                return false;
+            }
+        }
+        *remaining.get_mut(id).unwrap() -= 1;
+    }
+    true
+}
+
+// Each ordering is followed by its variants with a single fault injected at
+// each step that writes, by putting one of the client's fault acts just before
+// it. The actor then applies the fault to whatever that step sends to the
+// store; a read sends nothing, so a fault before it would change nothing.
+fn with_faults<'a, T>(plan: Vec<&'a Act<T>>, faults: &'a [Act<T>]) -> Vec<Vec<&'a Act<T>>> {
+    let mut plans = Vec::new();
+
+    for i in 0..plan.len() {
+        if plan[i].is_read_only() {
+            continue;
+        }
+        for fault in faults.iter().filter(|f| f.client_id == plan[i].client_id) {
+            let mut faulty = plan.clone();
+            faulty.insert(i, fault);
+            plans.push(faulty);
+        }
     }
+    plans.insert(0, plan);
+    plans
+}
+
+// Orderings that only differ by swapping adjacent independent acts end in the
+// same state, so only those with every such pair in client order are kept.
+// The least ordering of each class always qualifies, so every class is still
+// checked. States in between may differ, but only on keys the built-in checks
+// do not relate, since writes to a doc and its dirs are never independent.
+fn in_reduced_order<T>(a: &Act<T>, b: &Act<T>) -> bool {
+    a.client_id < b.client_id || !independent(a, b)
+}
+
+// Reads of a key commute with each other, and with writes to any other key.
+// Writes to a doc and to one of its dirs are kept in order, since the checker
+// relates the two.
+fn independent<T>(a: &Act<T>, b: &Act<T>) -> bool {
+    if a.client_id == b.client_id {
+        return false;
+    }
+    let others = b.footprint();
+
+    a.footprint().iter().all(|(x, x_writes)| {
+        others.iter().all(|(y, y_writes)| {
+            let same = x == y && (*x_writes || *y_writes);
+            let nested = *x_writes && *y_writes && (contains(x, y) || contains(y, x));
+            !same && !nested
+        })
+    })
+}
+
+fn contains(dir: &Path, path: &Path) -> bool {
+    dir.is_dir() && path.full().starts_with(dir.full())
 }
 
 pub struct Client<'a, T> {
@@ -177,6 +559,110 @@ impl<'a, T> Client<'a, T> {
         self.graph.add(&links, put);
     }
 
+    pub fn writes<I>(&mut self, key: &str, values: I)
+    where
+        I: IntoIterator<Item = T>,
+        T: Clone + Sync + 'static,
+    {
+        for value in values {
+            self.update(key, move |_| Some(value.clone()));
+        }
+    }
+
+    pub fn read_then_write<F>(&mut self, key: &str, f: F)
+    where
+        F: Fn(T) -> T + Sync + 'static,
+    {
+        let update = move |doc: Option<T>| doc.map(&f);
+
+        if self.config.fine_grained {
+            self.update(key, update);
+        } else {
+            self.update_in_place(key, update);
+        }
+    }
+
+    fn update_in_place<F>(&mut self, key: &str, update: F)
+    where
+        F: Fn(Option<T>) -> Option<T> + Sync + 'static,
+    {
+        let path = Path::from(key);
+
+        let lists: Vec<_> = path
+            .dirs()
+            .map(|dir| self.graph.add(&[], self.act(dir, Op::List)))
+            .collect();
+
+        let links: Vec<_> = path
+            .links()
+            .map(|(dir, name)| {
+                let link = self.act(dir, Op::Link(name.to_string()));
+                self.graph.add(&lists, link)
+            })
+            .collect();
+
+        let put = self.act(&path, Op::Put(Box::new(update)));
+        self.graph.add(&links, put);
+    }
+
+    // The acts built inside a transaction are scheduled as a single step, run
+    // in the order they were planned, so no other client can act between them.
+    pub fn transaction<F>(&mut self, build: F)
+    where
+        F: FnOnce(&mut Client<T>),
+    {
+        let mut graph = Graph::new();
+        build(&mut Client::new(&mut graph, &self.id, self.config.clone()));
+
+        let acts = graph.into_values();
+
+        let Some(path) = acts.first().map(|act| act.path.clone()) else {
+            return;
+        };
+        self.graph.add(&[], self.act(path, Op::Transaction(acts)));
+    }
+
+    // Every key in the batch is read first so the actor holds the revs to
+    // compare against, and written docs have their parent links made before
+    // the batch is applied.
+    pub fn write_many(&mut self, writes: &[(&str, Option<T>)])
+    where
+        T: Clone,
+    {
+        let Some((first, _)) = writes.first() else {
+            return;
+        };
+        let mut deps = Vec::new();
+
+        for (key, value) in writes {
+            let path = Path::from(*key);
+            let reads = self.do_reads(&path);
+
+            if value.is_none() {
+                deps.extend(reads);
+                continue;
+            }
+            for (dir, name) in path.links() {
+                let link = self.act(dir, Op::Link(name.to_string()));
+                deps.push(self.graph.add(&reads, link));
+            }
+        }
+
+        let writes = writes
+            .iter()
+            .map(|(key, value)| (Path::from(*key), value.clone()))
+            .collect();
+
+        let batch = self.act(*first, Op::WriteMany(writes));
+        self.graph.add(&deps, batch);
+    }
+
+    pub fn churn(&mut self, key: &str, n: usize) {
+        for _ in 0..n {
+            self.update(key, |doc| doc);
+        }
+    }
+
     pub fn remove(&mut self, key: &str) {
         if self.config.remove == Remove::UnlinkParallel {
             self.remove_unlink_parallel(key);
@@ -232,6 +718,44 @@ mod tests {
         assert_eq!(clients, ["alice", "bob"]);
     }
 
+    fn writer(planner: &mut Planner<char>) {
+        planner.client("A").update("/x", |_| Some('a'));
+    }
+
+    fn remover(planner: &mut Planner<char>) {
+        planner.client("B").remove("/x");
+    }
+
+    #[test]
+    fn includes_the_clients_and_acts_of_other_fragments() {
+        let mut composed: Planner<char> = Planner::new(Config::new());
+        assert_eq!(composed.include(&writer), Ok(()));
+        assert_eq!(composed.include(&remover), Ok(()));
+
+        let mut manual: Planner<char> = Planner::new(Config::new());
+        writer(&mut manual);
+        remover(&mut manual);
+
+        assert!(composed.clients().eq(["A", "B"]));
+
+        let a: Vec<_> = composed.orderings().collect();
+        let b: Vec<_> = manual.orderings().collect();
+        assert_eq!(a.len(), 70 * 4);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn refuses_to_include_a_fragment_with_an_existing_client() {
+        let mut planner: Planner<char> = Planner::new(Config::new());
+        writer(&mut planner);
+
+        assert_eq!(
+            planner.include(&writer),
+            Err(String::from("client 'A' is already defined"))
+        );
+        assert_eq!(planner.orderings().next().unwrap().len(), 4);
+    }
+
     #[test]
     fn produces_instructions_to_create_a_document() {
         let mut planner: Planner<Vec<char>> = Planner::new(Config::new());
@@ -253,6 +777,39 @@ mod tests {
         assert_eq!(s.read("/path/x.json"), Some((1, Some(Db::Doc(vec!['a'])))));
     }
 
+    #[test]
+    fn produces_instructions_to_write_several_documents_at_once() {
+        let mut planner: Planner<char> = Planner::new(Config::new());
+        planner
+            .client("A")
+            .write_many(&[("/path/x.json", Some('a')), ("/y.json", Some('b'))]);
+
+        for plan in planner.orderings() {
+            let last = plan.last().unwrap();
+            assert_eq!(
+                last.describe_op(),
+                "write_many([\"/path/x.json\", \"/y.json\"])"
+            );
+
+            let store = RefCell::new(DbStore::new(Config::new()));
+            let mut actor = Actor::new(&store, Config::new());
+
+            for act in plan {
+                actor.dispatch(act);
+            }
+
+            let s = store.into_inner();
+
+            assert_eq!(
+                s.read("/"),
+                Some((2, Some(Db::dir_from(&["path/", "y.json"]))))
+            );
+            assert_eq!(s.read("/path/"), Some((1, Some(Db::dir_from(&["x.json"])))));
+            assert_eq!(s.read("/path/x.json"), Some((1, Some(Db::Doc('a')))));
+            assert_eq!(s.read("/y.json"), Some((1, Some(Db::Doc('b')))));
+        }
+    }
+
     #[test]
     fn produces_instructions_to_update_a_document() {
         let mut planner: Planner<(char, usize)> = Planner::new(Config::new());
@@ -299,6 +856,425 @@ mod tests {
         assert_eq!(s.read("/path/x.json"), Some((2, None)));
     }
 
+    #[test]
+    fn expands_writes_into_one_update_per_value() {
+        let mut sugar: Planner<char> = Planner::new(Config::new());
+        sugar.client("A").writes("/x", ['a', 'b']);
+
+        let mut manual: Planner<char> = Planner::new(Config::new());
+        manual.client("A").update("/x", |_| Some('a'));
+        manual.client("A").update("/x", |_| Some('b'));
+
+        let a: Vec<_> = sugar.orderings().collect();
+        let b: Vec<_> = manual.orderings().collect();
+        assert_eq!(a, b);
+
+        let store = RefCell::new(DbStore::new(Config::new()));
+        let mut actor = Actor::new(&store, Config::new());
+
+        for act in sugar.orderings().next().unwrap() {
+            actor.dispatch(act);
+        }
+        assert_eq!(store.borrow().read("/x"), Some((2, Some(Db::Doc('b')))));
+    }
+
+    #[test]
+    fn explores_both_orders_of_concurrent_acts_from_one_client() {
+        let mut planner: Planner<char> = Planner::new(Config::new());
+        planner.client("A").writes("/x", ['a', 'b']);
+
+        let mut values = BTreeSet::new();
+
+        for plan in planner.orderings() {
+            let store = RefCell::new(DbStore::new(Config::new()));
+            let mut actor = Actor::new(&store, Config::new());
+
+            for act in plan {
+                actor.dispatch(act);
+            }
+            let store = store.borrow();
+
+            if let Some(Db::Doc(c)) = store.get("/x") {
+                values.insert(*c);
+            }
+        }
+        assert_eq!(values, BTreeSet::from(['a', 'b']));
+    }
+
+    #[test]
+    fn expands_read_then_write_into_an_update_of_the_existing_doc() {
+        let mut planner: Planner<usize> = Planner::new(Config::new());
+        planner.client("A").read_then_write("/x", |n| n + 1);
+
+        let store = RefCell::new(DbStore::new(Config::new()));
+        store
+            .borrow_mut()
+            .write("/".into(), None, Db::dir_from(&["x"]));
+        store.borrow_mut().write("/x".into(), None, Db::Doc(41));
+
+        let mut actor = Actor::new(&store, Config::new());
+
+        for act in planner.orderings().next().unwrap() {
+            actor.dispatch(act);
+        }
+        assert_eq!(store.borrow().read("/x"), Some((2, Some(Db::Doc(42)))));
+    }
+
+    fn final_counter_values(config: Config) -> BTreeSet<usize> {
+        let mut planner: Planner<usize> = Planner::new(config.clone());
+        planner.client("A").read_then_write("/x", |n| n + 1);
+        planner.client("B").read_then_write("/x", |n| n + 1);
+
+        let mut values = BTreeSet::new();
+
+        for plan in planner.orderings() {
+            let store = RefCell::new(DbStore::new(config.clone()));
+            store
+                .borrow_mut()
+                .write("/".into(), None, Db::dir_from(&["x"]));
+            store.borrow_mut().write("/x".into(), None, Db::Doc(0));
+
+            let mut a = Actor::new(&store, config.clone());
+            let mut b = Actor::new(&store, config.clone());
+
+            for act in plan {
+                let actor = if act.client_id == "A" { &mut a } else { &mut b };
+                actor.dispatch(act);
+            }
+            if let Some(Db::Doc(n)) = store.borrow().get("/x") {
+                values.insert(*n);
+            };
+        }
+        values
+    }
+
+    #[test]
+    fn does_not_lose_updates_with_coarse_grained_read_then_write() {
+        let config = Config::new().skip_links(true).fine_grained(false);
+        let values = final_counter_values(config);
+
+        assert_eq!(values, BTreeSet::from([2]));
+    }
+
+    #[test]
+    fn loses_updates_with_fine_grained_read_then_write() {
+        let config = Config::new().skip_links(true).fine_grained(true);
+        let values = final_counter_values(config);
+
+        assert_eq!(values, BTreeSet::from([1, 2]));
+    }
+
+    fn half_applied_counts(transaction: bool) -> BTreeSet<usize> {
+        fn writes(client: &mut Client<char>) {
+            client.update("/x", |_| Some('a'));
+            client.update("/y", |_| Some('a'));
+        }
+
+        let mut planner: Planner<char> = Planner::new(Config::new());
+
+        if transaction {
+            planner.client("A").transaction(writes);
+        } else {
+            writes(&mut planner.client("A"));
+        }
+        planner.client("B").update("/z", |_| Some('b'));
+
+        let mut counts = BTreeSet::new();
+
+        for plan in planner.orderings() {
+            let store = RefCell::new(DbStore::new(Config::new()));
+            let mut a = Actor::new(&store, Config::new());
+            let mut b = Actor::new(&store, Config::new());
+
+            for act in plan {
+                let actor = if act.client_id == "A" { &mut a } else { &mut b };
+                actor.dispatch(act);
+
+                let store = store.borrow();
+                let written = ["/x", "/y"]
+                    .iter()
+                    .filter(|key| store.get(**key) == Some(&Db::Doc('a')))
+                    .count();
+                counts.insert(written);
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn never_exposes_a_half_applied_transaction() {
+        assert_eq!(half_applied_counts(false), BTreeSet::from([0, 1, 2]));
+        assert_eq!(half_applied_counts(true), BTreeSet::from([0, 2]));
+    }
+
+    #[test]
+    fn schedules_a_transaction_as_a_single_act() {
+        let mut planner: Planner<char> = Planner::new(Config::new());
+
+        planner.client("A").transaction(|tx| {
+            tx.update("/x", |_| Some('a'));
+            tx.remove("/y");
+        });
+
+        let plans: Vec<_> = planner.orderings().collect();
+        assert_eq!(plans.len(), 1);
+        assert_eq!(plans[0].len(), 1);
+
+        let Op::Transaction(acts) = &plans[0][0].op else {
+            panic!("expected a transaction");
+        };
+        assert_eq!(acts.len(), 8);
+        assert_eq!(acts[0], Act::new("A", "/".into(), Op::List));
+        assert_eq!(acts[7], Act::new("A", "/".into(), Op::Unlink("y".into())));
+    }
+
+    #[test]
+    fn scripts_the_number_of_clients_given_by_the_config() {
+        for n in 2..=4 {
+            let config = Config::new().skip_links(true).fine_grained(true).clients(n);
+
+            let mut planner: Planner<usize> = Planner::new(config.clone());
+            planner.each_client(|client| client.read_then_write("/x", |n| n + 1));
+
+            let expected: Vec<_> = (0..n).map(|i| format!("c{}", i)).collect();
+            assert!(planner.clients().eq(expected.iter().map(|id| id.as_str())));
+
+            let lost_update = planner.orderings().find(|plan| {
+                let store = RefCell::new(DbStore::new(config.clone()));
+                store
+                    .borrow_mut()
+                    .write("/".into(), None, Db::dir_from(&["x"]));
+                store.borrow_mut().write("/x".into(), None, Db::Doc(0));
+
+                let mut actors: BTreeMap<_, _> = expected
+                    .iter()
+                    .map(|id| (id.as_str(), Actor::new(&store, config.clone())))
+                    .collect();
+
+                for act in plan {
+                    actors
+                        .get_mut(act.client_id.as_str())
+                        .unwrap()
+                        .dispatch(act);
+                }
+                let complete = store.borrow().get("/x") == Some(&Db::Doc(n));
+                !complete
+            });
+            assert!(lost_update.is_some());
+        }
+    }
+
+    #[test]
+    fn expands_churn_into_repeated_unchanged_updates() {
+        let mut planner: Planner<char> = Planner::new(Config::new());
+        planner.client("A").churn("/x", 3);
+
+        let plan = planner.orderings().next().unwrap();
+        let puts = plan.iter().filter(|act| matches!(act.op, Op::Put(_)));
+        assert_eq!(puts.count(), 3);
+    }
+
+    fn interleave(window: Option<usize>) -> Vec<String> {
+        let config = Config::new().fine_grained(false);
+        let mut planner: Planner<usize> = Planner::new(config);
+
+        planner.client("A").read_then_write("/x", |n| n + 1);
+        planner.client("B").read_then_write("/x", |n| n + 1);
+
+        if let Some(window) = window {
+            planner.fair(window);
+        }
+
+        let mut plans: Vec<String> = planner
+            .orderings()
+            .map(|plan| plan.iter().map(|act| act.client_id.as_str()).collect())
+            .collect();
+
+        plans.sort();
+        plans
+    }
+
+    #[test]
+    fn restricts_orderings_to_round_robin_with_a_fairness_window_of_one() {
+        assert_eq!(interleave(None).len(), 20);
+        assert_eq!(interleave(Some(1)), ["ABABAB", "BABABA"]);
+    }
+
+    #[test]
+    fn allows_longer_runs_by_one_client_with_a_wider_fairness_window() {
+        let plans = interleave(Some(2));
+
+        assert!(plans.contains(&String::from("AABBAB")));
+        assert!(!plans.contains(&String::from("AAABBB")));
+        assert_eq!(interleave(Some(3)), interleave(None));
+    }
+
+    fn count_orderings(config: Config) -> usize {
+        let mut planner: Planner<char> = Planner::new(config);
+
+        planner.client("A").update("/x.json", |_| Some('a'));
+        planner.client("B").update("/y.json", |_| Some('b'));
+
+        planner.orderings().count()
+    }
+
+    #[test]
+    fn skips_orderings_that_only_swap_independent_acts() {
+        assert_eq!(count_orderings(Config::new()), 280);
+        assert_eq!(count_orderings(Config::new().partial_order(true)), 32);
+    }
+
+    struct AlwaysHolds;
+
+    impl Invariant<char> for AlwaysHolds {
+        fn check(&self, _: &DbStore<char>) -> Result<(), Vec<String>> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn keeps_all_orderings_when_checks_may_relate_other_keys() {
+        let orderings = |setup: fn(&mut Planner<char>)| {
+            let mut planner = Planner::new(Config::new().partial_order(true));
+            setup(&mut planner);
+            planner.client("A").update("/x.json", |_| Some('a'));
+            planner.client("B").update("/y.json", |_| Some('b'));
+            (planner.is_reduced(), planner.orderings().count())
+        };
+
+        assert_eq!(orderings(|_| {}), (true, 32));
+        assert_eq!(
+            orderings(|planner| planner.invariant(AlwaysHolds)),
+            (false, 280)
+        );
+        assert_eq!(
+            orderings(|planner| planner.keep_all_orderings()),
+            (false, 280)
+        );
+    }
+
+    #[test]
+    fn keeps_both_orders_of_conflicting_writes_to_the_same_key() {
+        let config = Config::new().fine_grained(false).partial_order(true);
+        let mut planner: Planner<usize> = Planner::new(config);
+
+        planner.client("A").read_then_write("/x", |n| n + 1);
+        planner.client("B").read_then_write("/x", |n| n + 1);
+
+        let plans: Vec<String> = planner
+            .orderings()
+            .map(|plan| plan.iter().map(|act| act.client_id.as_str()).collect())
+            .collect();
+
+        assert!(plans.len() < interleave(None).len());
+        assert!(plans.iter().any(|plan| plan.ends_with('A')));
+        assert!(plans.iter().any(|plan| plan.ends_with('B')));
+    }
+
+    #[test]
+    fn describes_the_keys_each_act_reads_and_writes() {
+        let x = Path::from("/path/x.json");
+        let y = Path::from("/y");
+
+        let get: Act<char> = Act::new("A", x.clone(), Op::Get);
+        assert_eq!(get.footprint(), [(&x, false)]);
+
+        let writes = vec![(x.clone(), Some('a')), (y.clone(), None)];
+        let batch: Act<char> = Act::new("A", x.clone(), Op::WriteMany(writes));
+        assert_eq!(batch.footprint(), [(&x, true), (&y, true)]);
+    }
+
+    #[test]
+    fn adds_a_variant_of_each_ordering_for_every_injected_fault() {
+        let count = |config: Config| {
+            let mut planner: Planner<char> = Planner::new(config);
+            planner.client("A").update("/x.json", |_| Some('a'));
+            planner.orderings().count()
+        };
+
+        assert_eq!(count(Config::new()), 2);
+        assert_eq!(count(Config::new().inject(Fault::Drop)), 6);
+        assert_eq!(
+            count(Config::new().inject(Fault::Drop).inject(Fault::Duplicate)),
+            10
+        );
+    }
+
+    #[test]
+    fn fingerprints_the_acts_a_planner_builds() {
+        let fingerprint = |extra: bool, config: Config| {
+            let mut planner: Planner<char> = Planner::new(config);
+            planner.client("A").update("/x.json", |_| Some('a'));
+            if extra {
+                planner.client("B").remove("/x.json");
+            }
+            planner.fingerprint()
+        };
+
+        assert_eq!(
+            fingerprint(false, Config::new()),
+            fingerprint(false, Config::new())
+        );
+        assert_ne!(
+            fingerprint(false, Config::new()),
+            fingerprint(true, Config::new())
+        );
+
+        let config = Config::new().update(Update::GetBeforePut);
+        assert_ne!(
+            fingerprint(false, Config::new()),
+            fingerprint(false, config)
+        );
+    }
+
+    #[test]
+    fn finds_each_ordering_by_its_index_with_and_without_filters() {
+        let configs = [
+            Config::new(),
+            Config::new().inject(Fault::Drop).inject(Fault::Duplicate),
+            Config::new().partial_order(true),
+        ];
+
+        for config in configs {
+            let mut planner: Planner<char> = Planner::new(config);
+            planner.client("A").update("/x.json", |_| Some('a'));
+            planner.client("B").update("/y.json", |_| Some('b'));
+
+            let indices = |plan: &[&Act<char>]| -> Vec<_> {
+                plan.iter().map(|act| planner.act_index(act)).collect()
+            };
+            let count = planner.ordering_count();
+            assert_eq!(count, planner.orderings().count());
+
+            for (i, plan) in planner.orderings().enumerate().step_by(7) {
+                let found = planner.ordering_at(i).unwrap();
+                assert_eq!(indices(&found), indices(&plan));
+            }
+            assert!(planner.ordering_at(count).is_none());
+        }
+    }
+
+    #[test]
+    fn puts_a_fault_act_before_each_write_in_its_faulty_variants() {
+        let config = Config::new().inject(Fault::Duplicate);
+        let mut planner: Planner<char> = Planner::new(config);
+        planner.client("A").update("/x.json", |_| Some('a'));
+
+        let plans: Vec<_> = planner.orderings().take(3).collect();
+        let writes: Vec<_> = (0..plans[0].len())
+            .filter(|i| !plans[0][*i].is_read_only())
+            .collect();
+
+        assert_eq!(writes.len(), 2);
+
+        for (plan, &i) in plans[1..].iter().zip(&writes) {
+            assert_eq!(plan.len(), plans[0].len() + 1);
+            assert!(plan[i].op == Op::Fault(Fault::Duplicate));
+            assert!(std::ptr::eq(plan[i + 1], plans[0][i]));
+        }
+        assert!(planner.act_index(plans[1][writes[0]]).is_some());
+    }
+
     #[test]
     fn plans_a_top_level_document_update() {
         let mut planner: Planner<Vec<char>> = Planner::new(Config::new());
@@ -0,0 +1,755 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::db::rule_name;
+
+pub(crate) const SPLIT: &str =
+    "========================================================================";
+
+const MAX_SWIMLANES: usize = 8;
+
+const MAX_RULE_EXAMPLES: usize = 3;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub enum Level {
+    Quiet,
+    #[default]
+    Normal,
+    Verbose,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Report {
+    pub passed: bool,
+    pub count: usize,
+    pub bounded: bool,
+    pub truncated: bool,
+    pub expected: Option<usize>,
+    pub unstable: Option<Vec<(bool, usize)>>,
+    pub sample: Option<Sample>,
+    pub failure: Option<FailureDetails>,
+}
+
+// The seed a sampled run drew its orderings from and the indices it drew, any
+// of which can be checked again with Runner::replay_index().
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Sample {
+    pub seed: u64,
+    pub orderings: Vec<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FailureDetails {
+    pub index: usize,
+    pub errors: Vec<String>,
+    pub state: Vec<(String, String)>,
+    pub plan: Vec<(String, Option<String>)>,
+    pub lanes: Vec<(String, String)>,
+    pub step: Option<usize>,
+    pub shrunk_from: Option<usize>,
+    pub key: Option<String>,
+    pub history: Vec<(String, String)>,
+    pub explanation: Option<usize>,
+}
+
+impl Report {
+    pub fn expect_count(&mut self, min: usize) {
+        self.expected = Some(min);
+
+        if self.count < min {
+            self.passed = false;
+        }
+    }
+
+    pub fn expect_stable(&mut self, runs: &[(bool, usize)]) {
+        if runs.iter().any(|run| *run != (self.passed, self.count)) {
+            self.unstable = Some(runs.to_vec());
+            self.passed = false;
+        }
+    }
+
+    fn is_under_explored(&self) -> bool {
+        self.expected.is_some_and(|min| self.count < min)
+    }
+
+    pub fn is_exhaustive(&self) -> bool {
+        !self.truncated && !self.bounded
+    }
+
+    pub fn status(&self) -> &'static str {
+        if self.unstable.is_some() {
+            return "FAIL (nondeterministic)";
+        }
+        match (self.passed, self.truncated, self.bounded) {
+            (true, false, false) => "PASS (exhaustive)",
+            (true, false, true) => "PASS (bounded)",
+            (true, true, _) => "PASS (partial)",
+            (false, _, _) => "FAIL",
+        }
+    }
+
+    pub fn write(&self, w: &mut dyn Write, level: Level) -> io::Result<()> {
+        writeln!(w, "    result: {}", self.status())?;
+        writeln!(w, "    checked executions: {}", format_number(self.count))?;
+
+        if let Some(sample) = &self.sample {
+            writeln!(w, "    sample seed: {}", sample.seed)?;
+
+            if level == Level::Verbose {
+                writeln!(w, "    sampled orderings: {:?}", sample.orderings)?;
+            }
+        }
+
+        if let (true, Some(min)) = (self.is_under_explored(), self.expected) {
+            writeln!(
+                w,
+                "    expected executions: at least {}",
+                format_number(min)
+            )?;
+        }
+
+        if let Some(runs) = &self.unstable {
+            writeln!(w, "    repeated runs:")?;
+            for (passed, count) in runs {
+                let status = if *passed { "PASS" } else { "FAIL" };
+                writeln!(w, "        - {} ({})", status, format_number(*count))?;
+            }
+        }
+
+        let Some(failure) = &self.failure else {
+            return Ok(());
+        };
+
+        writeln!(w, "    ordering: {}", failure.index)?;
+        writeln!(w, "    errors:")?;
+        for error in &failure.errors {
+            writeln!(w, "        - {}", error)?;
+        }
+        writeln!(w, "    state:")?;
+        for (key, value) in &failure.state {
+            writeln!(w, "        '{}' => {}", key, value)?;
+        }
+        if let Some(len) = failure.shrunk_from {
+            writeln!(w, "    execution (shrunk from {} steps):", len)?;
+        } else {
+            writeln!(w, "    execution:")?;
+        }
+        for (i, (act, write)) in failure.plan.iter().enumerate() {
+            if Some(i) == failure.step {
+                writeln!(w, "    ==> {}", act)?;
+            } else {
+                writeln!(w, "        {}", act)?;
+            }
+            if let (Level::Verbose, Some(write)) = (level, write) {
+                writeln!(w, "            -> {}", write)?;
+            }
+        }
+        if failure.step.is_none() {
+            writeln!(w, "    ==> after_each")?;
+        }
+        if let Some(key) = &failure.key {
+            writeln!(w, "    history of '{}':", key)?;
+            for (client_id, value) in &failure.history {
+                writeln!(w, "        {} => {}", client_id, value)?;
+            }
+        }
+        if let Some(i) = failure.explanation {
+            writeln!(
+                w,
+                "    swapping steps {} and {} turns FAIL into PASS",
+                i,
+                i + 1
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn write_swimlanes(&self, w: &mut dyn Write) -> io::Result<()> {
+        let Some(failure) = &self.failure else {
+            return Ok(());
+        };
+
+        let mut clients: Vec<&str> = Vec::new();
+        for (client_id, _) in &failure.lanes {
+            if !clients.contains(&client_id.as_str()) {
+                clients.push(client_id);
+            }
+        }
+        let hidden = clients.len().saturating_sub(MAX_SWIMLANES);
+        clients.truncate(MAX_SWIMLANES);
+
+        let steps: Vec<_> = (0..failure.lanes.len())
+            .map(|i| {
+                let marker = if Some(i) == failure.step { "*" } else { "" };
+                format!("{}{}", i, marker)
+            })
+            .collect();
+
+        let widths: Vec<_> = failure
+            .lanes
+            .iter()
+            .zip(&steps)
+            .map(|((_, op), step)| op.len().max(step.len()))
+            .collect();
+
+        let label = clients.iter().map(|c| c.len()).max().unwrap_or(0);
+
+        let mut row = format!("{:label$}", "", label = label);
+        for (step, width) in steps.iter().zip(&widths) {
+            row.push_str(&format!(" | {:width$}", step, width = width));
+        }
+        writeln!(w, "    swimlanes:")?;
+        writeln!(w, "        {}", row.trim_end())?;
+
+        for client in &clients {
+            let mut row = format!("{:label$}", client, label = label);
+
+            for ((client_id, op), width) in failure.lanes.iter().zip(&widths) {
+                let cell = if client_id == client { op.as_str() } else { "" };
+                row.push_str(&format!(" | {:width$}", cell, width = width));
+            }
+            writeln!(w, "        {}", row.trim_end())?;
+        }
+        if hidden > 0 {
+            writeln!(w, "        ({} more clients not shown)", hidden)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_tap(&self, w: &mut dyn Write, n: usize, name: &str) -> io::Result<()> {
+        let status = if self.passed { "ok" } else { "not ok" };
+        let count = format_number(self.count);
+
+        writeln!(w, "{} {} - {} ({} executions)", status, n, name, count)?;
+
+        if let (true, Some(min)) = (self.is_under_explored(), self.expected) {
+            writeln!(w, "  ---")?;
+            writeln!(w, "  expected: {}", min)?;
+            writeln!(w, "  ...")?;
+        }
+
+        if let Some(runs) = &self.unstable {
+            let counts: Vec<_> = runs.iter().map(|(_, count)| count.to_string()).collect();
+
+            writeln!(w, "  ---")?;
+            writeln!(w, "  nondeterministic counts: [{}]", counts.join(", "))?;
+            writeln!(w, "  ...")?;
+        }
+
+        let Some(failure) = &self.failure else {
+            return Ok(());
+        };
+
+        writeln!(w, "  ---")?;
+        writeln!(w, "  errors:")?;
+        for error in &failure.errors {
+            writeln!(w, "    - {:?}", error)?;
+        }
+        match failure.step {
+            Some(step) => writeln!(w, "  step: {}", step)?,
+            None => writeln!(w, "  step: after_each")?,
+        }
+        writeln!(w, "  plan:")?;
+        for (act, _) in &failure.plan {
+            writeln!(w, "    - {:?}", act)?;
+        }
+        writeln!(w, "  ...")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FailureReport {
+    pub scenario: String,
+    pub config: Config,
+    pub fingerprint: u64,
+    pub acts: Vec<usize>,
+    pub plan: Vec<String>,
+    pub step: Option<usize>,
+    pub errors: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Outcome {
+    pub name: String,
+    pub exhaustive: bool,
+    pub report: Report,
+}
+
+// Exhaustiveness is saved alongside the report so that readers of the JSON
+// summary do not have to work it out from the bounded and truncated flags.
+impl Outcome {
+    pub fn new(name: &str, report: Report) -> Outcome {
+        Outcome {
+            name: name.to_string(),
+            exhaustive: report.is_exhaustive(),
+            report,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RunSummary {
+    pub configs: Vec<(Config, Vec<Outcome>)>,
+    pub total: usize,
+    pub rules: BTreeMap<String, RuleTally>,
+}
+
+// How many errors of the failing scenarios broke a rule, with the first few of
+// them kept as examples. Only the count grows with the size of the run.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RuleTally {
+    pub count: usize,
+    pub examples: Vec<String>,
+}
+
+impl RunSummary {
+    pub fn new() -> RunSummary {
+        RunSummary::default()
+    }
+
+    pub fn push(&mut self, config: &Config, outcome: Outcome) {
+        self.total += outcome.report.count;
+
+        for error in outcome.report.failure.iter().flat_map(|f| &f.errors) {
+            let tally = self.rules.entry(rule_name(error).to_string()).or_default();
+            tally.count += 1;
+
+            if tally.examples.len() < MAX_RULE_EXAMPLES {
+                tally.examples.push(error.clone());
+            }
+        }
+
+        if let Some((_, outcomes)) = self.configs.iter_mut().find(|(c, _)| c == config) {
+            outcomes.push(outcome);
+        } else {
+            self.configs.push((config.clone(), vec![outcome]));
+        }
+    }
+
+    pub fn merge(&mut self, other: RunSummary) {
+        for (config, outcomes) in other.configs {
+            for outcome in outcomes {
+                self.push(&config, outcome);
+            }
+        }
+    }
+
+    pub fn print(&self, level: Level) {
+        self.write(&mut io::stdout(), level).unwrap();
+    }
+
+    pub fn write(&self, w: &mut dyn Write, level: Level) -> io::Result<()> {
+        writeln!(w, "{}", SPLIT)?;
+        writeln!(w, "SUMMARY")?;
+        writeln!(w, "{}", SPLIT)?;
+        writeln!(w)?;
+
+        for (config, outcomes) in &self.configs {
+            writeln!(w, "{:?}", config)?;
+            let mut subtotal = 0;
+
+            for outcome in outcomes {
+                let report = &outcome.report;
+                subtotal += report.count;
+
+                if level == Level::Quiet && report.passed {
+                    continue;
+                }
+                let count = format_number(report.count);
+
+                if let (true, Some(sample)) = (report.passed, &report.sample) {
+                    writeln!(
+                        w,
+                        "    - PASS (sampled with seed {}, {} checked): {}",
+                        sample.seed, count, outcome.name
+                    )?;
+                } else if report.passed && report.truncated {
+                    writeln!(
+                        w,
+                        "    - PASS (partial, {} checked): {}",
+                        count, outcome.name
+                    )?;
+                } else {
+                    writeln!(w, "    - {} ({}): {}", report.status(), count, outcome.name)?;
+                }
+            }
+            let passed = outcomes.iter().filter(|o| o.report.passed).count();
+
+            writeln!(
+                w,
+                "    {} passed, {} failed; executions checked = {}",
+                passed,
+                outcomes.len() - passed,
+                format_number(subtotal)
+            )?;
+            writeln!(w)?;
+        }
+        writeln!(
+            w,
+            "Total executions checked = {}",
+            format_number(self.total)
+        )?;
+        self.write_rules(w, level)?;
+        writeln!(w)
+    }
+
+    // One line tallying every rule broken in the run, most often broken first.
+    fn write_rules(&self, w: &mut dyn Write, level: Level) -> io::Result<()> {
+        if self.rules.is_empty() {
+            return Ok(());
+        }
+        let mut rules: Vec<_> = self.rules.iter().collect();
+        rules.sort_by_key(|(_, tally)| std::cmp::Reverse(tally.count));
+
+        let counts: Vec<_> = rules
+            .iter()
+            .map(|(rule, tally)| format!("{}: {}", rule, format_number(tally.count)))
+            .collect();
+        writeln!(w, "Errors by rule: {}", counts.join(" / "))?;
+
+        if level == Level::Verbose {
+            for (rule, tally) in rules {
+                writeln!(w, "    {}:", rule)?;
+                for example in &tally.examples {
+                    writeln!(w, "        - {}", example)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> serde_json::Result<RunSummary> {
+        serde_json::from_str(json)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn write_json<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        std::fs::write(path, self.to_json()?)
+    }
+}
+
+pub(crate) fn format_number(n: usize) -> String {
+    n.to_string()
+        .as_bytes()
+        .rchunks(3)
+        .rev()
+        .map(|byte| std::str::from_utf8(byte))
+        .collect::<Result<Vec<&str>, _>>()
+        .unwrap()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pass() -> Outcome {
+        Outcome::new(
+            "update",
+            Report {
+                passed: true,
+                count: 1234,
+                bounded: false,
+                truncated: false,
+                expected: None,
+                unstable: None,
+                sample: None,
+                failure: None,
+            },
+        )
+    }
+
+    fn fail() -> Outcome {
+        Outcome::new(
+            "update/delete conflict",
+            Report {
+                passed: false,
+                count: 7,
+                bounded: false,
+                truncated: false,
+                expected: None,
+                unstable: None,
+                sample: None,
+                failure: Some(FailureDetails {
+                    index: 6,
+                    errors: vec![String::from("dir '/path/' is not linked")],
+                    state: vec![(String::from("/path/x"), String::from("<null>"))],
+                    plan: vec![
+                        (String::from("[A] get('/path/x')"), None),
+                        (
+                            String::from("[B] rm('/path/x')"),
+                            Some(String::from("'/path/x' = { rev: 2, value: <null> }")),
+                        ),
+                    ],
+                    lanes: vec![
+                        (String::from("A"), String::from("get('/path/x')")),
+                        (String::from("B"), String::from("rm('/path/x')")),
+                    ],
+                    step: Some(1),
+                    shrunk_from: None,
+                    key: Some(String::from("/path/x")),
+                    history: vec![(String::from("B"), String::from("<null>"))],
+                    explanation: None,
+                }),
+            },
+        )
+    }
+
+    #[cfg(feature = "serde")]
+    fn render(summary: &RunSummary, level: Level) -> String {
+        let mut out = Vec::new();
+        summary.write(&mut out, level).unwrap();
+        for (_, outcomes) in &summary.configs {
+            for outcome in outcomes {
+                outcome.report.write(&mut out, level).unwrap();
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn merges_outcomes_under_matching_configs() {
+        let a = Config::new();
+        let b = Config::new().skip_links(true);
+
+        let mut left = RunSummary::new();
+        left.push(&a, pass());
+
+        let mut right = RunSummary::new();
+        right.push(&b, pass());
+        right.push(&a, fail());
+
+        left.merge(right);
+
+        assert_eq!(left.configs.len(), 2);
+        assert_eq!(left.configs[0].0, a);
+        assert_eq!(left.configs[0].1, vec![pass(), fail()]);
+        assert_eq!(left.configs[1].0, b);
+        assert_eq!(left.configs[1].1, vec![pass()]);
+        assert_eq!(left.total, 1234 * 2 + 7);
+    }
+
+    #[test]
+    fn tallies_errors_by_rule_keeping_a_few_examples() {
+        let linked = |i: usize| {
+            format!(
+                "dir '/' does not include name '{}', required by doc '/{}'",
+                i, i
+            )
+        };
+        let mut outcome = fail();
+        let failure = outcome.report.failure.as_mut().unwrap();
+        failure.errors = (0..5).map(linked).collect();
+        failure.errors.push(String::from("'/x' was written"));
+
+        let mut summary = RunSummary::new();
+        summary.push(&Config::new(), outcome.clone());
+        summary.push(&Config::new(), pass());
+
+        let mut other = RunSummary::new();
+        other.push(&Config::new(), outcome);
+        summary.merge(other);
+
+        assert_eq!(summary.rules.len(), 2);
+        assert_eq!(summary.rules["dir_linked"].count, 10);
+        assert_eq!(
+            summary.rules["dir_linked"].examples,
+            [linked(0), linked(1), linked(2)]
+        );
+        assert_eq!(summary.rules["other"].count, 2);
+
+        let mut out = Vec::new();
+        summary.write(&mut out, Level::Normal).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("Errors by rule: dir_linked: 10 / other: 2\n"));
+    }
+
+    #[test]
+    fn fails_a_report_whose_repeated_runs_disagree() {
+        let mut stable = pass().report;
+        stable.expect_stable(&[(true, 1234), (true, 1234)]);
+
+        assert!(stable.passed);
+        assert_eq!(stable.unstable, None);
+
+        let mut unstable = pass().report;
+        unstable.expect_stable(&[(true, 1234), (true, 1230)]);
+
+        assert!(!unstable.passed);
+        assert_eq!(unstable.status(), "FAIL (nondeterministic)");
+        assert_eq!(unstable.unstable, Some(vec![(true, 1234), (true, 1230)]));
+    }
+
+    #[test]
+    fn places_each_act_in_its_client_lane() {
+        let mut out = Vec::new();
+        fail().report.write_swimlanes(&mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+
+        assert_eq!(
+            lines,
+            [
+                "    swimlanes:",
+                "          | 0              | 1*",
+                "        A | get('/path/x') |",
+                "        B |                | rm('/path/x')",
+            ]
+        );
+    }
+
+    #[test]
+    fn numbers_the_explained_swap_like_the_failing_step() {
+        let mut report = fail().report;
+        report.failure.as_mut().unwrap().explanation = Some(0);
+
+        let mut out = Vec::new();
+        report.write(&mut out, Level::Normal).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("    ==> [B] rm('/path/x')\n"));
+        assert!(out.contains("    swapping steps 0 and 1 turns FAIL into PASS\n"));
+    }
+
+    #[test]
+    fn truncates_swimlanes_with_many_clients() {
+        let mut report = fail().report;
+        let failure = report.failure.as_mut().unwrap();
+
+        failure.lanes = (0..10)
+            .map(|i| (format!("c{}", i), String::from("get('/x')")))
+            .collect();
+        failure.step = Some(9);
+
+        let mut out = Vec::new();
+        report.write_swimlanes(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(out.lines().count(), 11);
+        assert!(out.contains("c7 |"));
+        assert!(!out.contains("c8 |"));
+        assert!(out.ends_with("        (2 more clients not shown)\n"));
+    }
+
+    #[test]
+    fn distinguishes_partial_searches_in_the_summary() {
+        let mut report = pass().report;
+        report.truncated = true;
+        let partial = Outcome::new("update", report);
+
+        let mut summary = RunSummary::new();
+        summary.push(&Config::new(), pass());
+        summary.push(&Config::new(), partial);
+
+        let mut out = Vec::new();
+        summary.write(&mut out, Level::Normal).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("    - PASS (exhaustive) (1,234): update\n"));
+        assert!(out.contains("    - PASS (partial, 1,234 checked): update\n"));
+    }
+
+    #[test]
+    fn names_the_seed_of_a_sampled_pass() {
+        let mut report = pass().report;
+        report.truncated = true;
+        report.sample = Some(Sample {
+            seed: 42,
+            orderings: vec![3, 17],
+        });
+
+        let mut out = Vec::new();
+        report.write(&mut out, Level::Verbose).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("    sample seed: 42\n"));
+        assert!(out.contains("    sampled orderings: [3, 17]\n"));
+
+        let mut summary = RunSummary::new();
+        summary.push(&Config::new(), Outcome::new("update", report));
+
+        let mut out = Vec::new();
+        summary.write(&mut out, Level::Normal).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("    - PASS (sampled with seed 42, 1,234 checked): update\n"));
+    }
+
+    #[test]
+    fn tells_exhaustive_searches_apart_from_bounded_and_partial_ones() {
+        let mut report = pass().report;
+        assert!(report.is_exhaustive());
+
+        report.bounded = true;
+        assert!(!report.is_exhaustive());
+
+        report.bounded = false;
+        report.truncated = true;
+        assert!(!report.is_exhaustive());
+        assert!(!Outcome::new("update", report).exhaustive);
+    }
+
+    #[test]
+    fn formats_execution_counts_with_separators() {
+        assert_eq!(format_number(7), "7");
+        assert_eq!(format_number(1234), "1,234");
+        assert_eq!(format_number(1234567), "1,234,567");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn round_trips_pass_and_fail_through_json() {
+        let mut summary = RunSummary::new();
+        summary.push(&Config::new(), pass());
+        summary.push(&Config::new(), fail());
+
+        let reloaded = RunSummary::from_json(&summary.to_json().unwrap()).unwrap();
+
+        assert_eq!(reloaded, summary);
+        assert_eq!(
+            render(&reloaded, Level::Verbose),
+            render(&summary, Level::Verbose)
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn writes_the_summary_as_json_to_a_file() {
+        let mut summary = RunSummary::new();
+        summary.push(&Config::new(), pass());
+        summary.push(&Config::new(), fail());
+
+        let path = std::env::temp_dir().join(format!("mc2-summary-{}.json", std::process::id()));
+        summary.write_json(&path).unwrap();
+
+        let json = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(json.contains(r#""name":"update","exhaustive":true"#));
+
+        let reloaded = RunSummary::from_json(&json).unwrap();
+        assert_eq!(reloaded.total, 1241);
+        assert_eq!(reloaded, summary);
+    }
+}
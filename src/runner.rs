@@ -1,40 +1,128 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Debug;
-use std::sync::{mpsc, Mutex};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::actor::Actor;
-use crate::config::Config;
-use crate::db::{Checker, Db, DbStore};
-use crate::planner::{Act, Client, Planner};
+use crate::config::{Config, Count};
+use crate::db::{alias_rule, AliasRule, Checker, Db, DbStore, Invariant};
+use crate::path::Path;
+#[cfg(feature = "serde")]
+use crate::planner::SavedAct;
+use crate::planner::{Act, Client, Op, Planner};
+use crate::report::{FailureDetails, FailureReport, Outcome, Report, RunSummary, Sample, SPLIT};
+use crate::store::Rev;
+
+pub use crate::report::Level;
 
-const SPLIT: &str = "========================================================================";
+type InitFn<T> = Box<dyn Fn(Client<T>)>;
+type PlanFn<T> = Box<dyn Fn(&mut Planner<T>)>;
+type BeforeFn = Arc<dyn Fn() + Send + Sync>;
+type AfterFn<T> = Arc<dyn Fn(&DbStore<T>) -> Result<(), String> + Send + Sync>;
 
 struct Scenario<T> {
     name: String,
-    init: Box<dyn Fn(Client<T>)>,
-    plan: Box<dyn Fn(&mut Planner<T>)>,
+    init: InitFn<T>,
+    plan: PlanFn<T>,
+    before_each: Option<BeforeFn>,
+    after_each: Option<AfterFn<T>>,
 }
 
-type Outcome = (String, bool, usize);
-
 #[derive(Default)]
 pub struct Runner<T> {
     configs: Vec<Config>,
     scenarios: Vec<Scenario<T>>,
-    results: Vec<(Config, Vec<Outcome>)>,
+    results: RunSummary,
+    level: Level,
+    aliases: Option<AliasRule<T>>,
+    expected: BTreeMap<String, usize>,
+    seed: Option<u64>,
+    runs: usize,
+    swimlanes: bool,
+    limits: Limits,
+    workers: Option<usize>,
+    sample: Option<(u64, usize)>,
+    failures: Vec<FailureReport>,
+}
+
+// A failure saved with everything needed to run it again without the
+// scenario: the initial store, and the acts of the failing plan with the
+// values their puts wrote.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct Reproducer<T> {
+    config: Config,
+    clients: Vec<String>,
+    store: serde_json::Value,
+    plan: Vec<SavedAct<T>>,
+    step: Option<usize>,
+    errors: Vec<String>,
+}
+
+#[cfg(feature = "serde")]
+impl<T> Reproducer<T>
+where
+    T: Clone + Sync + Serialize + DeserializeOwned + 'static,
+{
+    fn load_store(&self) -> Result<DbStore<T>, String> {
+        DbStore::from_json(&self.store.to_string()).map_err(|e| e.to_string())
+    }
+
+    // Runs the saved plan against the store, checking only the config's
+    // rules, since invariants, aliases and hooks are closures held by the
+    // runner rather than the file.
+    fn run(&self, store: &DbStore<T>) -> Result<(), (Option<usize>, Vec<String>)> {
+        let client_ids: Vec<_> = self.clients.iter().map(String::as_str).collect();
+        let acts: Vec<_> = self.plan.iter().map(SavedAct::to_act).collect();
+        let plan: Vec<_> = acts.iter().collect();
+
+        let execution = Execution {
+            config: &self.config,
+            client_ids: &client_ids,
+            store,
+            aliases: None,
+            invariants: &[],
+            before_each: None,
+            after_each: None,
+        };
+        execution
+            .run(&plan, None)
+            .map_err(|failure| (failure.step, failure.errors))
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Limits {
+    max_execs: Option<usize>,
+    timeout: Option<Duration>,
 }
 
 impl<T> Runner<T>
 where
-    T: Clone + Debug + Send,
+    T: Clone + Debug + Send + Sync,
 {
     pub fn new() -> Runner<T> {
         Runner {
             configs: Vec::new(),
             scenarios: Vec::new(),
-            results: Vec::new(),
+            results: RunSummary::new(),
+            level: Level::Normal,
+            aliases: None,
+            expected: BTreeMap::new(),
+            seed: None,
+            runs: 1,
+            swimlanes: false,
+            limits: Limits::default(),
+            workers: None,
+            sample: None,
+            failures: Vec::new(),
         }
     }
 
@@ -42,6 +130,223 @@ where
         self.configs.extend(configs.iter().cloned());
     }
 
+    pub fn verbosity(&mut self, level: Level) {
+        self.level = level;
+    }
+
+    pub fn limits(&mut self, max_execs: Option<usize>, timeout: Option<Duration>) {
+        self.limits = Limits { max_execs, timeout };
+    }
+
+    pub fn workers(&mut self, n: usize) {
+        self.workers = Some(n.max(1));
+    }
+
+    pub fn swimlanes(&mut self, enabled: bool) {
+        self.swimlanes = enabled;
+    }
+
+    pub fn normalize_keys<F>(&mut self, normalize: F)
+    where
+        T: PartialEq + 'static,
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.aliases = Some(alias_rule(normalize));
+    }
+
+    pub fn shuffle(&mut self, seed: u64) {
+        self.seed = Some(seed);
+    }
+
+    // Checks n orderings of each scenario drawn at random from the seed, and
+    // reports a pass over fewer than all of them as truncated. With
+    // partial_order or a fairness window the planner cannot count or index
+    // orderings without walking them, so sampling still walks every ordering
+    // up to the last one drawn, and only saves checking the rest.
+    pub fn sample(&mut self, seed: u64, n: usize) {
+        self.sample = Some((seed, n));
+    }
+
+    // Each scenario is checked `runs` times and fails if the runs disagree on
+    // the verdict or the count. Which ordering fails first, and so the count
+    // of a failing run, depends on how orderings are split between workers,
+    // so every run is then made with a single worker.
+    pub fn verify_determinism(&mut self, runs: usize) {
+        self.runs = runs.max(1);
+    }
+
+    pub fn expect_executions(&mut self, scenario: &str, min: usize) {
+        self.expected.insert(scenario.to_string(), min);
+    }
+
+    pub fn summary(&self) -> RunSummary {
+        self.results.clone()
+    }
+
+    pub fn failures(&self) -> &[FailureReport] {
+        &self.failures
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn write_failures(&self, w: &mut dyn Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut *w, &self.failures)?;
+        writeln!(w)
+    }
+
+    // Replays each failure written by write_failures() against the scenario of
+    // the same name, and fails unless it reproduces the same errors at the same
+    // step. Failures are saved as indices of the acts the planner built, so one
+    // saved from a scenario whose planner fingerprint has since changed is
+    // refused rather than replayed against different acts.
+    #[cfg(feature = "serde")]
+    pub fn replay<P>(&self, path: P) -> Result<(), String>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        let json = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let failures: Vec<FailureReport> =
+            serde_json::from_str(&json).map_err(|e| e.to_string())?;
+
+        for failure in &failures {
+            let name = &failure.scenario;
+            let scenario = self.find_scenario(name)?;
+            let runner = self.scenario_runner(&failure.config, scenario);
+
+            let plan: Option<Vec<_>> = failure
+                .acts
+                .iter()
+                .map(|i| runner.planner.act_at(*i))
+                .collect();
+            let plan = plan.filter(|_| runner.planner.fingerprint() == failure.fingerprint);
+
+            let Some(plan) = plan else {
+                return Err(format!(
+                    "scenario '{}' no longer builds the same plan",
+                    name
+                ));
+            };
+
+            match runner.replay(&plan) {
+                Err((step, errors)) if step == failure.step && errors == failure.errors => {}
+                Err((step, errors)) => {
+                    return Err(format!(
+                        "scenario '{}' failed {} with {:?}, expected {} with {:?}",
+                        name,
+                        describe_step(step),
+                        errors,
+                        describe_step(failure.step),
+                        failure.errors
+                    ));
+                }
+                Ok(()) => return Err(format!("scenario '{}' no longer fails", name)),
+            }
+        }
+        Ok(())
+    }
+
+    // Saves a failure as a reproducer that run_reproducer() can run in a
+    // runner that has no scenarios at all. Only a failure of the config's
+    // rules can be saved this way; one that takes an invariant, an alias rule
+    // or a hook to find is refused.
+    #[cfg(feature = "serde")]
+    pub fn to_reproducer(&self, failure: &FailureReport) -> Result<String, String>
+    where
+        T: Serialize + DeserializeOwned + 'static,
+    {
+        let name = &failure.scenario;
+        let scenario = self.find_scenario(name)?;
+        let runner = self.scenario_runner(&failure.config, scenario);
+
+        let plan: Option<Vec<_>> = failure
+            .acts
+            .iter()
+            .map(|i| runner.planner.act_at(*i))
+            .collect();
+        let plan = plan.filter(|_| runner.planner.fingerprint() == failure.fingerprint);
+
+        let Some(plan) = plan else {
+            return Err(format!(
+                "scenario '{}' no longer builds the same plan",
+                name
+            ));
+        };
+
+        let json = runner.create_store().to_json().map_err(|e| e.to_string())?;
+        let reproducer = Reproducer {
+            config: failure.config.clone(),
+            clients: runner.planner.clients().map(String::from).collect(),
+            store: serde_json::from_str(&json).map_err(|e| e.to_string())?,
+            plan: runner.save_plan(&plan),
+            step: failure.step,
+            errors: failure.errors.clone(),
+        };
+
+        let result = reproducer.run(&reproducer.load_store()?);
+
+        if result != Err((failure.step, failure.errors.clone())) {
+            return Err(format!(
+                "the failure of scenario '{}' does not reproduce without its invariants, aliases and hooks",
+                name
+            ));
+        }
+        serde_json::to_string_pretty(&reproducer).map_err(|e| e.to_string())
+    }
+
+    // Runs a reproducer written by to_reproducer(), and fails unless it
+    // reproduces the same errors at the same step.
+    #[cfg(feature = "serde")]
+    pub fn run_reproducer<P>(&self, path: P) -> Result<(), String>
+    where
+        P: AsRef<std::path::Path>,
+        T: Serialize + DeserializeOwned + 'static,
+    {
+        let json = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let reproducer: Reproducer<T> = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+
+        match reproducer.run(&reproducer.load_store()?) {
+            Err((step, errors)) if step == reproducer.step && errors == reproducer.errors => Ok(()),
+            Err((step, errors)) => Err(format!(
+                "reproducer failed {} with {:?}, expected {} with {:?}",
+                describe_step(step),
+                errors,
+                describe_step(reproducer.step),
+                reproducer.errors
+            )),
+            Ok(()) => Err(String::from("reproducer no longer fails")),
+        }
+    }
+
+    // Runs just one ordering of a scenario, identified by its position among
+    // the orderings the planner produces. That position is fixed for a given
+    // config, and a failure's count is always the index of its ordering plus
+    // one, whatever the number of workers.
+    pub fn replay_index(
+        &self,
+        scenario: &str,
+        config: &Config,
+        index: usize,
+    ) -> Result<(), String> {
+        let scenario = self.find_scenario(scenario)?;
+        let runner = self.scenario_runner(config, scenario);
+
+        let Some(plan) = runner.planner.ordering_at(index) else {
+            return Err(format!(
+                "scenario '{}' has no ordering {}",
+                scenario.name, index
+            ));
+        };
+        let steps = config.max_steps.unwrap_or(plan.len()).min(plan.len());
+
+        runner.replay(&plan[0..steps]).map_err(|(step, errors)| {
+            format!(
+                "ordering {} failed {}: {}",
+                index,
+                describe_step(step),
+                errors.join("; ")
+            )
+        })
+    }
+
     pub fn add<S, R>(&mut self, name: &str, setup: S, run: R)
     where
         S: Fn(Client<T>) + 'static,
@@ -51,43 +356,216 @@ where
             name: name.to_string(),
             init: Box::new(setup),
             plan: Box::new(run),
+            before_each: None,
+            after_each: None,
         });
     }
 
+    pub fn before_each<F>(&mut self, scenario: &str, hook: F) -> Result<(), String>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.scenario_mut(scenario)?.before_each = Some(Arc::new(hook));
+        Ok(())
+    }
+
+    pub fn after_each<F>(&mut self, scenario: &str, hook: F) -> Result<(), String>
+    where
+        F: Fn(&DbStore<T>) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.scenario_mut(scenario)?.after_each = Some(Arc::new(hook));
+        Ok(())
+    }
+
     pub fn run(&mut self) {
+        let mut shuffle = self.seed.map(Shuffle);
+
+        if let Some(seed) = self.seed {
+            println!("Shuffling scenarios with seed {}", seed);
+            println!();
+        }
+
         for config in &self.configs {
-            println!("{}\n\n{:?}\n", SPLIT, config);
-            let mut results = Vec::new();
+            let mut header = Some(format!("{}\n\n{:?}\n", SPLIT, config));
+
+            if self.level > Level::Quiet {
+                println!("{}", header.take().unwrap());
+            }
+
+            for i in self.scenario_order(&mut shuffle) {
+                let scenario = &self.scenarios[i];
+                let runner = self.scenario_runner(config, scenario);
+                let (report, failure) = self.check(&runner);
+                self.failures.extend(failure);
 
-            for scenario in &self.scenarios {
-                let runner = RunnerScenario::new(config.clone(), scenario);
-                let result = runner.run();
-                results.push((scenario.name.clone(), result.is_pass(), result.count()));
+                if self.level > Level::Quiet || !report.passed {
+                    if let Some(header) = header.take() {
+                        println!("{}", header);
+                    }
+                    runner.print(&report, self.level);
+                }
+
+                let outcome = Outcome::new(&scenario.name, report);
+                self.results.push(config, outcome);
             }
-            self.results.push((config.clone(), results));
         }
-        self.print_summary();
+        self.results.print(self.level);
     }
 
-    fn print_summary(&self) {
-        println!("{}", SPLIT);
-        println!("SUMMARY");
-        println!("{}", SPLIT);
-        println!();
+    pub fn run_tap(&mut self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "TAP version 13")?;
+        writeln!(w, "1..{}", self.configs.len() * self.scenarios.len())?;
+
+        let mut shuffle = self.seed.map(Shuffle);
+
+        if let Some(seed) = self.seed {
+            writeln!(w, "# shuffling scenarios with seed {}", seed)?;
+        }
+
+        let mut n = 0;
+
+        for config in &self.configs {
+            writeln!(w, "# {:?}", config)?;
+
+            for i in self.scenario_order(&mut shuffle) {
+                let scenario = &self.scenarios[i];
+                let runner = self.scenario_runner(config, scenario);
+                let (report, failure) = self.check(&runner);
+                self.failures.extend(failure);
 
-        let mut total = 0;
+                n += 1;
+                report.write_tap(w, n, &scenario.name)?;
 
-        for (config, results) in &self.results {
-            println!("{:?}", config);
-            for (name, passed, count) in results {
-                let status = if *passed { "PASS" } else { "FAIL" };
-                total += count;
-                println!("    - {} ({}): {}", status, format_number(*count), name);
+                let outcome = Outcome::new(&scenario.name, report);
+                self.results.push(config, outcome);
             }
-            println!();
         }
-        println!("Total executions checked = {}", format_number(total));
-        println!();
+        Ok(())
+    }
+
+    pub fn export_graph(&self, config: &Config, name: &str, w: &mut dyn Write) -> io::Result<()> {
+        let Some(scenario) = self.scenarios.iter().find(|s| s.name == name) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no scenario named '{}'", name),
+            ));
+        };
+        self.scenario_runner(config, scenario).export_graph(w)
+    }
+
+    fn scenario_order(&self, shuffle: &mut Option<Shuffle>) -> Vec<usize> {
+        let mut order: Vec<_> = (0..self.scenarios.len()).collect();
+
+        if let Some(shuffle) = shuffle {
+            shuffle.permute(&mut order);
+        }
+        order
+    }
+
+    fn scenario_mut(&mut self, name: &str) -> Result<&mut Scenario<T>, String> {
+        self.scenarios
+            .iter_mut()
+            .find(|s| s.name == name)
+            .ok_or_else(|| format!("no scenario named '{}'", name))
+    }
+
+    fn find_scenario(&self, name: &str) -> Result<&Scenario<T>, String> {
+        self.scenarios
+            .iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| format!("no scenario named '{}'", name))
+    }
+
+    fn scenario_runner<'s>(
+        &self,
+        config: &Config,
+        scenario: &'s Scenario<T>,
+    ) -> RunnerScenario<'s, T> {
+        let mut runner = RunnerScenario::new(config.clone(), scenario);
+
+        if self.aliases.is_some() || scenario.after_each.is_some() {
+            runner.planner.keep_all_orderings();
+        }
+        runner.aliases = self.aliases.clone();
+        runner.swimlanes = self.swimlanes;
+        runner.limits = self.limits;
+        runner.workers = if self.runs > 1 { Some(1) } else { self.workers };
+        runner.sample = self.sample;
+        runner
+    }
+
+    fn check(&self, runner: &RunnerScenario<T>) -> (Report, Option<FailureReport>) {
+        let result = runner.check_execution();
+        let failure = runner.failure_report(&result);
+        let mut report = result.report();
+
+        if self.runs > 1 {
+            let mut runs = vec![(report.passed, report.count)];
+
+            for _ in 1..self.runs {
+                let repeat = runner.check_execution().report();
+                runs.push((repeat.passed, repeat.count));
+            }
+            report.expect_stable(&runs);
+        }
+
+        if let Some(min) = self.expected.get(&runner.scenario.name) {
+            report.expect_count(*min);
+        }
+        (report, failure)
+    }
+}
+
+// Draws n distinct ordering indices below `total` from the seed, so the same
+// seed always picks the same orderings.
+fn sample_indices(seed: u64, n: usize, total: usize) -> BTreeSet<usize> {
+    let mut indices = BTreeSet::new();
+
+    if n >= total {
+        indices.extend(0..total);
+    } else {
+        let mut rng = Shuffle(seed);
+
+        while indices.len() < n {
+            indices.insert(rng.below(total as u64) as usize);
+        }
+    }
+    indices
+}
+
+// A splitmix64 generator, which is all we need to get a reproducible
+// permutation of the scenarios, or sample of orderings, from a seed.
+struct Shuffle(u64);
+
+impl Shuffle {
+    // Draws below `bound` without favouring the low values, by rejecting the
+    // draws from the last partial run of `bound` values before u64::MAX.
+    fn below(&mut self, bound: u64) -> u64 {
+        let zone = u64::MAX - u64::MAX % bound;
+
+        loop {
+            let value = self.next();
+
+            if value < zone {
+                return value % bound;
+            }
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn permute<I>(&mut self, items: &mut [I]) {
+        for i in (1..items.len()).rev() {
+            let j = self.below(i as u64 + 1) as usize;
+            items.swap(i, j);
+        }
     }
 }
 
@@ -95,13 +573,18 @@ struct RunnerScenario<'s, T> {
     config: Config,
     scenario: &'s Scenario<T>,
     planner: Planner<T>,
+    aliases: Option<AliasRule<T>>,
+    swimlanes: bool,
+    limits: Limits,
+    workers: Option<usize>,
+    sample: Option<(u64, usize)>,
 }
 
 impl<T> RunnerScenario<'_, T>
 where
-    T: Clone + Send,
+    T: Clone + Send + Sync,
 {
-    fn new(config: Config, scenario: &Scenario<T>) -> RunnerScenario<T> {
+    fn new(config: Config, scenario: &Scenario<T>) -> RunnerScenario<'_, T> {
         let mut planner = Planner::new(config.clone());
         (scenario.plan)(&mut planner);
 
@@ -109,21 +592,22 @@ where
             config,
             scenario,
             planner,
+            aliases: None,
+            swimlanes: false,
+            limits: Limits::default(),
+            workers: None,
+            sample: None,
         }
     }
 
-    fn run(&self) -> TestResult<T>
-    where
-        T: Debug,
-    {
+    fn print(&self, report: &Report, level: Level) {
         println!("Scenario: {}", self.scenario.name);
+        report.write(&mut io::stdout(), level).unwrap();
 
-        let result = self.check_execution();
-        result.print();
-
+        if self.swimlanes {
+            report.write_swimlanes(&mut io::stdout()).unwrap();
+        }
         println!();
-
-        result
     }
 
     fn create_store(&self) -> DbStore<T> {
@@ -140,26 +624,58 @@ where
         store.into_inner()
     }
 
-    fn check_execution(&self) -> TestResult<T> {
-        let plans = Mutex::new(Box::new(self.planner.orderings().enumerate()) as PlanQueue<T>);
+    fn check_execution(&self) -> TestResult<'_, T> {
+        let mut sample = None;
+
+        let plans: PlanQueue<T> = match self.sample {
+            Some((seed, n)) => {
+                let total = self.planner.ordering_count();
+                let indices = sample_indices(seed, n, total);
+
+                if n < total {
+                    sample = Some(Sample {
+                        seed,
+                        orderings: indices.iter().copied().collect(),
+                    });
+                }
+                self.sample_orderings(indices)
+            }
+            None => Box::new(self.planner.orderings().enumerate()),
+        };
+        let plans = Mutex::new(plans);
         let client_ids: Vec<_> = self.planner.clients().collect();
         let store = self.create_store();
 
+        let checked = AtomicUsize::new(0);
+        let deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+
         let mut supervisor = Supervisor {
+            count: self.config.count.clone(),
             result: mpsc::channel(),
             aborts: Vec::new(),
         };
 
+        let worker_count = self
+            .workers
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
         let mut workers = Vec::new();
 
-        for _ in 0..WORKER_COUNT {
+        for _ in 0..worker_count {
             let (abort_send, abort_recv) = mpsc::channel();
 
             let worker = Worker {
                 config: self.config.clone(),
+                planner: &self.planner,
                 plans: &plans,
                 client_ids: &client_ids,
+                checked: &checked,
+                max_execs: self.limits.max_execs,
+                deadline,
                 store: store.clone(),
+                aliases: self.aliases.clone(),
+                invariants: self.planner.invariants().to_vec(),
+                before_each: self.scenario.before_each.clone(),
+                after_each: self.scenario.after_each.clone(),
                 result_ch: supervisor.result.0.clone(),
                 abort_ch: abort_recv,
             };
@@ -168,25 +684,282 @@ where
             supervisor.aborts.push(abort_send);
         }
 
-        thread::scope(|scope| {
+        let result = thread::scope(|scope| {
             for worker in &mut workers {
                 scope.spawn(|| worker.run());
             }
 
             supervisor.collect_result()
+        });
+
+        match result {
+            TestResult::Pass { count, bounded, .. } if sample.is_some() => TestResult::Pass {
+                count,
+                bounded,
+                truncated: true,
+                sample,
+            },
+            result => result,
+        }
+    }
+
+    // Checks the sampled orderings in ascending order, so each one keeps the
+    // index replay_index() takes. A filtered planner can only find an
+    // ordering by walking those before it, so the sample is picked out of a
+    // single walk rather than one for each index.
+    fn sample_orderings(&self, indices: BTreeSet<usize>) -> PlanQueue<'_, T> {
+        let planner = &self.planner;
+
+        if planner.is_filtered() {
+            let last = indices.last().copied().unwrap_or(0);
+
+            Box::new(
+                planner
+                    .orderings()
+                    .enumerate()
+                    .take_while(move |(i, _)| *i <= last)
+                    .filter(move |(i, _)| indices.contains(i)),
+            )
+        } else {
+            Box::new(
+                indices
+                    .into_iter()
+                    .filter_map(move |i| Some((i, planner.ordering_at(i)?))),
+            )
+        }
+    }
+
+    fn failure_report(&self, result: &TestResult<'_, T>) -> Option<FailureReport>
+    where
+        T: Debug,
+    {
+        let TestResult::Fail {
+            errors, plan, step, ..
+        } = result
+        else {
+            return None;
+        };
+
+        let executed = &plan[..executed_len(plan, *step)];
+
+        Some(FailureReport {
+            scenario: self.scenario.name.clone(),
+            config: self.config.clone(),
+            fingerprint: self.planner.fingerprint(),
+            acts: executed
+                .iter()
+                .map(|act| {
+                    let index = self.planner.act_index(act);
+                    index.expect("failing plan holds an act the planner did not build")
+                })
+                .collect(),
+            plan: executed.iter().map(|act| format!("{:?}", act)).collect(),
+            step: *step,
+            errors: errors.clone(),
         })
     }
+
+    // Runs a single plan the same way a worker would, returning the step and
+    // errors of the first failed check.
+    fn replay(&self, plan: &[&Act<T>]) -> Result<(), (Option<usize>, Vec<String>)> {
+        let store = self.create_store();
+        let client_ids: Vec<_> = self.planner.clients().collect();
+
+        let execution = Execution {
+            config: &self.config,
+            client_ids: &client_ids,
+            store: &store,
+            aliases: self.aliases.as_ref(),
+            invariants: self.planner.invariants(),
+            before_each: self.scenario.before_each.as_ref(),
+            after_each: self.scenario.after_each.as_ref(),
+        };
+        execution
+            .run(plan, None)
+            .map_err(|failure| (failure.step, failure.errors))
+    }
+
+    // Runs the plan without checking it, to save each act with the values its
+    // puts wrote.
+    #[cfg(feature = "serde")]
+    fn save_plan(&self, plan: &[&Act<T>]) -> Vec<SavedAct<T>> {
+        let store = RefCell::new(self.create_store());
+        let client_ids: Vec<_> = self.planner.clients().collect();
+        let mut actors = create_actors(&client_ids, &store, &self.config);
+
+        for actor in actors.values_mut() {
+            actor.record_puts();
+        }
+        for act in plan {
+            actors.get_mut(&act.client_id).unwrap().dispatch(act);
+        }
+
+        let mut puts: BTreeMap<_, _> = actors
+            .iter_mut()
+            .map(|(id, actor)| (id.clone(), actor.take_puts().into_iter()))
+            .collect();
+
+        plan.iter()
+            .map(|act| act.save(puts.get_mut(&act.client_id).unwrap()))
+            .collect()
+    }
+
+    fn export_graph(&self, w: &mut dyn Write) -> io::Result<()>
+    where
+        T: Debug,
+    {
+        let client_ids: Vec<_> = self.planner.clients().collect();
+        let store = self.create_store();
+
+        let mut nodes = vec![(state_label(&store), false)];
+        let mut edges = Vec::new();
+        let mut children = BTreeMap::new();
+
+        for plan in self.planner.orderings() {
+            let state = RefCell::new(store.clone());
+            let mut checker = Checker::new(&state);
+            checker.check_rules(&self.config.rules);
+
+            let mut actors: BTreeMap<_, _> = client_ids
+                .iter()
+                .map(|id| (*id, Actor::new(&state, self.config.clone())))
+                .collect();
+
+            if let Some(rule) = &self.aliases {
+                checker.check_aliases(rule.clone());
+            }
+            for invariant in self.planner.invariants() {
+                checker.check_invariant(invariant.clone());
+            }
+
+            let mut node = 0;
+
+            for act in plan {
+                actors
+                    .get_mut(act.client_id.as_str())
+                    .unwrap()
+                    .dispatch(act);
+                let violated = checker.check().is_err();
+
+                let key = (node, act as *const Act<T>);
+
+                if let Some(child) = children.get(&key) {
+                    node = *child;
+                    continue;
+                }
+                if nodes.len() == GRAPH_NODE_LIMIT {
+                    return Err(io::Error::other(format!(
+                        "interleaving graph for '{}' has more than {} nodes",
+                        self.scenario.name, GRAPH_NODE_LIMIT
+                    )));
+                }
+                nodes.push((state_label(&state.borrow()), violated));
+                edges.push((node, nodes.len() - 1, format!("{:?}", act)));
+
+                node = nodes.len() - 1;
+                children.insert(key, node);
+            }
+        }
+
+        writeln!(w, "digraph interleavings {{")?;
+        writeln!(w, "    node [shape=box];")?;
+
+        for (i, (label, violated)) in nodes.iter().enumerate() {
+            let color = if *violated { ", color=red" } else { "" };
+            writeln!(w, "    {} [label=\"{}\"{}];", i, escape_dot(label), color)?;
+        }
+        for (from, to, act) in &edges {
+            writeln!(w, "    {} -> {} [label=\"{}\"];", from, to, escape_dot(act))?;
+        }
+        writeln!(w, "}}")
+    }
+}
+
+const GRAPH_NODE_LIMIT: usize = 500;
+
+// A failure found by the after_each hook, once every act has run, has no step.
+struct Failure<T> {
+    step: Option<usize>,
+    errors: Vec<String>,
+    state: Box<DbStore<T>>,
+}
+
+// Everything needed to run one plan against the initial store and check it
+// after each act, shared by the workers and by replay.
+struct Execution<'e, T> {
+    config: &'e Config,
+    client_ids: &'e [&'e str],
+    store: &'e DbStore<T>,
+    aliases: Option<&'e AliasRule<T>>,
+    invariants: &'e [Arc<dyn Invariant<T>>],
+    before_each: Option<&'e BeforeFn>,
+    after_each: Option<&'e AfterFn<T>>,
 }
 
-const WORKER_COUNT: usize = 4;
+impl<T> Execution<'_, T>
+where
+    T: Clone,
+{
+    // A plan cut short by the deadline returns Ok.
+    fn run(&self, plan: &[&Act<T>], deadline: Option<Instant>) -> Result<(), Failure<T>> {
+        if let Some(hook) = self.before_each {
+            hook();
+        }
+        let state = RefCell::new(self.store.clone());
+        let mut actors = create_actors(self.client_ids, &state, self.config);
+        let mut checker = Checker::new(&state);
+        checker.check_rules(&self.config.rules);
+
+        if let Some(rule) = self.aliases {
+            checker.check_aliases(rule.clone());
+        }
+        for invariant in self.invariants {
+            checker.check_invariant(invariant.clone());
+        }
+
+        for (i, act) in plan.iter().enumerate() {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(());
+            }
+            actors.get_mut(&act.client_id).unwrap().dispatch(act);
+
+            if let Err(errors) = checker.check() {
+                return Err(Failure {
+                    step: Some(i),
+                    errors,
+                    state: Box::new(state.borrow().clone()),
+                });
+            }
+        }
+
+        if let Some(hook) = self.after_each {
+            if let Err(error) = hook(&state.borrow()) {
+                return Err(Failure {
+                    step: None,
+                    errors: vec![error],
+                    state: Box::new(state.borrow().clone()),
+                });
+            }
+        }
+        Ok(())
+    }
+}
 
 type PlanQueue<'a, T> = Box<dyn Iterator<Item = (usize, Vec<&'a Act<T>>)> + Send + 'a>;
 
 struct Worker<'a, 'e, T> {
     config: Config,
+    planner: &'a Planner<T>,
     plans: &'e Mutex<PlanQueue<'a, T>>,
     client_ids: &'e [&'a str],
+    checked: &'e AtomicUsize,
+    max_execs: Option<usize>,
+    deadline: Option<Instant>,
     store: DbStore<T>,
+    aliases: Option<AliasRule<T>>,
+    invariants: Vec<Arc<dyn Invariant<T>>>,
+    before_each: Option<BeforeFn>,
+    after_each: Option<AfterFn<T>>,
     result_ch: mpsc::Sender<TestResult<'a, T>>,
     abort_ch: mpsc::Receiver<()>,
 }
@@ -196,47 +969,238 @@ where
     T: Clone,
 {
     fn run(&mut self) {
-        let mut result = TestResult::Pass { count: 0 };
+        let mut count = 0;
+        let mut bounded = false;
+        let mut truncated = false;
+        let mut checked = 0;
 
-        while let Some((n, plan)) = self.next_plan() {
+        while let Some((n, mut plan)) = self.next_plan() {
             if self.abort_ch.try_recv().is_ok() {
                 return;
             }
+            if self.over_limit() {
+                truncated = true;
+                break;
+            }
 
-            let state = RefCell::new(self.store.clone());
-            let mut actors = self.create_actors(&state);
-            let mut checker = Checker::new(&state);
+            let steps = self.steps(&plan);
+            let checked_plan = self.check_plan_until(&plan[0..steps], self.deadline);
 
-            for (i, act) in plan.iter().enumerate() {
-                actors.get_mut(&act.client_id).unwrap().dispatch(act);
+            if checked_plan.is_ok() && self.past_deadline() {
+                truncated = true;
+                break;
+            }
 
-                if let Err(errors) = checker.check() {
-                    self.send_result(TestResult::Fail {
-                        count: n + 1,
-                        errors,
-                        plan,
-                        state: state.borrow().clone(),
-                        step: i,
-                    });
-                    return;
-                }
+            if let Err(failure) = checked_plan {
+                // Acts past max_steps never ran, and an after_each failure
+                // comes after every act that did.
+                plan.truncate(steps);
+
+                let (plan, failure, shrunk_from) = if self.config.shrink_failure {
+                    let original = plan.len();
+                    let failing = plan[..executed_len(&plan, failure.step)].to_vec();
+                    let (plan, failure) = self.shrink(failing, failure);
+                    (plan, failure, Some(original))
+                } else {
+                    (plan, failure, None)
+                };
+
+                let trace = self.trace(&plan[..executed_len(&plan, failure.step)]);
+                let history = match failure.step {
+                    Some(step) => self.key_history(&plan[step].path, &plan, &trace),
+                    None => Vec::new(),
+                };
+
+                let explanation = if self.config.explain_failure {
+                    self.explain(&plan, failure.step)
+                } else {
+                    None
+                };
+
+                self.send_result(TestResult::Fail {
+                    count: n + 1,
+                    index: n,
+                    errors: failure.errors,
+                    plan,
+                    state: failure.state,
+                    step: failure.step,
+                    shrunk_from,
+                    trace,
+                    history,
+                    explanation,
+                });
+                return;
             }
-            result = TestResult::Pass { count: n + 1 };
+            checked += 1;
+
+            count = match self.config.count {
+                Count::Total => checked,
+                Count::MaxIndex => n + 1,
+            };
+            bounded = bounded || plan.len() > steps;
         }
-        self.send_result(result);
+        self.send_result(TestResult::Pass {
+            count,
+            bounded,
+            truncated,
+            sample: None,
+        });
     }
 
-    fn next_plan(&self) -> Option<(usize, Vec<&'a Act<T>>)> {
-        self.plans.lock().unwrap().next()
+    // The execution cap is shared by all workers, so each one reserves a slot
+    // before checking a plan and the total never exceeds max_execs.
+    fn over_limit(&self) -> bool {
+        self.past_deadline()
+            || self
+                .max_execs
+                .is_some_and(|max| self.checked.fetch_add(1, Ordering::SeqCst) >= max)
+    }
+
+    fn steps(&self, plan: &[&Act<T>]) -> usize {
+        self.config.max_steps.unwrap_or(plan.len()).min(plan.len())
+    }
+
+    fn past_deadline(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // Shrinking and explaining re-run plans with the hooks around them, as the
+    // orderings were, so that a failure the after_each hook reports can be
+    // found again in the plans they try.
+    fn check_plan(&self, plan: &[&Act<T>]) -> Result<(), Failure<T>> {
+        self.check_plan_until(plan, None)
+    }
+
+    // A plan cut short by the deadline returns Ok, so callers must check
+    // past_deadline() before counting it as checked.
+    fn check_plan_until(
+        &self,
+        plan: &[&Act<T>],
+        deadline: Option<Instant>,
+    ) -> Result<(), Failure<T>> {
+        let execution = Execution {
+            config: &self.config,
+            client_ids: self.client_ids,
+            store: &self.store,
+            aliases: self.aliases.as_ref(),
+            invariants: &self.invariants,
+            before_each: self.before_each.as_ref(),
+            after_each: self.after_each.as_ref(),
+        };
+        execution.run(plan, deadline)
+    }
+
+    // Each candidate is replayed from the initial store, since dropping an act
+    // changes the revs every later act sees. A candidate only counts if it
+    // fails with exactly the same errors, and shrinking stops once no single
+    // client or act can be removed. Acts are removed along with those that
+    // depend on them, so every candidate is still an ordering the planner
+    // could have produced.
+    fn shrink(
+        &self,
+        mut plan: Vec<&'a Act<T>>,
+        mut failure: Failure<T>,
+    ) -> (Vec<&'a Act<T>>, Failure<T>) {
+        loop {
+            let clients: BTreeSet<_> = plan.iter().map(|act| &act.client_id).collect();
+
+            let without_clients = clients.into_iter().map(|id| {
+                let acts = plan.iter().filter(|act| &act.client_id != id);
+                acts.copied().collect::<Vec<_>>()
+            });
+            let without_acts = (0..plan.len()).map(|i| self.without_act(&plan, i));
+
+            let smaller = without_clients
+                .chain(without_acts)
+                .filter(|acts| !acts.is_empty())
+                .find_map(|acts| match self.check_plan(&acts) {
+                    Err(next) if next.errors == failure.errors => Some((acts, next)),
+                    _ => None,
+                });
+
+            let Some((acts, next)) = smaller else {
+                return (plan, failure);
+            };
+            plan = acts[..executed_len(&acts, next.step)].to_vec();
+            failure = next;
+        }
     }
 
-    fn create_actors<'r>(&self, store: &'r RefCell<DbStore<T>>) -> HashMap<String, Actor<'r, T>> {
-        self.client_ids
+    // A fault is injected into the act right after it, so it goes when that
+    // act does.
+    fn without_act(&self, plan: &[&'a Act<T>], i: usize) -> Vec<&'a Act<T>> {
+        let dependents = self.planner.dependents(plan[i]);
+        let dropped: Vec<_> = plan
             .iter()
-            .map(|name| (name.to_string(), Actor::new(store, self.config.clone())))
+            .map(|act| dependents.iter().any(|dep| std::ptr::eq(*dep, *act)))
+            .collect();
+
+        plan.iter()
+            .enumerate()
+            .filter(|(j, act)| {
+                let injected = matches!(act.op, Op::Fault(_)) && dropped.get(j + 1) == Some(&true);
+                !dropped[*j] && !injected
+            })
+            .map(|(_, act)| *act)
             .collect()
     }
 
+    // An after_each failure comes after the last act, so any swap in the plan
+    // may explain it.
+    fn explain(&self, plan: &[&Act<T>], step: Option<usize>) -> Option<usize> {
+        let step = step.unwrap_or(plan.len().saturating_sub(1));
+
+        (0..step).find(|&i| {
+            if plan[i].client_id == plan[i + 1].client_id {
+                return false;
+            }
+            let mut swapped = plan.to_vec();
+            swapped.swap(i, i + 1);
+
+            self.check_plan(&swapped[0..self.steps(&swapped)]).is_ok()
+        })
+    }
+
+    fn trace(&self, plan: &[&Act<T>]) -> Vec<StepWrite<T>> {
+        let state = RefCell::new(self.store.clone());
+        let mut actors = create_actors(self.client_ids, &state, &self.config);
+
+        plan.iter()
+            .map(|act| {
+                let seq = state.borrow().seq;
+                actors.get_mut(&act.client_id).unwrap().dispatch(act);
+
+                let store = state.borrow();
+
+                if store.seq == seq {
+                    return None;
+                }
+                let (rev, value) = store.peek(&act.path)?;
+                Some((rev, value.cloned()))
+            })
+            .collect()
+    }
+
+    fn key_history(&self, key: &Path, plan: &[&Act<T>], trace: &[StepWrite<T>]) -> Vec<Version<T>> {
+        let mut history = Vec::new();
+
+        if let Some((rev, value)) = self.store.peek(key) {
+            history.push((rev, String::from("init"), value.cloned()));
+        }
+
+        for (act, write) in plan.iter().zip(trace) {
+            if let (true, Some((rev, value))) = (act.path == *key, write) {
+                history.push((*rev, act.client_id.clone(), value.clone()));
+            }
+        }
+        history
+    }
+
+    fn next_plan(&self) -> Option<(usize, Vec<&'a Act<T>>)> {
+        self.plans.lock().unwrap().next()
+    }
+
     fn send_result(&self, result: TestResult<'a, T>) {
         self.result_ch.send(result).unwrap();
     }
@@ -245,20 +1209,24 @@ where
 type Channel<T> = (mpsc::Sender<T>, mpsc::Receiver<T>);
 
 struct Supervisor<'a, T> {
+    count: Count,
     result: Channel<TestResult<'a, T>>,
     aborts: Vec<mpsc::Sender<()>>,
 }
 
 impl<'a, T> Supervisor<'a, T> {
     fn collect_result(&self) -> TestResult<'a, T> {
-        let mut result = TestResult::Pass { count: 0 };
+        let mut result = TestResult::Pass {
+            count: 0,
+            bounded: false,
+            truncated: false,
+            sample: None,
+        };
         let mut finished = 0;
 
         for worker_result in &self.result.1 {
             if worker_result.is_pass() {
-                if worker_result.count() > result.count() {
-                    result = worker_result
-                }
+                result = self.combine(result, worker_result);
                 finished += 1;
                 if finished == self.aborts.len() {
                     break;
@@ -272,6 +1240,22 @@ impl<'a, T> Supervisor<'a, T> {
         result
     }
 
+    // With Count::Total each worker reports how many plans it checked, so the
+    // sum is the number of orderings verified; with Count::MaxIndex we report
+    // the furthest position any worker reached in the ordering sequence.
+    fn combine(&self, a: TestResult<'a, T>, b: TestResult<'a, T>) -> TestResult<'a, T> {
+        let count = match self.count {
+            Count::Total => a.count() + b.count(),
+            Count::MaxIndex => a.count().max(b.count()),
+        };
+        TestResult::Pass {
+            count,
+            bounded: a.is_bounded() || b.is_bounded(),
+            truncated: a.is_truncated() || b.is_truncated(),
+            sample: None,
+        }
+    }
+
     fn abort(&self) {
         for abort in &self.aborts {
             abort.send(()).unwrap();
@@ -279,16 +1263,27 @@ impl<'a, T> Supervisor<'a, T> {
     }
 }
 
+type Version<T> = (Rev, String, Option<Db<T>>);
+type StepWrite<T> = Option<(Rev, Option<Db<T>>)>;
+
 enum TestResult<'a, T> {
     Pass {
         count: usize,
+        bounded: bool,
+        truncated: bool,
+        sample: Option<Sample>,
     },
     Fail {
         count: usize,
+        index: usize,
         errors: Vec<String>,
-        state: DbStore<T>,
+        state: Box<DbStore<T>>,
         plan: Vec<&'a Act<T>>,
-        step: usize,
+        step: Option<usize>,
+        shrunk_from: Option<usize>,
+        trace: Vec<StepWrite<T>>,
+        history: Vec<Version<T>>,
+        explanation: Option<usize>,
     },
 }
 
@@ -302,57 +1297,154 @@ impl<T> TestResult<'_, T> {
 
     fn count(&self) -> usize {
         match self {
-            TestResult::Pass { count } => *count,
+            TestResult::Pass { count, .. } => *count,
             TestResult::Fail { count, .. } => *count,
         }
     }
 
-    fn print(&self)
+    fn is_bounded(&self) -> bool {
+        match self {
+            TestResult::Pass { bounded, .. } => *bounded,
+            TestResult::Fail { .. } => false,
+        }
+    }
+
+    fn is_truncated(&self) -> bool {
+        match self {
+            TestResult::Pass { truncated, .. } => *truncated,
+            TestResult::Fail { .. } => false,
+        }
+    }
+
+    fn report(&self) -> Report
     where
         T: Clone + Debug,
     {
-        let status = if self.is_pass() { "PASS" } else { "FAIL" };
-        println!("    result: {}", status);
-        println!("    checked executions: {}", format_number(self.count()));
-
-        if let TestResult::Fail {
+        let failure = if let TestResult::Fail {
+            index,
             errors,
             state,
             plan,
             step,
+            shrunk_from,
+            trace,
+            history,
+            explanation,
             ..
         } = self
         {
-            println!("    errors:");
-            for error in errors {
-                println!("        - {}", error);
-            }
-            println!("    state:");
-            for key in state.keys() {
-                let value = format_value(state.read(key));
-                println!("        '{}' => {}", key, value);
-            }
-            println!("    execution:");
-            for (i, act) in plan.iter().enumerate() {
-                if i == *step {
-                    println!("    ==> {:?}", act);
-                } else {
-                    println!("        {:?}", act);
-                }
-            }
+            let key = step.map(|step| plan[step].path.to_string());
+
+            let state = state
+                .keys()
+                .map(|key| (key.to_string(), format_value(state.read(key))))
+                .collect();
+
+            let lanes = plan
+                .iter()
+                .map(|act| (act.client_id.clone(), act.describe_op()))
+                .collect();
+
+            let plan = plan
+                .iter()
+                .enumerate()
+                .map(|(i, act)| {
+                    let write = trace.get(i).cloned().flatten().map(|(rev, value)| {
+                        format!("'{}' = {}", act.path, format_value(Some((rev, value))))
+                    });
+                    (format!("{:?}", act), write)
+                })
+                .collect();
+
+            let history = history
+                .iter()
+                .map(|(rev, client_id, value)| {
+                    let value = format_value(Some((*rev, value.clone())));
+                    (client_id.clone(), value)
+                })
+                .collect();
+
+            Some(FailureDetails {
+                index: *index,
+                errors: errors.clone(),
+                state,
+                plan,
+                lanes,
+                step: *step,
+                shrunk_from: *shrunk_from,
+                key,
+                history,
+                explanation: *explanation,
+            })
+        } else {
+            None
+        };
+
+        let sample = match self {
+            TestResult::Pass { sample, .. } => sample.clone(),
+            TestResult::Fail { .. } => None,
+        };
+
+        Report {
+            passed: self.is_pass(),
+            count: self.count(),
+            bounded: self.is_bounded(),
+            truncated: self.is_truncated(),
+            expected: None,
+            unstable: None,
+            sample,
+            failure,
         }
     }
 }
 
-fn format_number(n: usize) -> String {
-    n.to_string()
-        .as_bytes()
-        .rchunks(3)
-        .rev()
-        .map(|byte| std::str::from_utf8(byte))
-        .collect::<Result<Vec<&str>, _>>()
-        .unwrap()
-        .join(",")
+fn create_actors<'r, T>(
+    client_ids: &[&str],
+    store: &'r RefCell<DbStore<T>>,
+    config: &Config,
+) -> BTreeMap<String, Actor<'r, T>>
+where
+    T: Clone,
+{
+    client_ids
+        .iter()
+        .map(|name| (name.to_string(), Actor::new(store, config.clone())))
+        .collect()
+}
+
+fn state_label<T>(store: &DbStore<T>) -> String
+where
+    T: Clone + Debug,
+{
+    let entries: Vec<_> = store
+        .keys()
+        .map(|key| format!("'{}' => {}", key, format_value(store.read(key))))
+        .collect();
+
+    if entries.is_empty() {
+        String::from("<empty>")
+    } else {
+        entries.join("\n")
+    }
+}
+
+// The number of acts run before a failure, which is the whole plan when the
+// after_each hook failed it.
+fn executed_len<A>(plan: &[A], step: Option<usize>) -> usize {
+    step.map_or(plan.len(), |step| step + 1)
+}
+
+fn describe_step(step: Option<usize>) -> String {
+    match step {
+        Some(step) => format!("at step {}", step),
+        None => String::from("in after_each"),
+    }
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
 }
 
 fn format_value<T>(value: Option<(usize, Option<Db<T>>)>) -> String
@@ -369,3 +1461,1220 @@ where
         String::from("<null>")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::{Fault, Update};
+    use crate::planner::Op;
+
+    fn update_delete_conflict() -> Scenario<char> {
+        Scenario {
+            name: String::from("update/delete conflict"),
+            init: Box::new(|mut db| {
+                db.update("/path/x", |_| Some('a'));
+            }),
+            plan: Box::new(|planner| {
+                planner.client("A").update("/path/x", |_| Some('b'));
+                planner.client("B").remove("/path/x");
+            }),
+            before_each: None,
+            after_each: None,
+        }
+    }
+
+    fn outcomes(runner: &Runner<char>) -> Vec<&Report> {
+        runner
+            .results
+            .configs
+            .iter()
+            .flat_map(|(_, r)| r)
+            .map(|outcome| &outcome.report)
+            .collect()
+    }
+
+    #[test]
+    fn reports_each_scenario_as_a_tap_test_point() {
+        let mut runner = Runner::new();
+
+        runner.configs(&[Config::new(), Config::new().update(Update::GetBeforePut)]);
+
+        runner.add(
+            "update",
+            |mut db| {
+                db.update("/path/x", |_| Some('a'));
+            },
+            |planner| {
+                planner.client("A").update("/path/x", |_| Some('b'));
+            },
+        );
+        runner.add(
+            "update/delete conflict",
+            |mut db| {
+                db.update("/path/x", |_| Some('a'));
+            },
+            |planner| {
+                planner.client("A").update("/path/x", |_| Some('b'));
+                planner.client("B").remove("/path/x");
+            },
+        );
+
+        let mut out = Vec::new();
+        runner.run_tap(&mut out).unwrap();
+        let tap = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = tap.lines().collect();
+
+        assert_eq!(lines[0], "TAP version 13");
+        assert_eq!(lines[1], "1..4");
+
+        let points: Vec<_> = lines
+            .iter()
+            .filter(|line| line.starts_with("ok ") || line.starts_with("not ok "))
+            .collect();
+        assert_eq!(points.len(), 4);
+
+        for (i, point) in points.iter().enumerate() {
+            let rest = point.trim_start_matches("not ").trim_start_matches("ok ");
+            assert!(rest.starts_with(&format!("{} - ", i + 1)));
+        }
+        assert!(points[0].starts_with("ok 1 - update ("));
+        assert!(points[3].starts_with("not ok 4 - update/delete conflict ("));
+
+        let diagnostics = tap.split("  ---\n").nth(1).unwrap();
+        let block = diagnostics.split("  ...\n").next().unwrap();
+        assert!(block.starts_with("  errors:\n    - \""));
+        assert!(block.contains("\n  step: "));
+        assert!(block.contains("\n  plan:\n    - \"Act<"));
+    }
+
+    #[test]
+    fn fails_scenarios_that_check_fewer_executions_than_expected() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new()]);
+
+        for name in ["enough", "too few"] {
+            runner.add(
+                name,
+                |_| {},
+                |planner| {
+                    planner.client("A").update("/x", |_| Some('a'));
+                    planner.client("B").update("/y", |_| Some('b'));
+                },
+            );
+        }
+
+        let scenario = independent_updates();
+        let count = RunnerScenario::new(Config::new(), &scenario)
+            .planner
+            .orderings()
+            .count();
+
+        runner.expect_executions("enough", count);
+        runner.expect_executions("too few", count + 1);
+        runner.run();
+
+        let results = outcomes(&runner);
+        assert_eq!(results[0].count, count);
+        assert!(results[0].passed);
+        assert_eq!(results[1].count, count);
+        assert!(!results[1].passed);
+        assert_eq!(results[1].status(), "FAIL");
+    }
+
+    fn export_graph(runner: &Runner<char>, config: Config, name: &str) -> io::Result<String> {
+        let mut out = Vec::new();
+        runner.export_graph(&config, name, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn exports_the_interleaving_tree_marking_violations() {
+        let mut runner = Runner::new();
+        runner.normalize_keys(|key| key.to_lowercase());
+
+        runner.add(
+            "alias",
+            |mut db| {
+                db.update("/x", |_| Some('a'));
+            },
+            |planner| {
+                planner.client("A").update("/X", |_| Some('b'));
+            },
+        );
+
+        let dot = export_graph(&runner, Config::new(), "alias").unwrap();
+        let lines: Vec<_> = dot.lines().collect();
+
+        assert_eq!(lines[0], "digraph interleavings {");
+        assert_eq!(lines.last(), Some(&"}"));
+
+        let nodes = lines
+            .iter()
+            .filter(|l| l.contains("[label=") && !l.contains("->"));
+        let edges = lines.iter().filter(|l| l.contains("->"));
+        let red = lines.iter().filter(|l| l.contains("color=red"));
+
+        assert_eq!(nodes.count(), 9);
+        assert_eq!(edges.count(), 8);
+        assert_eq!(red.count(), 2);
+        assert!(lines
+            .iter()
+            .any(|l| l.contains("'/X' => { rev: 1, value: Doc('b') }")));
+    }
+
+    #[test]
+    fn refuses_to_export_a_graph_above_the_node_limit() {
+        let mut runner = Runner::new();
+        let scenario = update_delete_conflict();
+        runner.scenarios.push(scenario);
+
+        let config = Config::new().update(Update::GetBeforePut);
+        let error = export_graph(&runner, config, "update/delete conflict").unwrap_err();
+        assert!(error.to_string().contains("more than 500 nodes"));
+
+        let error = export_graph(&runner, Config::new(), "missing").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    fn add_independent_updates(runner: &mut Runner<char>, name: &str) {
+        runner.add(
+            name,
+            |_| {},
+            |planner| {
+                planner.client("A").update("/x", |_| Some('a'));
+                planner.client("B").update("/y", |_| Some('b'));
+            },
+        );
+    }
+
+    #[test]
+    fn runs_hooks_around_every_ordering() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new()]);
+        add_independent_updates(&mut runner, "hooks");
+
+        let before = Arc::new(Mutex::new(0));
+        let after = Arc::new(Mutex::new(0));
+
+        let counter = before.clone();
+        runner
+            .before_each("hooks", move || *counter.lock().unwrap() += 1)
+            .unwrap();
+
+        let counter = after.clone();
+        runner
+            .after_each("hooks", move |store| {
+                assert!(store.get("/x").is_some() || store.get("/y").is_some());
+                *counter.lock().unwrap() += 1;
+                Ok(())
+            })
+            .unwrap();
+
+        runner.run();
+
+        let count = RunnerScenario::new(Config::new(), &runner.scenarios[0])
+            .planner
+            .orderings()
+            .count();
+
+        assert!(outcomes(&runner)[0].passed);
+        assert_eq!(*before.lock().unwrap(), count);
+        assert_eq!(*after.lock().unwrap(), count);
+    }
+
+    #[test]
+    fn runs_hooks_around_the_plans_shrinking_and_explaining_try() {
+        let config = Config::new()
+            .update(Update::GetBeforePut)
+            .shrink_failure(true)
+            .explain_failure(true);
+
+        let mut runner = Runner::new();
+        runner.configs(&[config]);
+        runner.workers(1);
+        runner.scenarios.push(update_delete_conflict());
+
+        let before = Arc::new(Mutex::new(0));
+        let counter = before.clone();
+        runner
+            .before_each("update/delete conflict", move || {
+                *counter.lock().unwrap() += 1;
+            })
+            .unwrap();
+
+        runner.run();
+
+        let report = outcomes(&runner)[0];
+        assert!(!report.passed);
+        assert!(*before.lock().unwrap() > report.count);
+    }
+
+    #[test]
+    fn replays_a_shrunk_failure_found_by_an_after_each_hook() {
+        let config = Config::new().shrink_failure(true);
+
+        let mut runner = Runner::new();
+        runner.configs(std::slice::from_ref(&config));
+        runner.verbosity(Level::Quiet);
+        runner.workers(1);
+        add_independent_updates(&mut runner, "hooked");
+
+        runner
+            .after_each("hooked", |store| match store.get("/x") {
+                Some(_) => Err(String::from("'/x' was written")),
+                None => Ok(()),
+            })
+            .unwrap();
+
+        runner.run();
+
+        let report = outcomes(&runner)[0];
+        let failure = report.failure.as_ref().unwrap();
+        assert_eq!(failure.step, None);
+        assert!(failure.shrunk_from.unwrap() > failure.plan.len());
+        assert!(failure.lanes.iter().all(|(client_id, _)| client_id == "A"));
+
+        let saved = &runner.failures()[0];
+        let scenario_runner = runner.scenario_runner(&config, &runner.scenarios[0]);
+        let plan: Vec<_> = saved
+            .acts
+            .iter()
+            .map(|i| scenario_runner.planner.act_at(*i).unwrap())
+            .collect();
+
+        assert_eq!(
+            scenario_runner.replay(&plan),
+            Err((None, vec![String::from("'/x' was written")]))
+        );
+    }
+
+    #[test]
+    fn refuses_hooks_for_an_unknown_scenario() {
+        let mut runner: Runner<char> = Runner::new();
+        add_independent_updates(&mut runner, "hooks");
+
+        let error = Err(String::from("no scenario named 'missing'"));
+        assert_eq!(runner.before_each("missing", || ()), error);
+        assert_eq!(runner.after_each("missing", |_| Ok(())), error);
+        assert!(runner.scenarios[0].before_each.is_none());
+    }
+
+    struct AtMostOneOf(&'static str, &'static str);
+
+    impl Invariant<char> for AtMostOneOf {
+        fn check(&self, store: &DbStore<char>) -> Result<(), Vec<String>> {
+            if store.get(self.0).is_some() && store.get(self.1).is_some() {
+                Err(vec![format!("both '{}' and '{}' exist", self.0, self.1)])
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn checks_the_invariants_a_scenario_registers_after_each_act() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new()]);
+
+        runner.add(
+            "exclusive",
+            |_| {},
+            |planner| {
+                planner.invariant(AtMostOneOf("/x", "/y"));
+                planner.client("A").update("/x", |_| Some('a'));
+                planner.client("B").update("/y", |_| Some('b'));
+            },
+        );
+        add_independent_updates(&mut runner, "independent");
+
+        runner.run();
+
+        let reports = outcomes(&runner);
+        assert!(!reports[0].passed);
+        assert!(reports[1].passed);
+
+        let failure = reports[0].failure.as_ref().unwrap();
+        assert_eq!(failure.errors, ["both '/x' and '/y' exist"]);
+    }
+
+    #[test]
+    fn finds_violations_while_checking_fewer_orderings_with_partial_order() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new(), Config::new().partial_order(true)]);
+        add_independent_updates(&mut runner, "independent");
+
+        runner.add(
+            "exclusive",
+            |_| {},
+            |planner| {
+                planner.invariant(AtMostOneOf("/x", "/y"));
+                planner.client("A").update("/x", |_| Some('a'));
+                planner.client("B").update("/y", |_| Some('b'));
+            },
+        );
+
+        runner.run();
+
+        let reports = outcomes(&runner);
+        assert!(reports[0].passed && reports[2].passed);
+        assert!(reports[2].count * 4 < reports[0].count);
+        assert!(!reports[1].passed && !reports[3].passed);
+    }
+
+    #[test]
+    fn checks_every_ordering_with_partial_order_when_after_each_hooks_are_registered() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new(), Config::new().partial_order(true)]);
+        add_independent_updates(&mut runner, "hooks");
+        runner.after_each("hooks", |_| Ok(())).unwrap();
+
+        runner.run();
+
+        let reports = outcomes(&runner);
+        assert!(reports[0].passed && reports[1].passed);
+        assert_eq!(reports[0].count, reports[1].count);
+    }
+
+    #[test]
+    fn finds_lost_operations_when_faults_are_injected() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new(), Config::new().inject(Fault::Drop)]);
+
+        runner.add(
+            "delete",
+            |mut db| {
+                db.update("/x", |_| Some('a'));
+            },
+            |planner| {
+                planner.client("A").remove("/x");
+            },
+        );
+
+        runner
+            .after_each("delete", |store| {
+                if store.get("/x").is_some() {
+                    Err(String::from("'/x' was not deleted"))
+                } else {
+                    Ok(())
+                }
+            })
+            .unwrap();
+
+        runner.run();
+
+        let reports = outcomes(&runner);
+        assert!(reports[0].passed);
+        assert!(!reports[1].passed);
+    }
+
+    #[test]
+    fn fails_a_scenario_when_an_after_each_hook_errors() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new()]);
+        add_independent_updates(&mut runner, "cleanup");
+
+        runner
+            .after_each("cleanup", |store| {
+                if store.get("/x").is_some() {
+                    Err(String::from("'/x' was not cleaned up"))
+                } else {
+                    Ok(())
+                }
+            })
+            .unwrap();
+
+        runner.run();
+
+        let report = outcomes(&runner)[0];
+        assert!(!report.passed);
+
+        let failure = report.failure.as_ref().unwrap();
+        assert_eq!(failure.errors, ["'/x' was not cleaned up"]);
+    }
+
+    #[test]
+    fn fails_a_scenario_with_no_acts_when_an_after_each_hook_errors() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new().shrink_failure(true)]);
+        runner.add("empty", |_| {}, |_| {});
+
+        runner
+            .after_each("empty", |_| Err(String::from("nothing ran")))
+            .unwrap();
+
+        runner.run();
+
+        let report = outcomes(&runner)[0];
+        assert!(!report.passed);
+
+        let failure = report.failure.as_ref().unwrap();
+        assert_eq!(failure.errors, ["nothing ran"]);
+        assert_eq!(failure.step, None);
+        assert_eq!(failure.key, None);
+        assert!(failure.plan.is_empty());
+
+        let mut out = Vec::new();
+        report.write(&mut out, Level::Verbose).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("==> after_each"));
+
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new().max_steps(0)]);
+        add_independent_updates(&mut runner, "cut");
+
+        runner
+            .after_each("cut", |_| Err(String::from("nothing ran")))
+            .unwrap();
+
+        runner.run();
+
+        let failure = outcomes(&runner)[0].failure.as_ref().unwrap();
+        assert_eq!(failure.step, None);
+        assert!(failure.plan.is_empty());
+    }
+
+    #[test]
+    fn shuffles_scenarios_reproducibly_from_a_seed() {
+        let mut a: Vec<_> = (0..8).collect();
+        let mut b: Vec<_> = (0..8).collect();
+        Shuffle(42).permute(&mut a);
+        Shuffle(42).permute(&mut b);
+
+        assert_eq!(a, b);
+        assert_ne!(a, (0..8).collect::<Vec<_>>());
+
+        let mut sorted = a.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn passes_scenarios_that_repeat_identically() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new()]);
+        runner.verbosity(Level::Quiet);
+        runner.verify_determinism(3);
+        add_independent_updates(&mut runner, "stable");
+
+        runner.run();
+
+        let report = outcomes(&runner)[0];
+        assert!(report.passed);
+        assert_eq!(report.unstable, None);
+    }
+
+    #[test]
+    fn flags_scenarios_whose_verdict_varies_between_runs() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new()]);
+        runner.verbosity(Level::Quiet);
+        runner.verify_determinism(2);
+        add_independent_updates(&mut runner, "flaky");
+
+        let count = RunnerScenario::new(Config::new(), &runner.scenarios[0])
+            .planner
+            .orderings()
+            .count();
+
+        let calls = Arc::new(Mutex::new(0));
+
+        runner
+            .after_each("flaky", move |_| {
+                let mut calls = calls.lock().unwrap();
+                *calls += 1;
+
+                if *calls > count {
+                    Err(String::from("second run fails"))
+                } else {
+                    Ok(())
+                }
+            })
+            .unwrap();
+
+        runner.run();
+
+        let report = outcomes(&runner)[0];
+        assert!(!report.passed);
+        assert_eq!(report.status(), "FAIL (nondeterministic)");
+        assert_eq!(report.unstable.as_ref().unwrap()[0], (true, count));
+        assert!(!report.unstable.as_ref().unwrap()[1].0);
+    }
+
+    #[test]
+    fn repeats_a_failing_scenario_identically_with_several_workers() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new().update(Update::GetBeforePut)]);
+        runner.verbosity(Level::Quiet);
+        runner.workers(4);
+        runner.verify_determinism(5);
+        runner.scenarios.push(update_delete_conflict());
+
+        runner.run();
+
+        let report = outcomes(&runner)[0];
+        assert!(!report.passed);
+        assert_eq!(report.unstable, None);
+        assert_eq!(report.status(), "FAIL");
+    }
+
+    #[test]
+    fn runs_scenarios_in_shuffled_order_within_each_config() {
+        let names: Vec<_> = (0..6).map(|i| format!("s{}", i)).collect();
+
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new(), Config::new().skip_links(true)]);
+        runner.verbosity(Level::Quiet);
+        runner.shuffle(7);
+
+        for name in &names {
+            runner.add(
+                name,
+                |_| {},
+                |planner| {
+                    planner.client("A").update("/x", |_| Some('a'));
+                },
+            );
+        }
+        runner.run();
+
+        let mut shuffle = Shuffle(7);
+
+        for (_, outcomes) in &runner.summary().configs {
+            let mut expected = names.clone();
+            shuffle.permute(&mut expected);
+
+            let ran: Vec<_> = outcomes.iter().map(|o| o.name.clone()).collect();
+            assert_eq!(ran, expected);
+        }
+    }
+
+    #[test]
+    fn stops_checking_plans_after_the_execution_cap() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new()]);
+        runner.verbosity(Level::Quiet);
+        runner.limits(Some(5), None);
+        add_independent_updates(&mut runner, "capped");
+
+        runner.run();
+
+        let report = outcomes(&runner)[0];
+        assert!(report.passed && report.truncated);
+        assert_eq!(report.count, 5);
+        assert_eq!(report.status(), "PASS (partial)");
+    }
+
+    #[test]
+    fn stops_checking_plans_after_the_timeout() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new()]);
+        runner.verbosity(Level::Quiet);
+        runner.limits(None, Some(Duration::ZERO));
+        add_independent_updates(&mut runner, "timed out");
+
+        runner.run();
+
+        let report = outcomes(&runner)[0];
+        assert!(report.passed && report.truncated);
+        assert_eq!(report.count, 0);
+    }
+
+    #[test]
+    fn reports_an_exhaustive_search_within_the_limits() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new()]);
+        runner.verbosity(Level::Quiet);
+        runner.limits(Some(1_000_000), Some(Duration::from_secs(600)));
+        add_independent_updates(&mut runner, "exhaustive");
+
+        runner.run();
+
+        let report = outcomes(&runner)[0];
+        assert!(report.passed && !report.truncated);
+        assert_eq!(report.status(), "PASS (exhaustive)");
+    }
+
+    #[test]
+    fn labels_results_bounded_when_plans_exceed_max_steps() {
+        let mut runner = Runner::new();
+
+        runner.configs(&[Config::new(), Config::new().max_steps(3)]);
+
+        runner.add(
+            "update",
+            |mut db| {
+                db.update("/x", |_| Some('a'));
+            },
+            |planner| {
+                planner.client("A").update("/x", |_| Some('b'));
+            },
+        );
+
+        runner.run();
+
+        let results = outcomes(&runner);
+        assert!(results[0].passed && !results[0].bounded);
+        assert!(results[1].passed && results[1].bounded);
+        assert_eq!(results[0].count, results[1].count);
+    }
+
+    fn independent_updates() -> Scenario<char> {
+        Scenario {
+            name: String::from("independent updates"),
+            init: Box::new(|_| {}),
+            plan: Box::new(|planner| {
+                planner.client("A").update("/x", |_| Some('a'));
+                planner.client("B").update("/y", |_| Some('b'));
+            }),
+            before_each: None,
+            after_each: None,
+        }
+    }
+
+    #[test]
+    fn maps_clients_to_actors_in_the_same_order_on_every_run() {
+        let scenario = Scenario::<char> {
+            name: String::from("clients"),
+            init: Box::new(|_| {}),
+            plan: Box::new(|planner| {
+                planner.client("C").update("/x", |_| Some('c'));
+                planner.client("A").update("/x", |_| Some('a'));
+                planner.client("B").remove("/x");
+            }),
+            before_each: None,
+            after_each: None,
+        };
+
+        let a = RunnerScenario::new(Config::new(), &scenario);
+        let b = RunnerScenario::new(Config::new(), &scenario);
+
+        let clients: Vec<_> = a.planner.clients().collect();
+        assert_eq!(clients, ["A", "B", "C"]);
+        assert!(a.planner.clients().eq(b.planner.clients()));
+
+        let plans_a = a.planner.orderings().map(|plan| plan_clients(&plan));
+        let plans_b = b.planner.orderings().map(|plan| plan_clients(&plan));
+        assert!(plans_a.eq(plans_b));
+
+        let dispatched = |runner: &RunnerScenario<char>| -> Vec<_> {
+            let client_ids: Vec<_> = runner.planner.clients().collect();
+
+            runner
+                .planner
+                .orderings()
+                .take(50)
+                .flat_map(|plan| {
+                    let store = RefCell::new(runner.create_store());
+                    let mut actors = create_actors(&client_ids, &store, &runner.config);
+
+                    plan.iter()
+                        .map(|act| {
+                            let actor = actors.keys().position(|id| *id == act.client_id);
+                            actors.get_mut(&act.client_id).unwrap().dispatch(act);
+
+                            let store = store.borrow();
+                            let write = store.peek(&act.path).map(|(rev, v)| (rev, v.cloned()));
+                            (actor.unwrap(), write)
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect()
+        };
+        let dispatched_a = dispatched(&a);
+
+        assert_eq!(dispatched_a[0].0, 2);
+        assert_eq!(dispatched_a, dispatched(&b));
+    }
+
+    fn plan_clients(plan: &[&Act<char>]) -> Vec<String> {
+        plan.iter().map(|act| act.client_id.clone()).collect()
+    }
+
+    #[test]
+    fn counts_every_ordering_checked_across_all_workers() {
+        let scenario = independent_updates();
+        let mut runner = RunnerScenario::new(Config::new(), &scenario);
+        runner.workers = Some(4);
+        let expected = runner.planner.orderings().count();
+
+        let result = runner.check_execution();
+        assert!(result.is_pass());
+        assert!(expected > 4);
+        assert_eq!(result.count(), expected);
+    }
+
+    #[test]
+    fn counts_the_furthest_ordering_reached_with_max_index() {
+        let scenario = independent_updates();
+        let config = Config::new().count(Count::MaxIndex);
+        let runner = RunnerScenario::new(config, &scenario);
+        let expected = runner.planner.orderings().count();
+
+        let result = runner.check_execution();
+        assert!(result.is_pass());
+        assert_eq!(result.count(), expected);
+    }
+
+    #[test]
+    fn records_the_history_of_the_key_written_by_the_failing_step() {
+        let scenario = update_delete_conflict();
+        let config = Config::new().update(Update::GetBeforePut);
+        let runner = RunnerScenario::new(config, &scenario);
+
+        let TestResult::Fail {
+            plan,
+            step,
+            state,
+            history,
+            ..
+        } = runner.check_execution()
+        else {
+            panic!("expected scenario to fail");
+        };
+
+        let step = step.unwrap();
+        let key = &plan[step].path;
+        let (rev, value) = state.peek(key).unwrap();
+
+        let (_, first, _) = history.first().unwrap();
+        assert_eq!(first, "init");
+
+        let last = history.last().unwrap();
+        assert_eq!(*last, (rev, plan[step].client_id.clone(), value.cloned()));
+
+        let revs: Vec<_> = history.iter().map(|(rev, _, _)| *rev).collect();
+        let expected: Vec<_> = (1..=rev).collect();
+        assert_eq!(revs, expected);
+    }
+
+    #[test]
+    fn lays_out_swimlanes_in_the_order_of_the_failing_plan() {
+        let scenario = update_delete_conflict();
+        let config = Config::new().update(Update::GetBeforePut);
+        let runner = RunnerScenario::new(config, &scenario);
+
+        let result = runner.check_execution();
+        let report = result.report();
+
+        let TestResult::Fail { plan, .. } = result else {
+            panic!("expected scenario to fail");
+        };
+        let failure = report.failure.unwrap();
+
+        let lanes: Vec<_> = plan
+            .iter()
+            .map(|act| (act.client_id.clone(), act.describe_op()))
+            .collect();
+        assert_eq!(failure.lanes, lanes);
+
+        for ((client_id, op), (act, _)) in failure.lanes.iter().zip(&failure.plan) {
+            assert_eq!(*act, format!("Act<{}: {}>", client_id, op));
+        }
+    }
+
+    fn replay(runner: &RunnerScenario<char>, plan: &[&Act<char>]) -> Result<(), Vec<String>> {
+        let state = RefCell::new(runner.create_store());
+        let mut checker = Checker::new(&state);
+
+        let mut actors: BTreeMap<_, _> = runner
+            .planner
+            .clients()
+            .map(|id| (id, Actor::new(&state, runner.config.clone())))
+            .collect();
+
+        for act in plan {
+            actors
+                .get_mut(act.client_id.as_str())
+                .unwrap()
+                .dispatch(act);
+            checker.check()?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn replays_failures_written_to_a_file() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new().update(Update::GetBeforePut)]);
+        runner.scenarios.push(update_delete_conflict());
+        runner.run();
+
+        let failure = &runner.failures()[0];
+        assert_eq!(failure.scenario, "update/delete conflict");
+        assert_eq!(failure.step, Some(failure.acts.len() - 1));
+
+        let path = std::env::temp_dir().join(format!("mc2-replay-{}.json", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        runner.write_failures(&mut file).unwrap();
+        assert_eq!(runner.replay(&path), Ok(()));
+
+        let mut tampered = runner.failures().to_vec();
+        tampered[0].errors = vec![String::from("something else")];
+        std::fs::write(&path, serde_json::to_string(&tampered).unwrap()).unwrap();
+        assert!(runner.replay(&path).is_err());
+
+        tampered[0].errors = runner.failures()[0].errors.clone();
+        tampered[0].fingerprint += 1;
+        std::fs::write(&path, serde_json::to_string(&tampered).unwrap()).unwrap();
+        assert_eq!(
+            runner.replay(&path),
+            Err(String::from(
+                "scenario 'update/delete conflict' no longer builds the same plan"
+            ))
+        );
+
+        tampered[0].scenario = String::from("missing");
+        std::fs::write(&path, serde_json::to_string(&tampered).unwrap()).unwrap();
+        assert_eq!(
+            runner.replay(&path),
+            Err(String::from("no scenario named 'missing'"))
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn reproduces_a_dumped_failure_in_a_runner_that_never_ran() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new().update(Update::GetBeforePut)]);
+        runner.scenarios.push(update_delete_conflict());
+        runner.run();
+
+        let mut json = Vec::new();
+        runner.write_failures(&mut json).unwrap();
+        let reloaded: Vec<FailureReport> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(reloaded, runner.failures());
+
+        let path = std::env::temp_dir().join(format!("mc2-reproduce-{}.json", std::process::id()));
+        std::fs::write(&path, &json).unwrap();
+
+        let reproducer = runner.to_reproducer(&reloaded[0]).unwrap();
+        std::fs::write(&path, &reproducer).unwrap();
+
+        let colleague: Runner<char> = Runner::new();
+        assert_eq!(colleague.run_reproducer(&path), Ok(()));
+
+        let tampered = reproducer.replace(&reloaded[0].errors[0], "something else");
+        assert_ne!(tampered, reproducer);
+        std::fs::write(&path, tampered).unwrap();
+        assert!(colleague.run_reproducer(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn refuses_a_reproducer_for_a_failure_found_by_a_hook() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new()]);
+        runner.add(
+            "cut",
+            |mut db| {
+                db.update("/x", |_| Some('a'));
+            },
+            |planner| {
+                planner.client("A").update("/x", |_| Some('b'));
+            },
+        );
+        runner
+            .after_each("cut", |_| Err(String::from("nothing ran")))
+            .unwrap();
+        runner.run();
+
+        let failure = &runner.failures()[0];
+        assert_eq!(
+            runner.to_reproducer(failure),
+            Err(String::from(
+                "the failure of scenario 'cut' does not reproduce without its invariants, aliases and hooks"
+            ))
+        );
+    }
+
+    #[test]
+    fn replays_a_single_ordering_by_its_index() {
+        let config = Config::new().update(Update::GetBeforePut);
+
+        let mut runner = Runner::new();
+        runner.configs(std::slice::from_ref(&config));
+        runner.workers(1);
+        runner.scenarios.push(update_delete_conflict());
+        runner.run();
+
+        let count = outcomes(&runner)[0].count;
+        let name = "update/delete conflict";
+
+        for index in 0..count - 1 {
+            assert_eq!(runner.replay_index(name, &config, index), Ok(()));
+        }
+        let error = runner.replay_index(name, &config, count - 1).unwrap_err();
+        assert!(error.starts_with(&format!("ordering {} failed at step", count - 1)));
+
+        let planner = runner
+            .scenario_runner(&config, &runner.scenarios[0])
+            .planner;
+        let total = planner.orderings().count();
+
+        assert_eq!(
+            runner.replay_index(name, &config, total),
+            Err(format!("scenario '{}' has no ordering {}", name, total))
+        );
+        assert_eq!(
+            runner.replay_index("missing", &config, 0),
+            Err(String::from("no scenario named 'missing'"))
+        );
+    }
+
+    #[test]
+    fn reports_a_sample_of_fewer_than_every_ordering_as_partial() {
+        let mut runner = Runner::new();
+        runner.configs(&[Config::new()]);
+        runner.workers(1);
+        runner.sample(42, 2);
+        add_independent_updates(&mut runner, "sampled");
+        runner.run();
+
+        let report = outcomes(&runner)[0];
+        assert_eq!(report.count, 2);
+        assert_eq!(report.status(), "PASS (partial)");
+
+        let sample = report.sample.as_ref().unwrap();
+        assert_eq!(sample.seed, 42);
+        assert_eq!(sample.orderings.len(), 2);
+
+        for index in &sample.orderings {
+            assert_eq!(
+                runner.replay_index("sampled", &Config::new(), *index),
+                Ok(())
+            );
+        }
+
+        runner.sample(42, usize::MAX);
+        runner.run();
+
+        let report = outcomes(&runner)[1];
+        assert_eq!(report.status(), "PASS (exhaustive)");
+        assert_eq!(report.sample, None);
+    }
+
+    #[test]
+    fn samples_a_filtered_planner_in_a_single_walk() {
+        let scenario = update_delete_conflict();
+        let config = Config::new().partial_order(true);
+        let runner = RunnerScenario::new(config, &scenario);
+        let total = runner.planner.ordering_count();
+
+        let indices = sample_indices(7, 3, total);
+        let sampled: Vec<_> = runner.sample_orderings(indices).collect();
+
+        assert_eq!(sampled.len(), 3);
+        for (i, plan) in sampled {
+            assert_eq!(Some(plan), runner.planner.ordering_at(i));
+        }
+    }
+
+    #[test]
+    fn draws_evenly_below_a_bound() {
+        let mut rng = Shuffle(1);
+        let mut counts = [0; 3];
+
+        for _ in 0..3000 {
+            counts[rng.below(3) as usize] += 1;
+        }
+        assert!(counts.iter().all(|count| (900..1100).contains(count)));
+    }
+
+    #[test]
+    fn samples_the_same_orderings_from_the_same_seed() {
+        let scenario = update_delete_conflict();
+        let runner = RunnerScenario::new(Config::new(), &scenario);
+        let total = runner.planner.ordering_count();
+
+        let sample = |seed, n| -> Vec<_> {
+            let plans = runner.sample_orderings(sample_indices(seed, n, total));
+            plans.map(|(i, _)| i).collect()
+        };
+        let picked = sample(42, 5);
+
+        assert_eq!(picked.len(), 5);
+        assert!(picked.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(picked.iter().all(|i| *i < total));
+        assert_eq!(sample(42, 5), picked);
+        assert_eq!(sample(42, total + 1), (0..total).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reports_the_index_of_a_sampled_failure_for_replay() {
+        let config = Config::new().update(Update::GetBeforePut);
+        let name = "update/delete conflict";
+
+        let mut runner = Runner::new();
+        runner.configs(std::slice::from_ref(&config));
+        runner.workers(1);
+        runner.sample(42, usize::MAX);
+        runner.scenarios.push(update_delete_conflict());
+        runner.run();
+
+        let report = outcomes(&runner)[0];
+        let index = report.failure.as_ref().unwrap().index;
+        assert_eq!(report.count, index + 1);
+
+        let error = runner.replay_index(name, &config, index).unwrap_err();
+        assert!(error.starts_with(&format!("ordering {} failed at step", index)));
+    }
+
+    #[test]
+    fn shrinks_a_failing_plan_to_a_minimal_counterexample() {
+        let scenario = update_delete_conflict();
+        let config = Config::new()
+            .update(Update::GetBeforePut)
+            .shrink_failure(true);
+        let runner = RunnerScenario::new(config, &scenario);
+
+        let result = runner.check_execution();
+        let report = result.report();
+
+        let TestResult::Fail {
+            plan,
+            step,
+            errors,
+            shrunk_from,
+            ..
+        } = result
+        else {
+            panic!("expected scenario to fail");
+        };
+
+        assert_eq!(step, Some(plan.len() - 1));
+        assert!(plan.len() < shrunk_from.unwrap());
+        assert_eq!(report.failure.unwrap().shrunk_from, shrunk_from);
+
+        assert_eq!(replay(&runner, &plan), Err(errors.clone()));
+
+        let holds = |act: &Act<char>| plan.iter().position(|a| std::ptr::eq(*a, act));
+
+        for act in (0..).map_while(|i| runner.planner.act_at(i)) {
+            let dependents = runner.planner.dependents(act);
+
+            match holds(act) {
+                Some(i) => assert!(dependents
+                    .iter()
+                    .all(|dep| holds(dep).is_none_or(|j| j >= i))),
+                None => assert!(dependents.iter().all(|dep| holds(dep).is_none())),
+            }
+        }
+
+        for act in &plan {
+            let dependents = runner.planner.dependents(act);
+            let smaller: Vec<_> = plan
+                .iter()
+                .filter(|a| !dependents.iter().any(|dep| std::ptr::eq(*dep, **a)))
+                .copied()
+                .collect();
+            assert_ne!(replay(&runner, &smaller), Err(errors.clone()));
+        }
+    }
+
+    #[test]
+    fn does_not_shrink_failures_by_default() {
+        let scenario = update_delete_conflict();
+        let config = Config::new().update(Update::GetBeforePut);
+        let runner = RunnerScenario::new(config, &scenario);
+
+        let TestResult::Fail { shrunk_from, .. } = runner.check_execution() else {
+            panic!("expected scenario to fail");
+        };
+        assert_eq!(shrunk_from, None);
+    }
+
+    #[test]
+    fn finds_the_first_failing_ordering_with_a_single_worker() {
+        let scenario = update_delete_conflict();
+        let config = Config::new().update(Update::GetBeforePut);
+
+        let mut runner = RunnerScenario::new(config, &scenario);
+        runner.workers = Some(1);
+
+        let first = runner
+            .planner
+            .orderings()
+            .position(|plan| replay(&runner, &plan).is_err())
+            .unwrap();
+
+        for _ in 0..3 {
+            let TestResult::Fail { count, plan, .. } = runner.check_execution() else {
+                panic!("expected scenario to fail");
+            };
+            assert_eq!(count, first + 1);
+            assert!(plan
+                .iter()
+                .copied()
+                .eq(runner.planner.orderings().nth(first).unwrap()));
+        }
+    }
+
+    #[test]
+    fn explains_a_failure_by_the_swap_that_introduces_it() {
+        let scenario = update_delete_conflict();
+        let config = Config::new()
+            .update(Update::GetBeforePut)
+            .explain_failure(true);
+        let runner = RunnerScenario::new(config, &scenario);
+
+        let TestResult::Fail {
+            plan,
+            step,
+            explanation,
+            ..
+        } = runner.check_execution()
+        else {
+            panic!("expected scenario to fail");
+        };
+
+        let i = explanation.unwrap();
+        assert!(i < step.unwrap());
+        assert_ne!(plan[i].client_id, plan[i + 1].client_id);
+    }
+
+    #[test]
+    fn does_not_explain_failures_by_default() {
+        let scenario = update_delete_conflict();
+        let config = Config::new().update(Update::GetBeforePut);
+        let runner = RunnerScenario::new(config, &scenario);
+
+        let TestResult::Fail { explanation, .. } = runner.check_execution() else {
+            panic!("expected scenario to fail");
+        };
+        assert_eq!(explanation, None);
+    }
+
+    #[test]
+    fn traces_the_write_made_by_each_step_of_a_failure() {
+        let scenario = update_delete_conflict();
+        let config = Config::new().update(Update::GetBeforePut);
+        let runner = RunnerScenario::new(config, &scenario);
+
+        let TestResult::Fail {
+            plan,
+            step,
+            state,
+            trace,
+            ..
+        } = runner.check_execution()
+        else {
+            panic!("expected scenario to fail");
+        };
+
+        let step = step.unwrap();
+        assert_eq!(trace.len(), step + 1);
+
+        for (act, write) in plan.iter().zip(&trace) {
+            let is_read = matches!(act.op, Op::Get | Op::List);
+            assert!(!is_read || write.is_none());
+        }
+
+        let (rev, value) = state.peek(&plan[step].path).unwrap();
+        assert_eq!(trace[step], Some((rev, value.cloned())));
+    }
+}
@@ -1,27 +1,122 @@
 use std::borrow::Borrow;
 use std::cell::RefCell;
-use std::collections::BTreeMap;
-
-use crate::config::{Cas, Config};
+use std::cmp::Ordering;
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::mem;
+use std::ops::{Bound, Deref, DerefMut, Index, IndexMut, RangeBounds};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::config::{Cas, Config, Fault};
+use crate::tree::Tree;
 
 pub type Rev = usize;
 
-#[derive(Clone)]
+type Observer<K, V> = Arc<Mutex<dyn FnMut(&K, Rev, Option<&V>) + Send>>;
+type Validator<K> = Arc<dyn Fn(&K) -> Result<(), String> + Send + Sync>;
+type Merge<V> = Box<dyn Fn(&V, &V) -> V>;
+type Conflicts<V> = Vec<(usize, Rev, Option<V>)>;
+type Derivation<K, V> =
+    Arc<dyn Fn(&Store<K, V>, &K, Option<&V>) -> Option<(K, Option<V>)> + Send + Sync>;
+
 pub struct Store<K, V> {
-    data: BTreeMap<K, (Rev, Option<V>)>,
+    data: Tree<K, Record<V>>,
+    config: Config,
+    observer: Option<Observer<K, V>>,
+    subscribers: Vec<Sender<Change<K, V>>>,
+    validator: Option<Validator<K>>,
+    derivations: Vec<Derivation<K, V>>,
+    conflicts: usize,
+    retain: usize,
+    snapshots: BTreeMap<Rev, usize>,
+    pub seq: Rev,
+}
+
+// Records are saved whole, so tombstones keep their revs and a client holding
+// a rev from before the save gets the same CAS result after reloading.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct SavedStore<M> {
     config: Config,
+    seq: Rev,
+    retain: usize,
+    data: M,
+}
+
+// Each record also keeps the store seq at which it was last written, so that
+// changes_since() can tell which entries a watcher has not yet seen, and the
+// versions it replaced when the store retains them for read_at(). The seq at
+// which it was first written tells read_at() the key did not exist before,
+// even once the version that created it has been dropped.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct Record<V> {
+    rev: Rev,
+    value: Option<V>,
+    modified: Rev,
+    created: Rev,
+    versions: Vec<Version<V>>,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct Version<V> {
+    rev: Rev,
+    value: Option<V>,
+    modified: Rev,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StoreStats {
+    pub live: usize,
+    pub tombstones: usize,
+    pub total: usize,
     pub seq: Rev,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change<K, V> {
+    pub key: K,
+    pub rev: Rev,
+    pub value: Option<V>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MergePolicy {
+    PreferSelf,
+    PreferOther,
+    HigherRev,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReadResult<V> {
+    NotModified,
+    Modified { rev: Rev, value: V },
+    Deleted { rev: Rev },
+}
+
 impl<K, V> Store<K, V>
 where
-    K: Ord,
+    K: Clone + Ord,
     V: Clone,
 {
     pub fn new(config: Config) -> Store<K, V> {
         Store {
-            data: BTreeMap::new(),
+            data: Tree::new(),
             config,
+            observer: None,
+            subscribers: Vec::new(),
+            validator: None,
+            derivations: Vec::new(),
+            conflicts: 0,
+            retain: 0,
+            snapshots: BTreeMap::new(),
             seq: 0,
         }
     }
@@ -31,11 +126,32 @@ where
         K: Borrow<Q>,
         Q: Ord + ?Sized,
     {
-        if let Some((_, Some(value))) = self.data.get(key) {
-            Some(value)
-        } else {
-            None
-        }
+        self.data.get(key)?.value.as_ref()
+    }
+
+    pub fn get_or<'a, Q>(&'a self, key: &Q, default: &'a V) -> &'a V
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get(key).unwrap_or(default)
+    }
+
+    pub fn peek<Q>(&self, key: &Q) -> Option<(Rev, Option<&V>)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let record = self.data.get(key)?;
+        Some((record.rev, record.value.as_ref()))
+    }
+
+    pub fn rev<Q>(&self, key: &Q) -> Option<Rev>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.data.get(key).map(|record| record.rev)
     }
 
     pub fn read<Q>(&self, key: &Q) -> Option<(Rev, Option<V>)>
@@ -43,396 +159,3277 @@ where
         K: Borrow<Q>,
         Q: Ord + ?Sized,
     {
+        let record = self.data.get(key)?;
+        self.visible(record.rev, &record.value)
+    }
+
+    // Tombstones are only handed out where their rev can be used to write the
+    // key again, in the same way for read() and read_at().
+    fn visible(&self, rev: Rev, value: &Option<V>) -> Option<(Rev, Option<V>)> {
         if self.config.store == Cas::Strict || self.config.store == Cas::MatchRev {
-            if let Some((rev, value)) = self.data.get(key) {
-                Some((*rev, value.clone()))
+            Some((rev, value.clone()))
+        } else {
+            Some((rev, Some(value.clone()?)))
+        }
+    }
+
+    pub fn read_if_changed<Q>(&self, key: &Q, known_rev: Rev) -> ReadResult<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        match self.peek(key).unwrap_or((0, None)) {
+            (rev, _) if rev == known_rev => ReadResult::NotModified,
+            (rev, Some(value)) => ReadResult::Modified {
+                rev,
+                value: value.clone(),
+            },
+            (rev, None) => ReadResult::Deleted { rev },
+        }
+    }
+
+    // Entries are returned in the order they were written, including
+    // tombstones, so a watcher can apply them in turn and then resume from
+    // the current seq. Keys written together by write_many() share a seq and
+    // come out in key order.
+    pub fn changes_since(&self, seq: Rev) -> Vec<(K, Rev, Option<V>)> {
+        let mut changes: Vec<_> = self
+            .data
+            .iter()
+            .filter(|(_, record)| record.modified > seq)
+            .collect();
+
+        changes.sort_by_key(|(_, record)| record.modified);
+
+        changes
+            .into_iter()
+            .map(|(key, record)| (key.clone(), record.rev, record.value.clone()))
+            .collect()
+    }
+
+    // Returns what read() would have returned at `seq`, or an error if the
+    // store no longer holds the version current then: keys written since can
+    // only be read back once retain_versions() is enabled, and only as far
+    // back as the versions kept for them.
+    pub fn read_at<Q>(&self, key: &Q, seq: Rev) -> Result<Option<(Rev, Option<V>)>, String>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let Some(record) = self.data.get(key) else {
+            return Ok(None);
+        };
+        if seq < record.created {
+            return Ok(None);
+        }
+        if record.modified <= seq {
+            return Ok(self.visible(record.rev, &record.value));
+        }
+        let versions = &record.versions;
+
+        if let Some(version) = versions
+            .iter()
+            .rev()
+            .find(|version| version.modified <= seq)
+        {
+            return Ok(self.visible(version.rev, &version.value));
+        }
+        Err(format!("version at seq {} is no longer retained", seq))
+    }
+
+    // Keeps up to `n` replaced versions of each key for read_at(), dropping
+    // the oldest once a key has more than that and no open snapshot needs it.
+    pub fn retain_versions(&mut self, n: usize) {
+        self.retain = n;
+    }
+
+    // Pins the current seq until close_snapshot(), so that compact() keeps
+    // every version needed to read the store as it is now.
+    pub fn open_snapshot(&mut self) -> Rev {
+        *self.snapshots.entry(self.seq).or_insert(0) += 1;
+        self.seq
+    }
+
+    pub fn close_snapshot(&mut self, seq: Rev) {
+        if let Entry::Occupied(mut slot) = self.snapshots.entry(seq) {
+            *slot.get_mut() -= 1;
+
+            if *slot.get() == 0 {
+                slot.remove();
+            }
+        }
+    }
+
+    // Drops every version older than the one each key held at the oldest open
+    // snapshot, or at the current seq if there are none, returning how many
+    // were dropped.
+    pub fn compact(&mut self) -> usize {
+        let oldest = self.snapshots.keys().next().copied().unwrap_or(self.seq);
+        let mut dropped = 0;
+
+        self.data.update_values(|_, record| {
+            let keep = if record.modified <= oldest {
+                record.versions.len()
             } else {
-                None
+                let mut versions = record.versions.iter();
+                versions
+                    .rposition(|version| version.modified <= oldest)
+                    .unwrap_or(0)
+            };
+            if keep == 0 {
+                return None;
             }
-        } else if let Some((rev, Some(value))) = self.data.get(key) {
-            Some((*rev, Some(value.clone())))
-        } else {
-            None
+            let mut record = record.clone();
+            record.versions.drain(..keep);
+            dropped += keep;
+            Some(record)
+        });
+        dropped
+    }
+
+    // Restores every key to what read_at(seq) returns for it, dropping keys
+    // created since, and sets seq back to `seq`. Nothing is changed if any key
+    // no longer holds the version it had then. Like reset(), it does not
+    // notify the observer or subscribers.
+    pub fn rollback_to(&mut self, seq: Rev) -> Result<(), String> {
+        if seq > self.seq {
+            return Err(format!("seq {} is past the current seq {}", seq, self.seq));
+        }
+        for key in self.data.keys() {
+            self.read_at(key, seq)?;
+        }
+        let mut data = Tree::new();
+
+        for (key, record) in self.data.iter() {
+            if record.modified <= seq {
+                data.insert(key.clone(), record.clone());
+                continue;
+            }
+            let mut versions = record.versions.clone();
+
+            let Some(i) = versions.iter().rposition(|version| version.modified <= seq) else {
+                continue;
+            };
+            let version = versions.remove(i);
+            versions.truncate(i);
+
+            let record = Record {
+                rev: version.rev,
+                value: version.value,
+                modified: version.modified,
+                created: record.created,
+                versions,
+            };
+            data.insert(key.clone(), record);
+        }
+        self.data = data;
+        self.snapshots.retain(|snapshot, _| *snapshot <= seq);
+        self.seq = seq;
+
+        Ok(())
+    }
+
+    pub fn snapshot_read(&self) -> SnapshotView<K, V> {
+        SnapshotView {
+            data: self.data.clone(),
+            seq: self.seq,
         }
     }
 
     pub fn write(&mut self, key: K, rev: Option<Rev>, value: V) -> Option<Rev> {
-        self.set_key(key, rev, Some(value))
+        self.set_key(key, rev, Some(value)).unwrap_or(None)
     }
 
     pub fn remove(&mut self, key: K, rev: Option<Rev>) -> Option<Rev> {
+        self.set_key(key, rev, None).unwrap_or(None)
+    }
+
+    pub fn touch(&mut self, key: K, rev: Option<Rev>) -> Option<Rev> {
+        let value = self.data.get(&key)?.value.clone();
+        self.set_key(key, rev, value).unwrap_or(None)
+    }
+
+    // Like write() and remove(), but a key the validator rejects comes back as
+    // an error rather than as a write that was not applied.
+    pub fn try_write(&mut self, key: K, rev: Option<Rev>, value: V) -> Result<Option<Rev>, String> {
+        self.set_key(key, rev, Some(value))
+    }
+
+    pub fn try_remove(&mut self, key: K, rev: Option<Rev>) -> Result<Option<Rev>, String> {
         self.set_key(key, rev, None)
     }
 
-    fn set_key(&mut self, key: K, rev: Option<Rev>, value: Option<V>) -> Option<Rev> {
-        let client_rev = rev.unwrap_or(0);
-        let entry = self.data.entry(key).or_insert((0, None));
+    pub fn apply_batch(&mut self, entries: &[(K, Option<Rev>, Option<V>)]) -> Vec<Option<Rev>> {
+        entries
+            .iter()
+            .map(|(key, rev, value)| {
+                self.set_key(key.clone(), *rev, value.clone())
+                    .unwrap_or(None)
+            })
+            .collect()
+    }
 
-        if entry.1.is_some() || self.config.store == Cas::Strict {
-            if client_rev != entry.0 {
-                return None;
-            }
-        } else if self.config.store == Cas::MatchRev {
-            if client_rev != 0 && client_rev != entry.0 {
-                return None;
+    // Like write_many(), the batch is applied as a single change that
+    // advances seq once. A key repeated in the batch, or one the validator
+    // rejects, is reported as a conflict at its index, so nothing is applied.
+    pub fn cas_many(&mut self, entries: &[(K, Rev, Option<V>)]) -> Result<Vec<Rev>, Conflicts<V>> {
+        let mut conflicts = Vec::new();
+        let mut seen = BTreeSet::new();
+
+        for (i, (key, rev, _)) in entries.iter().enumerate() {
+            let (current, value) = match self.data.get(key) {
+                Some(record) => (record.rev, record.value.clone()),
+                None => (0, None),
+            };
+
+            if current != *rev {
+                self.conflicts += 1;
             }
-        } else if self.config.store == Cas::NoRev {
-            if client_rev != 0 {
-                return None;
+            if self.validate_key(key).is_err() || !seen.insert(key) || current != *rev {
+                conflicts.push((i, current, value));
             }
         }
+        if !conflicts.is_empty() {
+            return Err(conflicts);
+        }
 
-        *entry = (entry.0 + 1, value);
         self.seq += 1;
 
-        Some(entry.0)
-    }
+        let revs = entries
+            .iter()
+            .map(|(key, _, value)| self.apply(key.clone(), value.clone()))
+            .collect();
 
-    pub fn keys(&self) -> impl Iterator<Item = &K> {
-        self.data.keys()
+        Ok(revs)
     }
-}
 
-pub struct Cache<'a, K, V> {
-    store: &'a RefCell<Store<K, V>>,
-    data: BTreeMap<K, Option<(Rev, Option<V>)>>,
-}
+    // A batch is applied as a single change: either every entry is written
+    // and seq advances once, or nothing is touched at all.
+    pub fn write_many(&mut self, ops: &[(K, Option<Rev>, Option<V>)]) -> Option<Vec<Rev>> {
+        if ops
+            .iter()
+            .any(|(key, _, _)| self.validate_key(key).is_err())
+        {
+            return None;
+        }
 
-impl<K, V> Cache<'_, K, V>
-where
-    K: Clone + Ord,
-    V: Clone,
-{
-    pub fn new(store: &RefCell<Store<K, V>>) -> Cache<K, V> {
-        Cache {
-            store,
-            data: BTreeMap::new(),
+        let mut keys: Vec<_> = ops.iter().map(|(key, _, _)| key).collect();
+        keys.sort();
+
+        if keys.windows(2).any(|pair| pair[0] == pair[1]) {
+            return None;
         }
+        // Like cas_many(), each key whose rev does not match counts as a
+        // conflict of its own.
+        let mismatched = ops
+            .iter()
+            .filter(|(key, rev, _)| !self.accepts(key, *rev))
+            .count();
+
+        if mismatched > 0 {
+            self.conflicts += mismatched;
+            return None;
+        }
+
+        self.seq += 1;
+
+        let revs = ops
+            .iter()
+            .map(|(key, _, value)| self.apply(key.clone(), value.clone()))
+            .collect();
+
+        Some(revs)
     }
 
-    pub fn read<'a, Q>(&mut self, key: &'a Q) -> Option<V>
+    pub fn delete_if<Q>(&mut self, key: &Q, expected: &V) -> bool
     where
         K: Borrow<Q>,
         Q: Ord + ?Sized,
-        &'a Q: Into<K>,
+        V: PartialEq,
     {
-        if !self.data.contains_key(key) {
-            let record = self.store.borrow().read(key);
-            self.data.insert(key.into(), record);
-        }
+        let bounds = (Bound::Included(key), Bound::Included(key));
 
-        if let Some(Some((_, Some(value)))) = self.data.get(key) {
-            Some(value.clone())
-        } else {
-            None
+        let Some((key, record)) = self.data.range::<Q, _>(bounds).next() else {
+            return false;
+        };
+        if record.value.as_ref() != Some(expected) {
+            return false;
         }
+        self.commit(key.clone(), None);
+        true
     }
 
-    pub fn write(&mut self, key: &K, value: V) -> bool {
-        let old_rev = self.get_rev(key);
-        let mut store = self.store.borrow_mut();
+    pub fn clear(&mut self) -> usize {
+        let keys: Vec<_> = self.live_keys().cloned().collect();
 
-        if let Some(new_rev) = store.write(key.clone(), old_rev, value.clone()) {
-            self.data.insert(key.clone(), Some((new_rev, Some(value))));
-            true
-        } else {
-            self.data.remove(key);
-            false
+        for key in &keys {
+            self.commit(key.clone(), None);
         }
+        keys.len()
     }
 
-    pub fn remove(&mut self, key: &K) -> bool {
-        let old_rev = self.get_rev(key);
-        let mut store = self.store.borrow_mut();
+    pub fn scan<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a str, Rev, &'a V)>
+    where
+        K: Borrow<str>,
+    {
+        self.scan_entries(prefix)
+            .map(|(key, rev, value)| (key.borrow(), rev, value))
+    }
 
-        if store.remove(key.clone(), old_rev).is_some() {
-            self.data.insert(key.clone(), None);
-            true
-        } else {
-            self.data.remove(key);
-            false
-        }
+    // Only strict descendants of the prefix are returned, so scanning "/path/"
+    // skips the directory itself as well as siblings like "/pathx".
+    fn scan_entries<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a K, Rev, &'a V)>
+    where
+        K: Borrow<str>,
+    {
+        let bounds = (Bound::Included(prefix), Bound::Unbounded);
+
+        self.data
+            .range::<str, _>(bounds)
+            .take_while(move |(key, _)| (*key).borrow().starts_with(prefix))
+            .filter(move |(key, _)| (*key).borrow() != prefix)
+            .filter_map(|(key, record)| Some((key, record.rev, record.value.as_ref()?)))
     }
 
-    fn get_rev(&self, key: &K) -> Option<Rev> {
-        if let Some(Some((rev, _))) = self.data.get(key) {
-            Some(*rev)
-        } else {
-            None
+    pub fn prefix_remove(&mut self, prefix: &str) -> Vec<(K, Rev)>
+    where
+        K: Borrow<str>,
+    {
+        let bounds = (Bound::Included(prefix), Bound::Unbounded);
+
+        let keys: Vec<_> = self
+            .data
+            .range::<str, _>(bounds)
+            .take_while(|(key, _)| (*key).borrow().starts_with(prefix))
+            .filter(|(_, record)| record.value.is_some())
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        keys.into_iter()
+            .map(|key| (key.clone(), self.commit(key, None)))
+            .collect()
+    }
+
+    pub fn split_off<Q>(&mut self, key: &Q) -> Store<K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut data = self.data.split_off(key);
+        let seq = data.values().map(|record| record.rev).max().unwrap_or(0);
+
+        // The shard starts its seq from the highest rev it holds, so the seqs
+        // recorded against this store no longer mean anything there. Like a
+        // clone, it starts without the observer or any subscribers.
+        data.update_values(|_, record| {
+            Some(Record {
+                rev: record.rev,
+                value: record.value.clone(),
+                modified: record.rev,
+                created: record.rev.min(1),
+                versions: Vec::new(),
+            })
+        });
+
+        Store {
+            data,
+            config: self.config.clone(),
+            observer: None,
+            subscribers: Vec::new(),
+            validator: self.validator.clone(),
+            derivations: self.derivations.clone(),
+            conflicts: 0,
+            retain: self.retain,
+            snapshots: BTreeMap::new(),
+            seq,
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // Records are imported whole, so a tombstone is just another record: it
+    // replaces a live value whenever the policy picks the other side, and a
+    // tombstone on this side survives under PreferSelf. Keys missing here are
+    // always imported. An imported record keeps the rev it had in `other`
+    // unless that is not past the rev held here, since a rev that went back
+    // would let a client holding an old one write the key again. Every key to
+    // be imported is checked by the validator first, and if any is rejected
+    // nothing is imported.
+    pub fn import_from(&mut self, other: &Store<K, V>, policy: MergePolicy) -> Result<usize, String>
+    where
+        V: PartialEq,
+    {
+        let taken: Vec<_> = other
+            .data
+            .iter()
+            .filter(|(key, record)| match (self.data.get(*key), policy) {
+                (None, _) => true,
+                (Some(_), MergePolicy::PreferSelf) => false,
+                (Some(current), MergePolicy::PreferOther) => current.value != record.value,
+                (Some(current), MergePolicy::HigherRev) => record.rev > current.rev,
+            })
+            .collect();
+
+        for (key, _) in &taken {
+            self.validate_key(key)?;
+        }
+        let mut changed = 0;
 
-    #[test]
-    fn returns_none_for_unknown_key() {
-        let store: Store<String, ()> = Store::new(Config::new());
-        assert_eq!(store.seq, 0);
-        assert_eq!(store.read("x"), None);
+        for (key, record) in taken {
+            self.seq += 1;
+            self.apply_from(key.clone(), record.value.clone(), record.rev);
+            changed += 1;
+        }
+        Ok(changed)
     }
 
-    #[test]
-    fn stores_a_new_value() {
-        let mut store: Store<String, _> = Store::new(Config::new());
-        assert_eq!(store.write("x".into(), None, 'a'), Some(1));
-        assert_eq!(store.seq, 1);
-        assert_eq!(store.read("x"), Some((1, Some('a'))));
+    // Observers, validators and derivations hold closures, so they are not
+    // saved and have to be set up again on the reloaded store.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> serde_json::Result<String>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        let saved = SavedStore {
+            config: self.config.clone(),
+            seq: self.seq,
+            retain: self.retain,
+            data: self.data.iter().collect::<BTreeMap<_, _>>(),
+        };
+        serde_json::to_string(&saved)
     }
 
-    #[test]
-    fn does_not_update_a_value_without_a_rev() {
-        let mut store: Store<String, _> = Store::new(Config::new());
-        store.write("x".into(), None, 'a');
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> serde_json::Result<Store<K, V>>
+    where
+        K: DeserializeOwned,
+        V: DeserializeOwned,
+    {
+        let saved: SavedStore<BTreeMap<K, Record<V>>> = serde_json::from_str(json)?;
 
-        assert_eq!(store.write("x".into(), None, 'b'), None);
-        assert_eq!(store.seq, 1);
-        assert_eq!(store.read("x"), Some((1, Some('a'))));
+        let mut store = Store::new(saved.config);
+        store.data = saved.data.into_iter().collect();
+        store.seq = saved.seq;
+        store.retain = saved.retain;
+
+        Ok(store)
     }
 
-    #[test]
-    fn does_not_update_a_value_with_a_bad_rev() {
-        let mut store: Store<String, _> = Store::new(Config::new());
-        let rev = store.write("x".into(), None, 'a');
+    // Open snapshots are dropped along with the data they pinned, so the seqs
+    // they were opened at do not keep versions written after the reset.
+    pub fn reset(&mut self) {
+        self.data.clear();
+        self.snapshots.clear();
+        self.conflicts = 0;
+        self.seq = 0;
+    }
 
-        assert_eq!(store.write("x".into(), rev.map(|r| r + 1), 'b'), None);
-        assert_eq!(store.seq, 1);
-        assert_eq!(store.read("x"), Some((1, Some('a'))));
+    // The observer only receives shared references to the changed entry, and
+    // cannot capture the store itself since it must be 'static, so it has no
+    // way to mutate the store from inside set_key().
+    pub fn set_observer<F>(&mut self, f: F)
+    where
+        F: FnMut(&K, Rev, Option<&V>) + Send + 'static,
+    {
+        self.observer = Some(Arc::new(Mutex::new(f)));
     }
 
-    #[test]
-    fn updates_a_value_with_a_matching_rev() {
-        let mut store: Store<String, _> = Store::new(Config::new());
-        let rev = store.write("x".into(), None, 'a');
+    // Subscribers are told of every change the observer is, independently of
+    // it, and are dropped once their receiver has been. Like the observer,
+    // they are not carried over to clones.
+    pub fn subscribe(&mut self) -> Receiver<Change<K, V>> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
 
-        assert_eq!(store.write("x".into(), rev, 'b'), Some(2));
-        assert_eq!(store.seq, 2);
-        assert_eq!(store.read("x"), Some((2, Some('b'))));
+    // Keys the validator rejects are never written: try_write() and
+    // try_remove() return its error, and every other write leaves the store
+    // unchanged and reports the key as not written.
+    pub fn set_key_validator<F>(&mut self, f: F)
+    where
+        K: Borrow<str>,
+        F: Fn(&str) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.validator = Some(Arc::new(move |key: &K| f(key.borrow())));
     }
 
-    #[test]
-    fn removes_a_value() {
-        let mut store: Store<String, _> = Store::new(Config::new());
-        let rev = store.write("x".into(), None, 'a');
+    // A derivation is evaluated against the store as it was before the change
+    // to the source key, and its result is written as part of the same change,
+    // so no reader can see the source and derived keys disagree. Derived
+    // writes do not trigger further derivations. Values changed through
+    // get_disjoint_mut() or values_mut() derive when their guard is dropped.
+    pub fn add_derivation<F>(&mut self, trigger_prefix: &str, f: F)
+    where
+        K: Borrow<str>,
+        F: Fn(&Store<K, V>, &K, Option<&V>) -> Option<(K, Option<V>)> + Send + Sync + 'static,
+    {
+        let prefix = trigger_prefix.to_string();
+
+        self.derivations.push(Arc::new(
+            move |store: &Store<K, V>, key: &K, value: Option<&V>| {
+                if key.borrow().starts_with(&prefix) {
+                    f(store, key, value)
+                } else {
+                    None
+                }
+            },
+        ));
+    }
 
-        assert_eq!(store.remove("x".into(), rev), Some(2));
-        assert_eq!(store.read("x"), Some((2, None)));
+    // Nodes of the tree may be shared with clones of the store, so the guard
+    // holds copies of the values rather than borrows into it, and writes the
+    // ones changed through it back with commit() when dropped.
+    pub fn get_disjoint_mut<Q>(&mut self, keys: &[&Q]) -> Option<DisjointMut<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut sorted: Vec<_> = keys.iter().enumerate().map(|(i, q)| (*q, i)).collect();
+        sorted.sort_by(|a, b| a.0.cmp(b.0));
+
+        if sorted.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return None;
+        }
+        let mut entries = Vec::with_capacity(keys.len());
+
+        for key in keys {
+            let (key, record) = self.data.get_key_value(*key)?;
+            entries.push((key.clone(), record.value.clone()?, false));
+        }
+
+        Some(DisjointMut {
+            entries,
+            store: self,
+        })
     }
 
-    mod strict_mode {
-        use super::*;
+    pub fn values_mut(&mut self) -> impl Iterator<Item = RevGuard<'_, K, V>> {
+        let keys: Vec<_> = self.live_keys().cloned().collect();
+        let store = Rc::new(RefCell::new(self));
 
-        const MODE: Cas = Cas::Strict;
+        keys.into_iter().filter_map(move |key| {
+            let value = RefCell::borrow(&store).get(&key)?.clone();
 
-        #[test]
-        fn returns_a_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+            Some(RevGuard {
+                store: store.clone(),
+                key,
+                value,
+                dirty: false,
+            })
+        })
+    }
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.read("x"), Some((2, None)));
+    fn validate_key(&self, key: &K) -> Result<(), String> {
+        match &self.validator {
+            Some(validator) => validator(key),
+            None => Ok(()),
         }
+    }
+
+    fn accepts(&self, key: &K, rev: Option<Rev>) -> bool {
+        let client_rev = rev.unwrap_or(0);
+
+        let (current, live) = match self.data.get(key) {
+            Some(record) => (record.rev, record.value.is_some()),
+            None => (0, false),
+        };
+
+        if live || self.config.store == Cas::Strict {
+            client_rev == current
+        } else if self.config.store == Cas::MatchRev {
+            client_rev == 0 || client_rev == current
+        } else {
+            self.config.store != Cas::NoRev || client_rev == 0
+        }
+    }
+
+    fn set_key(
+        &mut self,
+        key: K,
+        rev: Option<Rev>,
+        value: Option<V>,
+    ) -> Result<Option<Rev>, String> {
+        self.validate_key(&key)?;
+
+        if !self.accepts(&key, rev) {
+            self.data.get_or_insert_with(key, empty);
+            self.conflicts += 1;
+            return Ok(None);
+        }
+
+        Ok(Some(self.commit(key, value)))
+    }
+
+    fn commit(&mut self, key: K, value: Option<V>) -> Rev {
+        self.seq += 1;
+        self.apply(key, value)
+    }
+
+    // Writes the key as part of the change at the current seq, for batches
+    // that advance seq once however many keys they write. Every write to a
+    // key goes through here, so derivations always apply.
+    fn apply(&mut self, key: K, value: Option<V>) -> Rev {
+        self.apply_from(key, value, 0)
+    }
+
+    // Like apply(), but never leaves the key's rev below `rev`, so that a
+    // record imported from another store keeps the rev it had there.
+    fn apply_from(&mut self, key: K, value: Option<V>, rev: Rev) -> Rev {
+        let derived = self.derive(&key, value.as_ref());
+        let rev = self.put(key, value, rev);
+
+        for (key, value) in derived {
+            self.put(key, value, 0);
+        }
+        rev
+    }
+
+    fn derive(&self, key: &K, value: Option<&V>) -> Vec<(K, Option<V>)> {
+        self.derivations
+            .iter()
+            .filter_map(|derive| derive(self, key, value))
+            .collect()
+    }
+
+    fn put(&mut self, key: K, value: Option<V>, rev: Rev) -> Rev {
+        let (key, record) = self.data.get_or_insert_with(key, empty);
+        overwrite(record, value, self.seq, self.retain, &self.snapshots);
+        record.rev = record.rev.max(rev);
+
+        let value = record.value.as_ref();
+        notify(
+            &self.observer,
+            &mut self.subscribers,
+            key,
+            record.rev,
+            value,
+        );
+        record.rev
+    }
+
+    // Includes tombstones, since their revs still matter for CAS; use
+    // live_keys() for only the keys that currently hold a value.
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.data.keys()
+    }
+
+    pub fn live_keys(&self) -> impl Iterator<Item = &K> {
+        self.live_entries().map(|(key, _)| key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.live_entries().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live_entries().next().is_none()
+    }
+
+    pub fn total_keys(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn dump(&self) -> String
+    where
+        K: Borrow<str>,
+        V: Debug,
+    {
+        self.dump_with(|value| format!("{:?}", value))
+    }
+
+    pub fn dump_with<F>(&self, mut format: F) -> String
+    where
+        K: Borrow<str>,
+        F: FnMut(&V) -> String,
+    {
+        self.data
+            .iter()
+            .filter_map(|(key, record)| {
+                let value = format(record.value.as_ref()?);
+                Some(format!(
+                    "'{}' => {{ rev: {}, value: {} }}\n",
+                    key.borrow(),
+                    record.rev,
+                    value
+                ))
+            })
+            .collect()
+    }
+
+    pub fn conflict_count(&self) -> usize {
+        self.conflicts
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        let live = self.len();
+
+        StoreStats {
+            live,
+            tombstones: self.total_keys() - live,
+            total: self.total_keys(),
+            seq: self.seq,
+        }
+    }
+
+    // Counts the inline size of each entry, including tombstones, and of the
+    // versions each one retains, but not any heap memory owned by the keys or
+    // values themselves.
+    pub fn memory_estimate(&self) -> usize {
+        let versions: usize = self.data.values().map(|record| record.versions.len()).sum();
+
+        self.data.len() * mem::size_of::<(K, Record<V>)>() + versions * mem::size_of::<Version<V>>()
+    }
+
+    pub fn contents_eq(&self, other: &Store<K, V>) -> bool
+    where
+        V: PartialEq,
+    {
+        self.live_entries().eq(other.live_entries())
+    }
+
+    pub fn max_by<F>(&self, mut f: F) -> Option<(&K, &V)>
+    where
+        F: FnMut(&V, &V) -> Ordering,
+    {
+        self.live_entries().reduce(|best, entry| {
+            if f(entry.1, best.1) == Ordering::Greater {
+                entry
+            } else {
+                best
+            }
+        })
+    }
+
+    pub fn min_by<F>(&self, mut f: F) -> Option<(&K, &V)>
+    where
+        F: FnMut(&V, &V) -> Ordering,
+    {
+        self.live_entries().min_by(|a, b| f(a.1, b.1))
+    }
+
+    fn live_entries(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.data
+            .iter()
+            .filter_map(|(key, record)| Some((key, record.value.as_ref()?)))
+    }
+}
+
+// A clone starts without the observer, which would otherwise have its state
+// shared by both stores, and by every plan the runner clones the store for.
+// Subscribers are left behind too, so they only hear of the original store.
+// Validators and derivations cannot mutate anything, so they are kept.
+impl<K, V> Clone for Store<K, V>
+where
+    K: Clone,
+    V: Clone,
+{
+    fn clone(&self) -> Store<K, V> {
+        Store {
+            data: self.data.clone(),
+            config: self.config.clone(),
+            observer: None,
+            subscribers: Vec::new(),
+            validator: self.validator.clone(),
+            derivations: self.derivations.clone(),
+            conflicts: self.conflicts,
+            retain: self.retain,
+            snapshots: self.snapshots.clone(),
+            seq: self.seq,
+        }
+    }
+}
+
+fn notify<K, V>(
+    observer: &Option<Observer<K, V>>,
+    subscribers: &mut Vec<Sender<Change<K, V>>>,
+    key: &K,
+    rev: Rev,
+    value: Option<&V>,
+) where
+    K: Clone,
+    V: Clone,
+{
+    if let Some(observer) = observer {
+        (observer.lock().unwrap())(key, rev, value);
+    }
+    subscribers.retain(|subscriber| {
+        let change = Change {
+            key: key.clone(),
+            rev,
+            value: value.cloned(),
+        };
+        subscriber.send(change).is_ok()
+    });
+}
+
+fn empty<V>() -> Record<V> {
+    Record {
+        rev: 0,
+        value: None,
+        modified: 0,
+        created: 0,
+        versions: Vec::new(),
+    }
+}
+
+// Bumps the rev of a record and sets its new value, moving the old one into
+// its versions if the store retains them.
+fn overwrite<V>(
+    record: &mut Record<V>,
+    value: Option<V>,
+    seq: Rev,
+    retain: usize,
+    snapshots: &BTreeMap<Rev, usize>,
+) {
+    let old = mem::replace(&mut record.value, value);
+
+    if record.rev > 0 {
+        let version = Version {
+            rev: record.rev,
+            value: old,
+            modified: record.modified,
+        };
+        keep_version(&mut record.versions, version, retain, snapshots);
+    } else {
+        record.created = seq;
+    }
+    record.rev += 1;
+    record.modified = seq;
+}
+
+// Versions beyond the retain limit are dropped oldest first, except for those
+// that were current at an open snapshot, which are kept until compact() runs
+// after the snapshot is closed.
+fn keep_version<V>(
+    versions: &mut Vec<Version<V>>,
+    version: Version<V>,
+    retain: usize,
+    snapshots: &BTreeMap<Rev, usize>,
+) {
+    versions.push(version);
+
+    let excess = versions.len().saturating_sub(retain);
+    let pinned: Vec<_> = (0..excess)
+        .map(|i| {
+            let replaced = versions.get(i + 1).map_or(Rev::MAX, |next| next.modified);
+            snapshots
+                .range(versions[i].modified..replaced)
+                .next()
+                .is_some()
+        })
+        .collect();
+
+    let mut i = 0;
+    versions.retain(|_| {
+        i += 1;
+        i > excess || pinned[i - 1]
+    });
+}
+
+// Values are handed out as copies, which are written back when the guard is
+// dropped, one change per mutated value in the order the keys were given.
+// Writing them back like any other write bumps their revs, keeps the values
+// they replace and applies the store's derivations.
+pub struct DisjointMut<'a, K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    store: &'a mut Store<K, V>,
+    entries: Vec<(K, V, bool)>,
+}
+
+impl<K, V> DisjointMut<'_, K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<K, V> Index<usize> for DisjointMut<'_, K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    type Output = V;
+
+    fn index(&self, i: usize) -> &V {
+        &self.entries[i].1
+    }
+}
+
+impl<K, V> IndexMut<usize> for DisjointMut<'_, K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    fn index_mut(&mut self, i: usize) -> &mut V {
+        let (_, value, dirty) = &mut self.entries[i];
+        *dirty = true;
+        value
+    }
+}
+
+impl<K, V> Drop for DisjointMut<'_, K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    fn drop(&mut self) {
+        for (key, value, dirty) in self.entries.drain(..) {
+            if dirty {
+                self.store.commit(key, Some(value));
+            }
+        }
+    }
+}
+
+// Like DisjointMut, but for one value of a pass over the whole store. The
+// guards of a pass share the store, so any number of them can be held at
+// once, and each writes its value back when it is dropped.
+pub struct RevGuard<'a, K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    store: Rc<RefCell<&'a mut Store<K, V>>>,
+    key: K,
+    value: V,
+    dirty: bool,
+}
+
+impl<K, V> RevGuard<'_, K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<K, V> Deref for RevGuard<'_, K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.value
+    }
+}
+
+impl<K, V> DerefMut for RevGuard<'_, K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    fn deref_mut(&mut self) -> &mut V {
+        self.dirty = true;
+        &mut self.value
+    }
+}
+
+impl<K, V> Drop for RevGuard<'_, K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    fn drop(&mut self) {
+        if self.dirty {
+            let value = self.value.clone();
+            self.store
+                .borrow_mut()
+                .commit(self.key.clone(), Some(value));
+        }
+    }
+}
+
+pub struct SnapshotView<K, V> {
+    data: Tree<K, Record<V>>,
+    seq: Rev,
+}
+
+impl<K, V> SnapshotView<K, V>
+where
+    K: Ord,
+{
+    pub fn seq(&self) -> Rev {
+        self.seq
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.data.get(key)?.value.as_ref()
+    }
+
+    pub fn rev<Q>(&self, key: &Q) -> Option<Rev>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.data.get(key).map(|record| record.rev)
+    }
+
+    pub fn range<Q, R>(&self, range: R) -> impl Iterator<Item = (&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        self.data
+            .range::<Q, R>(range)
+            .filter_map(|(key, record)| Some((key, record.value.as_ref()?)))
+    }
+}
+
+pub struct Cache<'a, K, V> {
+    store: &'a RefCell<Store<K, V>>,
+    data: BTreeMap<K, Option<(Rev, Option<V>)>>,
+    snapshot: Option<Rev>,
+    txn: Option<Txn<K>>,
+    merge: Option<Merge<V>>,
+    fault: Option<Fault>,
+}
+
+// The rev each key had when the transaction first touched it, and which of
+// those keys it has staged a change for in the cache's data.
+struct Txn<K> {
+    observed: BTreeMap<K, Option<Rev>>,
+    written: BTreeSet<K>,
+}
+
+impl<K, V> Cache<'_, K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    pub fn new(store: &RefCell<Store<K, V>>) -> Cache<'_, K, V> {
+        Cache {
+            store,
+            data: BTreeMap::new(),
+            snapshot: None,
+            txn: None,
+            merge: None,
+            fault: None,
+        }
+    }
+
+    // On a conflicting write, the merge function combines the current value
+    // with the one being written, and the result is written once more against
+    // the current rev.
+    pub fn with_merge<F>(store: &RefCell<Store<K, V>>, merge: F) -> Cache<'_, K, V>
+    where
+        F: Fn(&V, &V) -> V + 'static,
+    {
+        let mut cache = Cache::new(store);
+        cache.merge = Some(Box::new(merge));
+        cache
+    }
+
+    // Every read is made as of the seq the store had when the snapshot was
+    // opened, however other clients change it in the meantime. Writes still
+    // go to the current store, so they conflict with any change made since.
+    // Until close() is called, the store keeps every version the snapshot may
+    // read, even past the retain_versions() limit or with none retained.
+    pub fn snapshot(store: &RefCell<Store<K, V>>) -> Cache<'_, K, V> {
+        let seq = store.borrow_mut().open_snapshot();
+
+        Cache {
+            store,
+            data: BTreeMap::new(),
+            snapshot: Some(seq),
+            txn: None,
+            merge: None,
+            fault: None,
+        }
+    }
+
+    // Applies a fault to every change sent to the store until it is cleared.
+    pub fn inject(&mut self, fault: Option<Fault>) {
+        self.fault = fault;
+    }
+
+    pub fn read<'a, Q>(&mut self, key: &'a Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        &'a Q: Into<K>,
+    {
+        self.read_snapshot(key).unwrap_or(None)
+    }
+
+    // Like read(), but tells a missing key apart from a snapshot read the
+    // store can no longer answer, as happens once the store is rolled back
+    // past the snapshot and written again. read() takes both as missing.
+    pub fn read_snapshot<'a, Q>(&mut self, key: &'a Q) -> Result<Option<V>, String>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        &'a Q: Into<K>,
+    {
+        if !self.data.contains_key(key) {
+            let store = self.store.borrow();
+
+            let record = match self.snapshot {
+                Some(seq) => store.read_at(key, seq)?,
+                None => store.read(key),
+            };
+            drop(store);
+            self.data.insert(key.into(), record);
+        }
+        if self.txn.is_some() {
+            self.observe(key.into());
+        }
+
+        if let Some(Some((_, Some(value)))) = self.data.get(key) {
+            Ok(Some(value.clone()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn close(self) {
+        if let Some(seq) = self.snapshot {
+            self.store.borrow_mut().close_snapshot(seq);
+        }
+    }
+
+    pub fn read_fresh<'a, Q>(&mut self, key: &'a Q, max_staleness: usize) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        &'a Q: Into<K>,
+    {
+        if let Some(record) = self.data.get(key) {
+            let cached = record.as_ref().map_or(0, |(rev, _)| *rev);
+            let current = self.store.borrow().rev(key).unwrap_or(0);
+
+            if current.saturating_sub(cached) > max_staleness {
+                self.data.remove(key);
+            }
+        }
+        self.read(key)
+    }
+
+    pub fn write(&mut self, key: &K, value: V) -> bool {
+        if self.txn.is_some() {
+            self.stage(key, Some(value));
+            return true;
+        }
+        let old_rev = self.get_rev(key);
+        let sent = self.send(
+            |store| next_rev(store, key),
+            |store| store.write(key.clone(), old_rev, value.clone()),
+        );
+
+        // Only one retry is made, so a write that keeps losing to other
+        // clients still fails rather than looping.
+        let written = match sent {
+            Some(rev) => Some((rev, value)),
+            None => self.merge.as_ref().and_then(|merge| {
+                let current = self.store.borrow().read(key);
+                let rev = current.as_ref().map(|(rev, _)| *rev);
+
+                let merged = match current {
+                    Some((_, Some(current))) => merge(&current, &value),
+                    _ => value,
+                };
+                let rev = self.send(
+                    |store| next_rev(store, key),
+                    |store| store.write(key.clone(), rev, merged.clone()),
+                )?;
+                Some((rev, merged))
+            }),
+        };
+
+        if let Some((new_rev, value)) = written {
+            self.data.insert(key.clone(), Some((new_rev, Some(value))));
+            true
+        } else {
+            self.data.remove(key);
+            false
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> bool {
+        if self.txn.is_some() {
+            self.stage(key, None);
+            return true;
+        }
+        let old_rev = self.get_rev(key);
+
+        if self
+            .send(
+                |store| next_rev(store, key),
+                |store| store.remove(key.clone(), old_rev),
+            )
+            .is_some()
+        {
+            self.data.insert(key.clone(), None);
+            true
+        } else {
+            self.data.remove(key);
+            false
+        }
+    }
+
+    pub fn scan(&self, prefix: &str) -> Vec<(K, Rev, V)>
+    where
+        K: Borrow<str>,
+    {
+        let store = self.store.borrow();
+
+        let mut entries: BTreeMap<&K, (Rev, &V)> = store
+            .scan_entries(prefix)
+            .map(|(key, rev, value)| (key, (rev, value)))
+            .collect();
+
+        let bounds = (Bound::Included(prefix), Bound::Unbounded);
+
+        for (key, record) in self.data.range::<str, _>(bounds) {
+            if !key.borrow().starts_with(prefix) {
+                break;
+            }
+            match record {
+                Some((rev, Some(value))) if key.borrow() != prefix => {
+                    entries.insert(key, (*rev, value));
+                }
+                _ => {
+                    entries.remove(key);
+                }
+            }
+        }
+
+        entries
+            .into_iter()
+            .map(|(key, (rev, value))| (key.clone(), rev, value.clone()))
+            .collect()
+    }
+
+    pub fn write_many(&mut self, entries: &[(K, Option<V>)]) -> bool {
+        if self.txn.is_some() {
+            for (key, value) in entries {
+                self.stage(key, value.clone());
+            }
+            return true;
+        }
+        let ops: Vec<_> = entries
+            .iter()
+            .map(|(key, value)| (key.clone(), self.get_rev(key), value.clone()))
+            .collect();
+
+        if let Some(revs) = self.send(
+            |store| next_revs(store, &ops),
+            |store| store.write_many(&ops),
+        ) {
+            for ((key, _, value), rev) in ops.into_iter().zip(revs) {
+                self.data.insert(key, value.map(|value| (rev, Some(value))));
+            }
+            true
+        } else {
+            for (key, _) in entries {
+                self.data.remove(key);
+            }
+            false
+        }
+    }
+
+    // Until commit() or rollback(), writes and removes are only staged in the
+    // cache, and every key read or written is checked again on commit.
+    pub fn begin(&mut self) {
+        self.txn = Some(Txn {
+            observed: BTreeMap::new(),
+            written: BTreeSet::new(),
+        });
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.txn.is_some()
+    }
+
+    // Applies the staged changes with a single write_many(), but only if no
+    // key the transaction read or wrote has changed since it was first seen.
+    // Otherwise the store is left untouched and the transaction rolled back.
+    pub fn commit(&mut self) -> bool {
+        let Some(txn) = self.txn.take() else {
+            return true;
+        };
+        let store = self.store.borrow();
+
+        let unchanged = txn
+            .observed
+            .iter()
+            .all(|(key, rev)| store.read(key).map(|(rev, _)| rev) == *rev);
+        drop(store);
+
+        let ops: Vec<_> = txn
+            .written
+            .iter()
+            .map(|key| (key.clone(), txn.observed[key], self.staged(key)))
+            .collect();
+
+        let revs = if !unchanged {
+            None
+        } else if ops.is_empty() {
+            Some(Vec::new())
+        } else {
+            self.send(
+                |store| next_revs(store, &ops),
+                |store| store.write_many(&ops),
+            )
+        };
+
+        let Some(revs) = revs else {
+            self.forget(txn.observed.keys());
+            return false;
+        };
+        for ((key, _, value), rev) in ops.into_iter().zip(revs) {
+            self.data.insert(key, value.map(|value| (rev, Some(value))));
+        }
+        true
+    }
+
+    pub fn rollback(&mut self) {
+        if let Some(txn) = self.txn.take() {
+            self.forget(txn.observed.keys());
+        }
+    }
+
+    // A dropped change is acknowledged with the revs the store would have
+    // given its keys, and a duplicated one is sent once more if it was
+    // accepted.
+    fn send<A, F, R>(&self, acked: A, op: F) -> Option<R>
+    where
+        A: FnOnce(&Store<K, V>) -> R,
+        F: Fn(&mut Store<K, V>) -> Option<R>,
+    {
+        let mut store = self.store.borrow_mut();
+
+        match self.fault {
+            Some(Fault::Drop) => Some(acked(&store)),
+            Some(Fault::Duplicate) => {
+                let sent = op(&mut store)?;
+                op(&mut store);
+                Some(sent)
+            }
+            None => op(&mut store),
+        }
+    }
+
+    fn observe(&mut self, key: K) {
+        let rev = self.get_rev(&key);
+
+        if let Some(txn) = &mut self.txn {
+            txn.observed.entry(key).or_insert(rev);
+        }
+    }
+
+    fn stage(&mut self, key: &K, value: Option<V>) {
+        self.observe(key.clone());
+        let rev = self.get_rev(key).unwrap_or(0);
+
+        if let Some(txn) = &mut self.txn {
+            txn.written.insert(key.clone());
+        }
+        self.data
+            .insert(key.clone(), value.map(|value| (rev, Some(value))));
+    }
+
+    fn staged(&self, key: &K) -> Option<V> {
+        match self.data.get(key) {
+            Some(Some((_, value))) => value.clone(),
+            _ => None,
+        }
+    }
+
+    // Dropping the cached entries makes the next read fetch them again, the
+    // same way a failed write does.
+    fn forget<'k>(&mut self, keys: impl Iterator<Item = &'k K>)
+    where
+        K: 'k,
+    {
+        for key in keys {
+            self.data.remove(key);
+        }
+    }
+
+    fn get_rev(&self, key: &K) -> Option<Rev> {
+        if let Some(Some((rev, _))) = self.data.get(key) {
+            Some(*rev)
+        } else {
+            None
+        }
+    }
+}
+
+// Revs are kept per key, so a change to a key always gives it the rev after
+// the one it holds, whatever else has been written since.
+fn next_rev<K, V>(store: &Store<K, V>, key: &K) -> Rev
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    store.rev(key).unwrap_or(0) + 1
+}
+
+fn next_revs<K, V>(store: &Store<K, V>, ops: &[(K, Option<Rev>, Option<V>)]) -> Vec<Rev>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    ops.iter().map(|(key, _, _)| next_rev(store, key)).collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    use crate::db::{Db, DbStore};
+    use crate::graph::Graph;
+    use crate::path::{path_validator, Path};
+
+    #[test]
+    fn returns_none_for_unknown_key() {
+        let store: Store<String, ()> = Store::new(Config::new());
+        assert_eq!(store.seq, 0);
+        assert_eq!(store.read("x"), None);
+    }
+
+    #[test]
+    fn returns_a_default_for_removed_or_unknown_keys() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+        store.remove("y".into(), Some(1));
+
+        assert_eq!(store.get_or("x", &'z'), &'a');
+        assert_eq!(store.get_or("y", &'z'), &'z');
+        assert_eq!(store.get_or("w", &'z'), &'z');
+    }
+
+    #[test]
+    fn reads_a_value_only_if_it_changed_since_a_known_rev() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+
+        assert_eq!(store.read_if_changed("x", 1), ReadResult::NotModified);
+
+        store.write("x".into(), Some(1), 'b');
+        assert_eq!(
+            store.read_if_changed("x", 1),
+            ReadResult::Modified { rev: 2, value: 'b' }
+        );
+
+        store.remove("x".into(), Some(2));
+        assert_eq!(
+            store.read_if_changed("x", 2),
+            ReadResult::Deleted { rev: 3 }
+        );
+        assert_eq!(store.read_if_changed("x", 3), ReadResult::NotModified);
+    }
+
+    #[test]
+    fn reads_an_unknown_key_as_unmodified_from_rev_zero() {
+        let store: Store<String, char> = Store::new(Config::new());
+
+        assert_eq!(store.read_if_changed("x", 0), ReadResult::NotModified);
+        assert_eq!(
+            store.read_if_changed("x", 1),
+            ReadResult::Deleted { rev: 0 }
+        );
+    }
+
+    #[test]
+    fn stores_a_new_value() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        assert_eq!(store.write("x".into(), None, 'a'), Some(1));
+        assert_eq!(store.seq, 1);
+        assert_eq!(store.read("x"), Some((1, Some('a'))));
+    }
+
+    #[test]
+    fn does_not_update_a_value_without_a_rev() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+
+        assert_eq!(store.write("x".into(), None, 'b'), None);
+        assert_eq!(store.seq, 1);
+        assert_eq!(store.read("x"), Some((1, Some('a'))));
+    }
+
+    #[test]
+    fn does_not_update_a_value_with_a_bad_rev() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        let rev = store.write("x".into(), None, 'a');
+
+        assert_eq!(store.write("x".into(), rev.map(|r| r + 1), 'b'), None);
+        assert_eq!(store.seq, 1);
+        assert_eq!(store.read("x"), Some((1, Some('a'))));
+    }
+
+    #[test]
+    fn updates_a_value_with_a_matching_rev() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        let rev = store.write("x".into(), None, 'a');
+
+        assert_eq!(store.write("x".into(), rev, 'b'), Some(2));
+        assert_eq!(store.seq, 2);
+        assert_eq!(store.read("x"), Some((2, Some('b'))));
+    }
+
+    #[test]
+    fn removes_a_value() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        let rev = store.write("x".into(), None, 'a');
+
+        assert_eq!(store.remove("x".into(), rev), Some(2));
+        assert_eq!(store.read("x"), Some((2, None)));
+    }
+
+    mod strict_mode {
+        use super::*;
+
+        const MODE: Cas = Cas::Strict;
+
+        #[test]
+        fn returns_a_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.read("x"), Some((2, None)));
+        }
+
+        #[test]
+        fn does_not_allow_write_with_no_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.write("x".into(), None, 'b'), None);
+            assert_eq!(store.read("x"), Some((2, None)));
+        }
+
+        #[test]
+        fn allows_write_with_matching_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.write("x".into(), Some(2), 'b'), Some(3));
+            assert_eq!(store.read("x"), Some((3, Some('b'))));
+        }
+
+        #[test]
+        fn does_not_allow_write_with_non_matching_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.write("x".into(), Some(99), 'b'), None);
+            assert_eq!(store.read("x"), Some((2, None)));
+        }
+    }
+
+    mod match_rev_mode {
+        use super::*;
+
+        const MODE: Cas = Cas::MatchRev;
+
+        #[test]
+        fn returns_a_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.read("x"), Some((2, None)));
+        }
+
+        #[test]
+        fn allows_write_with_no_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.write("x".into(), None, 'b'), Some(3));
+            assert_eq!(store.read("x"), Some((3, Some('b'))));
+        }
+
+        #[test]
+        fn allows_write_with_matching_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.write("x".into(), Some(2), 'b'), Some(3));
+            assert_eq!(store.read("x"), Some((3, Some('b'))));
+        }
+
+        #[test]
+        fn does_not_allow_write_with_non_matching_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.write("x".into(), Some(99), 'b'), None);
+            assert_eq!(store.read("x"), Some((2, None)));
+        }
+    }
+
+    mod no_rev_mode {
+        use super::*;
+
+        const MODE: Cas = Cas::NoRev;
+
+        #[test]
+        fn does_not_return_a_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.read("x"), None);
+        }
+
+        #[test]
+        fn allows_write_with_no_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.write("x".into(), None, 'b'), Some(3));
+            assert_eq!(store.read("x"), Some((3, Some('b'))));
+        }
+
+        #[test]
+        fn does_not_allow_write_with_matching_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.write("x".into(), Some(2), 'b'), None);
+            assert_eq!(store.read("x"), None);
+        }
+
+        #[test]
+        fn does_not_allow_write_with_non_matching_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.write("x".into(), Some(99), 'b'), None);
+            assert_eq!(store.read("x"), None);
+        }
+    }
+
+    mod lax_mode {
+        use super::*;
+
+        const MODE: Cas = Cas::Lax;
+
+        #[test]
+        fn does_not_return_a_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.read("x"), None);
+        }
+
+        #[test]
+        fn allows_write_with_no_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.write("x".into(), None, 'b'), Some(3));
+            assert_eq!(store.read("x"), Some((3, Some('b'))));
+        }
+
+        #[test]
+        fn allows_write_with_matching_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.write("x".into(), Some(2), 'b'), Some(3));
+            assert_eq!(store.read("x"), Some((3, Some('b'))));
+        }
+
+        #[test]
+        fn allows_write_with_non_matching_rev_after_remove() {
+            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
+            let rev = store.write("x".into(), None, 'a');
+
+            assert_eq!(store.remove("x".into(), rev), Some(2));
+            assert_eq!(store.write("x".into(), Some(99), 'b'), Some(3));
+            assert_eq!(store.read("x"), Some((3, Some('b'))));
+        }
+    }
+
+    #[test]
+    fn dumps_live_contents_in_key_order() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("y".into(), None, 'b');
+        store.write("x".into(), None, 'a');
+        store.write("x".into(), Some(1), 'c');
+
+        assert_eq!(
+            store.dump(),
+            "'x' => { rev: 2, value: 'c' }\n'y' => { rev: 1, value: 'b' }\n"
+        );
+        assert_eq!(
+            store.dump_with(|c| c.to_uppercase().to_string()),
+            "'x' => { rev: 2, value: C }\n'y' => { rev: 1, value: B }\n"
+        );
+    }
+
+    #[test]
+    fn dumps_stores_with_different_histories_identically() {
+        let mut a: Store<String, _> = Store::new(Config::new());
+        a.write("x".into(), None, 'a');
+        a.write("y".into(), None, 'b');
+        a.remove("y".into(), Some(1));
+
+        let mut b: Store<String, _> = Store::new(Config::new());
+        b.write("z".into(), None, 'c');
+        b.write("z".into(), Some(1), 'd');
+        b.remove("z".into(), Some(2));
+        b.write("x".into(), None, 'a');
+
+        assert_ne!(a.seq, b.seq);
+        assert_eq!(a.dump(), b.dump());
+    }
+
+    #[test]
+    fn counts_writes_rejected_for_a_rev_mismatch() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        assert_eq!(store.conflict_count(), 0);
+
+        store.write("x".into(), None, 'a');
+        store.write("x".into(), Some(1), 'b');
+        assert_eq!(store.conflict_count(), 0);
+
+        for rev in [None, Some(1), Some(3)] {
+            assert_eq!(store.write("x".into(), rev, 'c'), None);
+        }
+        assert_eq!(store.remove("x".into(), Some(1)), None);
+        assert_eq!(store.conflict_count(), 4);
+
+        store.remove("x".into(), Some(2));
+        assert_eq!(store.conflict_count(), 4);
+
+        let batch = [
+            ("x".to_string(), 1, Some('d')),
+            ("y".to_string(), 1, Some('d')),
+            ("z".to_string(), 0, Some('d')),
+        ];
+        assert!(store.cas_many(&batch).is_err());
+        assert_eq!(store.conflict_count(), 6);
+
+        store.reset();
+        assert_eq!(store.conflict_count(), 0);
+    }
+
+    #[test]
+    fn updates_a_different_key() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a').unwrap();
+
+        assert_eq!(store.write("y".into(), None, 'z'), Some(1));
+        assert_eq!(store.seq, 2);
+        assert_eq!(store.read("x"), Some((1, Some('a'))));
+        assert_eq!(store.read("y"), Some((1, Some('z'))));
+    }
+
+    #[test]
+    fn returns_a_copy_of_the_stored_value() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, vec![4, 5, 6]);
+
+        let mut a = store.read("x").unwrap().1.unwrap();
+        a.push(7);
+
+        assert_eq!(store.read("x"), Some((1, Some(vec![4, 5, 6]))));
+    }
+
+    #[test]
+    fn touches_a_value_bumping_its_rev() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+
+        assert_eq!(store.touch("x".into(), Some(1)), Some(2));
+        assert_eq!(store.seq, 2);
+        assert_eq!(store.read("x"), Some((2, Some('a'))));
+
+        assert_eq!(store.write("x".into(), Some(1), 'b'), None);
+        assert_eq!(store.write("x".into(), Some(2), 'b'), Some(3));
+    }
+
+    #[test]
+    fn does_not_touch_a_value_with_a_bad_rev() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+
+        assert_eq!(store.touch("x".into(), Some(2)), None);
+        assert_eq!(store.touch("y".into(), None), None);
+        assert_eq!(store.seq, 1);
+        assert_eq!(store.read("x"), Some((1, Some('a'))));
+        assert_eq!(store.read("y"), None);
+    }
+
+    #[test]
+    fn touches_a_removed_value_leaving_it_removed() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.remove("x".into(), Some(1));
+
+        assert_eq!(store.touch("x".into(), Some(2)), Some(3));
+        assert_eq!(store.seq, 3);
+        assert_eq!(store.read("x"), Some((3, None)));
+        assert_eq!(store.write("x".into(), Some(2), 'b'), None);
+    }
+
+    #[test]
+    fn applies_each_entry_in_a_batch_independently() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+
+        let results = store.apply_batch(&[
+            ("x".into(), Some(1), Some('c')),
+            ("y".into(), Some(2), Some('d')),
+            ("z".into(), None, Some('e')),
+            ("x".into(), Some(2), None),
+            ("y".into(), None, None),
+        ]);
+
+        assert_eq!(results, [Some(2), None, Some(1), Some(3), None]);
+        assert_eq!(store.seq, 5);
+        assert_eq!(store.read("x"), Some((3, None)));
+        assert_eq!(store.read("y"), Some((1, Some('b'))));
+        assert_eq!(store.read("z"), Some((1, Some('e'))));
+    }
+
+    #[test]
+    fn applies_a_conditional_batch_when_every_rev_matches() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+        store.remove("y".into(), Some(1));
+
+        let result = store.cas_many(&[
+            ("x".into(), 1, Some('c')),
+            ("y".into(), 2, Some('d')),
+            ("z".into(), 0, Some('e')),
+            ("w".into(), 0, None),
+        ]);
+
+        assert_eq!(result, Ok(vec![2, 3, 1, 1]));
+        assert_eq!(store.seq, 4);
+        assert_eq!(store.read("x"), Some((2, Some('c'))));
+        assert_eq!(store.read("y"), Some((3, Some('d'))));
+        assert_eq!(store.read("z"), Some((1, Some('e'))));
+        assert_eq!(store.read("w"), Some((1, None)));
+    }
+
+    #[test]
+    fn applies_nothing_from_a_conditional_batch_with_a_conflict() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+        store.write("y".into(), Some(1), 'c');
+
+        let result = store.cas_many(&[
+            ("x".into(), 1, Some('d')),
+            ("y".into(), 1, Some('e')),
+            ("z".into(), 0, Some('f')),
+        ]);
+
+        assert_eq!(result, Err(vec![(1, 2, Some('c'))]));
+        assert_eq!(store.seq, 3);
+        assert_eq!(store.read("x"), Some((1, Some('a'))));
+        assert_eq!(store.read("y"), Some((2, Some('c'))));
+        assert_eq!(store.read("z"), None);
+    }
+
+    #[test]
+    fn rejects_a_conditional_batch_that_repeats_a_key() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+
+        let result = store.cas_many(&[("x".into(), 1, Some('b')), ("x".into(), 1, Some('c'))]);
+
+        assert_eq!(result, Err(vec![(1, 1, Some('a'))]));
+        assert_eq!(store.seq, 1);
+        assert_eq!(store.read("x"), Some((1, Some('a'))));
+    }
+
+    #[test]
+    fn deletes_a_value_only_if_it_matches() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+
+        assert!(!store.delete_if("x", &'b'));
+        assert_eq!(store.seq, 1);
+        assert_eq!(store.read("x"), Some((1, Some('a'))));
+
+        assert!(store.delete_if("x", &'a'));
+        assert_eq!(store.seq, 2);
+        assert_eq!(store.read("x"), Some((2, None)));
+    }
+
+    #[test]
+    fn does_not_delete_a_removed_or_unknown_value() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.remove("x".into(), Some(1));
+
+        assert!(!store.delete_if("x", &'a'));
+        assert!(!store.delete_if("y", &'a'));
+        assert_eq!(store.seq, 2);
+        assert_eq!(store.read("x"), Some((2, None)));
+        assert_eq!(store.read("y"), None);
+    }
+
+    #[test]
+    fn deletes_by_value_regardless_of_intervening_revs() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("x".into(), Some(1), 'b');
+        store.write("x".into(), Some(2), 'a');
+
+        assert_eq!(store.remove("x".into(), Some(1)), None);
+        assert!(store.delete_if("x", &'a'));
+        assert_eq!(store.read("x"), Some((4, None)));
+    }
+
+    #[test]
+    fn clears_live_keys_leaving_tombstones() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+        store.remove("y".into(), Some(1));
+        store.write("z".into(), None, 'c');
+
+        assert_eq!(store.clear(), 2);
+        assert_eq!(store.seq, 6);
+        assert_eq!(store.read("x"), Some((2, None)));
+        assert_eq!(store.read("y"), Some((2, None)));
+        assert_eq!(store.read("z"), Some((2, None)));
+
+        assert_eq!(store.write("x".into(), Some(1), 'd'), None);
+        assert_eq!(store.write("x".into(), Some(2), 'd'), Some(3));
+    }
+
+    #[test]
+    fn removes_every_live_key_under_a_prefix() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("/a".into(), None, 'f');
+        store.write("/a/".into(), None, 'd');
+        store.write("/a/b/".into(), None, 'd');
+        store.write("/a/b/x".into(), None, 'x');
+        store.write("/a/y".into(), None, 'y');
+        store.remove("/a/y".into(), Some(1));
+        store.write("/ab".into(), None, 'z');
+
+        let removed = store.prefix_remove("/a/");
+
+        let expected: Vec<(String, Rev)> =
+            vec![("/a/".into(), 2), ("/a/b/".into(), 2), ("/a/b/x".into(), 2)];
+        assert_eq!(removed, expected);
+        assert_eq!(store.seq, 10);
+
+        assert!(store
+            .keys()
+            .filter(|k| k.starts_with("/a/"))
+            .all(|k| store.get(k).is_none()));
+        assert_eq!(store.read("/a"), Some((1, Some('f'))));
+        assert_eq!(store.read("/ab"), Some((1, Some('z'))));
+        assert_eq!(store.read("/a/y"), Some((2, None)));
+
+        assert_eq!(store.write("/a/b/x".into(), Some(1), 'w'), None);
+        assert_eq!(store.write("/a/b/x".into(), Some(2), 'w'), Some(3));
+    }
+
+    #[test]
+    fn counts_live_entries_and_tombstones() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+        store.write("z".into(), None, 'c');
+        store.remove("y".into(), Some(1));
+        store.write("x".into(), Some(1), 'd');
+        store.remove("z".into(), Some(1));
+        store.write("z".into(), Some(2), 'e');
+
+        let stats = store.stats();
+        assert_eq!(
+            stats,
+            StoreStats {
+                live: 2,
+                tombstones: 1,
+                total: 3,
+                seq: 7
+            }
+        );
+        assert_eq!(stats.live + stats.tombstones, stats.total);
+    }
+
+    #[test]
+    fn estimates_memory_held_by_entries_and_tombstones() {
+        let mut store: Store<String, usize> = Store::new(Config::new());
+        assert_eq!(store.memory_estimate(), 0);
+
+        store.write("x".into(), None, 1);
+        let one = store.memory_estimate();
+        assert!(one > 0);
+
+        store.write("y".into(), None, 2);
+        assert_eq!(store.memory_estimate(), 2 * one);
+
+        store.clear();
+        assert_eq!(store.memory_estimate(), 2 * one);
+
+        store.reset();
+        assert_eq!(store.memory_estimate(), 0);
+    }
+
+    #[test]
+    fn estimates_memory_held_by_retained_versions() {
+        let mut store: Store<String, usize> = Store::new(Config::new());
+        store.retain_versions(8);
+
+        store.write("x".into(), None, 1);
+        let one = store.memory_estimate();
+
+        let seq = store.open_snapshot();
+        store.write("x".into(), Some(1), 2);
+        store.write("x".into(), Some(2), 3);
+        let held = store.memory_estimate();
+        assert!(held > one);
+
+        store.close_snapshot(seq);
+        assert_eq!(store.compact(), 2);
+        assert_eq!(store.memory_estimate(), one);
+    }
+
+    #[test]
+    fn splits_off_keys_from_a_given_key_onwards() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("a".into(), None, 'a');
+        store.write("m".into(), None, 'm');
+        store.write("m".into(), Some(1), 'n');
+        store.write("x".into(), None, 'x');
+        store.remove("x".into(), Some(1));
+
+        let shard = store.split_off("m");
+
+        assert_eq!(store.keys().collect::<Vec<_>>(), ["a"]);
+        assert_eq!(store.seq, 5);
+        assert_eq!(shard.keys().collect::<Vec<_>>(), ["m", "x"]);
+        assert_eq!(shard.seq, 2);
+        assert_eq!(shard.read("m"), Some((2, Some('n'))));
+        assert_eq!(shard.read("x"), Some((2, None)));
+    }
+
+    #[test]
+    fn keeps_revs_valid_for_keys_moved_to_a_new_shard() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("m".into(), None, 'm');
+        store.write("x".into(), None, 'x');
+        store.remove("x".into(), Some(1));
+
+        let mut shard = store.split_off("m");
+
+        assert_eq!(shard.write("m".into(), None, 'n'), None);
+        assert_eq!(shard.write("m".into(), Some(1), 'n'), Some(2));
+        assert_eq!(shard.write("x".into(), None, 'y'), None);
+        assert_eq!(shard.write("x".into(), Some(2), 'y'), Some(3));
+        assert_eq!(shard.seq, 4);
+        assert_eq!(store.read("m"), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn round_trips_revs_and_tombstones_through_json() {
+        let mut store: Store<String, char> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("x".into(), Some(1), 'b');
+        store.write("y".into(), None, 'c');
+        store.remove("y".into(), Some(1));
+
+        let mut reloaded: Store<String, char> =
+            Store::from_json(&store.to_json().unwrap()).unwrap();
+
+        assert_eq!(reloaded.stats(), store.stats());
+        assert_eq!(reloaded.dump(), store.dump());
+        assert_eq!(reloaded.read("y"), Some((2, None)));
+        assert_eq!(reloaded.changes_since(0), store.changes_since(0));
+
+        assert_eq!(reloaded.write("x".into(), Some(1), 'd'), None);
+        assert_eq!(reloaded.write("x".into(), Some(2), 'd'), Some(3));
+        assert_eq!(reloaded.write("y".into(), None, 'e'), None);
+        assert_eq!(reloaded.write("y".into(), Some(2), 'e'), Some(3));
+        assert_eq!(reloaded.seq, 6);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn round_trips_a_db_store_through_json() {
+        let mut store: DbStore<char> = Store::new(Config::new());
+        store.write("/".into(), None, Db::dir_from(&["x.json"]));
+        store.write("/x.json".into(), None, Db::Doc('a'));
+        store.remove("/x.json".into(), Some(1));
+
+        let reloaded: DbStore<char> = Store::from_json(&store.to_json().unwrap()).unwrap();
+
+        assert_eq!(reloaded.get("/"), Some(&Db::dir_from(&["x.json"])));
+        assert_eq!(reloaded.read("/x.json"), Some((2, None)));
+        assert_eq!(reloaded.seq, store.seq);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn fails_to_save_keys_that_json_cannot_use_as_object_keys() {
+        let mut store: Store<(u8, u8), char> = Store::new(Config::new());
+        store.write((1, 2), None, 'a');
+
+        assert!(store.to_json().is_err());
+    }
+
+    #[test]
+    fn counts_live_keys_separately_from_tombstones() {
+        let mut store: Store<String, char> = Store::new(Config::new());
+        assert!(store.is_empty());
+
+        store.write("x".into(), None, 'a');
+        assert_eq!(store.len(), 1);
+        assert!(!store.is_empty());
+
+        store.remove("x".into(), Some(1));
+        assert_eq!(store.len(), 0);
+        assert!(store.is_empty());
+        assert_eq!(store.total_keys(), 1);
+
+        store.write("y".into(), None, 'b');
+        assert_eq!(store.live_keys().collect::<Vec<_>>(), ["y"]);
+        assert_eq!(store.keys().collect::<Vec<_>>(), ["x", "y"]);
+    }
+
+    #[test]
+    fn resets_to_an_empty_store() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("x".into(), Some(1), 'b');
+
+        store.reset();
+        assert_eq!(store.seq, 0);
+        assert_eq!(store.read("x"), None);
+        assert_eq!(store.keys().count(), 0);
+
+        assert_eq!(store.write("x".into(), Some(2), 'c'), None);
+        assert_eq!(store.write("x".into(), None, 'c'), Some(1));
+        assert_eq!(store.seq, 1);
+    }
+
+    #[test]
+    fn forgets_open_snapshots_on_reset() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("x".into(), Some(1), 'b');
+        store.open_snapshot();
+
+        store.reset();
+        store.write("x".into(), None, 'c');
+        store.write("x".into(), Some(1), 'd');
+        store.write("x".into(), Some(2), 'e');
+
+        assert!(store.read_at("x", 2).is_err());
+    }
+
+    fn derive_listing(
+        store: &DbStore<char>,
+        key: &Path,
+        value: Option<&Db<char>>,
+    ) -> Option<(Path, Option<Db<char>>)> {
+        if key.is_dir() {
+            return None;
+        }
+        let mut entries = match store.get("/d/") {
+            Some(Db::Dir(entries)) => entries.clone(),
+            _ => BTreeSet::new(),
+        };
+        let name = key.full().trim_start_matches("/d/").to_string();
+
+        if value.is_some() {
+            entries.insert(name);
+        } else {
+            entries.remove(&name);
+        }
+        Some(("/d/".into(), Some(Db::Dir(entries))))
+    }
+
+    #[test]
+    fn keeps_derived_listings_in_sync_in_every_interleaving() {
+        let mut graph = Graph::new();
+        let a = graph.add(&[], ("/d/a", Some('a')));
+        graph.add(&[a], ("/d/a", None));
+        let b = graph.add(&[], ("/d/b", Some('b')));
+        graph.add(&[b], ("/d/b", Some('c')));
+
+        for plan in graph.orderings() {
+            let mut store = DbStore::new(Config::new());
+            store.add_derivation("/d/", derive_listing);
+
+            for (key, value) in plan {
+                let rev = store.rev(*key);
+
+                if let Some(c) = value {
+                    store.write((*key).into(), rev, Db::Doc(*c));
+                } else {
+                    store.remove((*key).into(), rev);
+                }
+
+                let children: BTreeSet<_> = store
+                    .keys()
+                    .filter(|path| path.is_doc() && store.get(*path).is_some())
+                    .map(|path| path.full().trim_start_matches("/d/").to_string())
+                    .collect();
+
+                assert_eq!(store.get("/d/"), Some(&Db::Dir(children)));
+            }
+        }
+    }
+
+    #[test]
+    fn only_derives_from_keys_under_the_trigger_prefix() {
+        let mut store = DbStore::new(Config::new());
+        store.add_derivation("/d/", derive_listing);
+
+        assert_eq!(store.write("/e/x".into(), None, Db::Doc('x')), Some(1));
+        assert_eq!(store.read("/d/"), None);
+        assert_eq!(store.seq, 1);
+
+        assert_eq!(store.write("/d/x".into(), None, Db::Doc('x')), Some(1));
+        assert_eq!(store.read("/d/"), Some((1, Some(Db::dir_from(&["x"])))));
+        assert_eq!(store.seq, 2);
+    }
+
+    #[test]
+    fn derives_from_every_kind_of_write() {
+        let mut store = DbStore::new(Config::new());
+        store.add_derivation("/d/", derive_listing);
+
+        store.write_many(&[("/d/a".into(), None, Some(Db::Doc('a')))]);
+        assert!(store
+            .cas_many(&[("/d/b".into(), 0, Some(Db::Doc('b')))])
+            .is_ok());
+        store.apply_batch(&[("/d/c".into(), None, Some(Db::Doc('c')))]);
+        assert_eq!(store.get("/d/"), Some(&Db::dir_from(&["a", "b", "c"])));
+
+        store.delete_if("/d/a", &Db::Doc('a'));
+        store.prefix_remove("/d/b");
+        assert_eq!(store.get("/d/"), Some(&Db::dir_from(&["c"])));
+
+        let mut other = DbStore::new(Config::new());
+        other.write("/d/e".into(), None, Db::Doc('e'));
+        store.import_from(&other, MergePolicy::PreferSelf).unwrap();
+        assert_eq!(store.get("/d/"), Some(&Db::dir_from(&["c", "e"])));
+    }
+
+    #[test]
+    fn derives_from_values_mutated_in_place() {
+        let mut store: Store<String, i32> = Store::new(Config::new());
+
+        store.add_derivation("n/", |store, key, value| {
+            let others: i32 = store
+                .scan("n/")
+                .filter(|(other, _, _)| *other != key)
+                .map(|(_, _, n)| n)
+                .sum();
+            Some(("sum".into(), Some(others + value.unwrap_or(&0))))
+        });
+        store.write("n/a".into(), None, 1);
+        store.write("n/b".into(), None, 2);
+        assert_eq!(store.get("sum"), Some(&3));
+
+        for mut value in store.values_mut() {
+            if value.key().starts_with("n/") {
+                *value += 10;
+            }
+        }
+        assert_eq!(store.get("sum"), Some(&23));
+
+        let mut values = store.get_disjoint_mut(&["n/b", "n/a"]).unwrap();
+        values[0] = 0;
+        values[1] = 5;
+        drop(values);
+
+        assert_eq!(store.get("sum"), Some(&5));
+        assert_eq!(store.read("n/a"), Some((3, Some(5))));
+    }
+
+    #[test]
+    fn streams_each_successful_change_to_subscribers() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        let changes = Arc::new(Mutex::new(Vec::new()));
+
+        let log = changes.clone();
+        store.set_observer(move |key: &String, _, _: Option<&char>| {
+            log.lock().unwrap().push(key.clone());
+        });
+
+        let first = store.subscribe();
+        let second = store.subscribe();
+
+        store.write("x".into(), None, 'a');
+        store.write("x".into(), Some(9), 'b');
+        store.write("y".into(), None, 'c');
+        drop(second);
+        store.remove("x".into(), Some(1));
+
+        let change = |key: &str, rev, value| Change {
+            key: key.to_string(),
+            rev,
+            value,
+        };
+        let received: Vec<_> = first.try_iter().collect();
+
+        assert_eq!(
+            received,
+            [
+                change("x", 1, Some('a')),
+                change("y", 1, Some('c')),
+                change("x", 2, None),
+            ]
+        );
+        assert_eq!(*changes.lock().unwrap(), ["x", "y", "x"]);
+    }
+
+    #[test]
+    fn keeps_subscribers_apart_from_the_observer_and_from_clones() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        let changes = store.subscribe();
+        store.set_observer(|_: &String, _, _: Option<&char>| {});
+
+        let mut copy = store.clone();
+        copy.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+
+        for mut value in store.values_mut() {
+            *value = 'c';
+        }
+
+        let keys: Vec<_> = changes.try_iter().map(|change| change.key).collect();
+        assert_eq!(keys, ["y", "y"]);
+    }
+
+    fn make_tree() -> Store<String, char> {
+        let mut store = Store::new(Config::new());
+
+        for (i, key) in [
+            "/path",
+            "/path/",
+            "/path/a",
+            "/path/b",
+            "/path/c/d",
+            "/pathx",
+        ]
+        .iter()
+        .enumerate()
+        {
+            let value = (b'a' + i as u8) as char;
+            store.write(key.to_string(), None, value);
+        }
+        store.remove("/path/b".into(), Some(1));
+
+        store
+    }
+
+    #[test]
+    fn scans_only_live_descendants_of_a_prefix() {
+        let store = make_tree();
+        let entries: Vec<_> = store.scan("/path/").collect();
+
+        assert_eq!(entries, [("/path/a", 1, &'c'), ("/path/c/d", 1, &'e')]);
+        assert_eq!(store.scan("/nothing/").count(), 0);
+    }
+
+    #[test]
+    fn scans_a_cache_including_its_own_pending_changes() {
+        let store = RefCell::new(make_tree());
+        let mut cache = Cache::new(&store);
+
+        cache.read("/path/a");
+        cache.remove(&"/path/a".into());
+        cache.write(&"/path/e".into(), 'z');
+        cache.write(&"/pathy".into(), 'y');
+
+        assert_eq!(
+            cache.scan("/path/"),
+            [
+                (String::from("/path/c/d"), 1, 'e'),
+                (String::from("/path/e"), 1, 'z'),
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_several_keys_as_one_change() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+
+        let ops = [
+            ("y".into(), Some(1), None),
+            ("x".into(), Some(1), Some('c')),
+            ("z".into(), None, Some('d')),
+        ];
+
+        assert_eq!(store.write_many(&ops), Some(vec![2, 2, 1]));
+        assert_eq!(store.seq, 3);
+        assert_eq!(store.peek("x"), Some((2, Some(&'c'))));
+        assert_eq!(store.peek("y"), Some((2, None)));
+        assert_eq!(store.peek("z"), Some((1, Some(&'d'))));
+    }
+
+    #[test]
+    fn writes_nothing_if_any_rev_in_a_batch_mismatches() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+
+        let ops = [
+            ("x".into(), Some(1), Some('c')),
+            ("y".into(), Some(2), Some('d')),
+        ];
+
+        assert_eq!(store.write_many(&ops), None);
+        assert_eq!(store.seq, 2);
+        assert_eq!(store.conflict_count(), 1);
+        assert_eq!(store.peek("x"), Some((1, Some(&'a'))));
+        assert_eq!(store.peek("y"), Some((1, Some(&'b'))));
+    }
+
+    #[test]
+    fn counts_a_conflict_for_each_mismatched_key_in_a_batch() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+        store.write("z".into(), None, 'c');
+
+        let ops = [
+            ("x".into(), Some(2), Some('d')),
+            ("y".into(), Some(1), Some('d')),
+            ("z".into(), Some(2), Some('d')),
+        ];
+
+        assert_eq!(store.write_many(&ops), None);
+        assert_eq!(store.conflict_count(), 2);
+
+        let ops = [
+            ("x".into(), Some(2), Some('d')),
+            ("y".into(), Some(2), Some('d')),
+            ("z".into(), Some(2), Some('d')),
+        ];
+
+        assert_eq!(store.write_many(&ops), None);
+        assert_eq!(store.conflict_count(), 5);
+    }
+
+    #[test]
+    fn refuses_a_batch_that_writes_a_key_twice() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+
+        let ops = [
+            ("x".into(), Some(1), Some('b')),
+            ("x".into(), Some(1), Some('c')),
+        ];
+
+        assert_eq!(store.write_many(&ops), None);
+        assert_eq!(store.seq, 1);
+        assert_eq!(store.peek("x"), Some((1, Some(&'a'))));
+    }
+
+    #[test]
+    fn notifies_the_observer_of_each_successful_change() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        let changes = Arc::new(Mutex::new(Vec::new()));
+
+        let log = changes.clone();
+        store.set_observer(move |key: &String, rev, value: Option<&char>| {
+            log.lock().unwrap().push((key.clone(), rev, value.cloned()));
+        });
+
+        store.write("x".into(), None, 'a');
+        store.write("x".into(), None, 'b');
+        store.write("x".into(), Some(9), 'c');
+        store.write("y".into(), None, 'd');
+        store.remove("x".into(), Some(1));
+        store.remove("y".into(), None);
+
+        assert_eq!(
+            *changes.lock().unwrap(),
+            [
+                ("x".into(), 1, Some('a')),
+                ("y".into(), 1, Some('d')),
+                ("x".into(), 2, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn does_not_notify_the_observer_of_changes_to_a_clone() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        let changes = Arc::new(Mutex::new(Vec::new()));
+
+        let log = changes.clone();
+        store.set_observer(move |key: &String, rev, _: Option<&char>| {
+            log.lock().unwrap().push((key.clone(), rev));
+        });
+
+        let mut copy = store.clone();
+        copy.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+
+        assert_eq!(*changes.lock().unwrap(), [("y".into(), 1)]);
+    }
+
+    #[test]
+    fn does_not_notify_the_observer_of_changes_to_a_split_off_shard() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("m".into(), None, 'm');
+        let changes = Arc::new(Mutex::new(Vec::new()));
+
+        let log = changes.clone();
+        store.set_observer(move |key: &String, rev, _: Option<&char>| {
+            log.lock().unwrap().push((key.clone(), rev));
+        });
+
+        let mut shard = store.split_off("m");
+        shard.write("m".into(), Some(1), 'n');
+        store.write("a".into(), None, 'a');
+
+        assert_eq!(*changes.lock().unwrap(), [("a".into(), 1)]);
+    }
+
+    #[test]
+    fn rejects_writes_to_keys_that_fail_validation() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.set_key_validator(path_validator);
+
+        assert_eq!(store.try_write("/a".into(), None, 'a'), Ok(Some(1)));
+        assert_eq!(
+            store.try_write("//a".into(), None, 'b'),
+            Err(String::from("key '//a' contains an empty segment"))
+        );
+        assert_eq!(
+            store.try_remove("".into(), None),
+            Err(String::from("key is empty"))
+        );
+
+        assert_eq!(store.seq, 1);
+        assert_eq!(store.read("//a"), None);
+        assert_eq!(store.read(""), None);
+    }
+
+    #[test]
+    fn leaves_the_store_unchanged_on_any_write_to_an_invalid_key() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.set_key_validator(path_validator);
+        store.write("/a".into(), None, 'a');
+
+        assert_eq!(store.write("a".into(), None, 'a'), None);
+        assert_eq!(store.apply_batch(&[("b".into(), None, Some('b'))]), [None]);
+        assert_eq!(
+            store.write_many(&[("/a".into(), Some(1), None), ("a".into(), None, None)]),
+            None
+        );
+        assert_eq!(
+            store.cas_many(&[("/a".into(), 1, None), ("a".into(), 0, Some('c'))]),
+            Err(vec![(1, 0, None)])
+        );
+
+        assert_eq!(store.seq, 1);
+        assert_eq!(store.conflict_count(), 0);
+        assert_eq!(store.total_keys(), 1);
+        assert_eq!(store.read("/a"), Some((1, Some('a'))));
+    }
+
+    #[test]
+    fn mutates_several_values_in_place() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, vec!['a']);
+        store.write("y".into(), None, vec!['b']);
+        store.write("z".into(), None, vec!['c']);
+
+        {
+            let mut values = store.get_disjoint_mut(&["z", "x"]).unwrap();
+            assert_eq!(values.len(), 2);
+            assert_eq!(values[0], ['c']);
+
+            values[0].push('d');
+            values[1].push('e');
+        }
+
+        assert_eq!(store.seq, 5);
+        assert_eq!(store.read("x"), Some((2, Some(vec!['a', 'e']))));
+        assert_eq!(store.read("y"), Some((1, Some(vec!['b']))));
+        assert_eq!(store.read("z"), Some((2, Some(vec!['c', 'd']))));
+    }
+
+    #[test]
+    fn does_not_bump_revs_of_values_that_were_only_read() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+
+        {
+            let mut values = store.get_disjoint_mut(&["x", "y"]).unwrap();
+            assert_eq!(values[0], 'a');
+            values[1] = 'c';
+        }
+
+        assert_eq!(store.seq, 3);
+        assert_eq!(store.read("x"), Some((1, Some('a'))));
+        assert_eq!(store.read("y"), Some((2, Some('c'))));
+    }
+
+    #[test]
+    fn bumps_the_rev_of_each_value_mutated_in_a_pass() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, vec!['a']);
+        store.write("y".into(), None, vec!['b']);
+        store.write("y".into(), Some(1), vec!['c']);
+        store.write("z".into(), None, vec!['d']);
+        store.remove("z".into(), Some(1));
+
+        for mut value in store.values_mut() {
+            value.push('e');
+        }
+
+        assert_eq!(store.seq, 7);
+        assert_eq!(store.read("x"), Some((2, Some(vec!['a', 'e']))));
+        assert_eq!(store.read("y"), Some((3, Some(vec!['c', 'e']))));
+        assert_eq!(store.read("z"), Some((2, None)));
+    }
+
+    #[test]
+    fn keeps_the_rev_of_values_only_read_in_a_pass() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+
+        for mut value in store.values_mut() {
+            if value.key() == "y" {
+                *value = 'c';
+            } else {
+                assert_eq!(*value, 'a');
+            }
+        }
+
+        assert_eq!(store.seq, 3);
+        assert_eq!(store.read("x"), Some((1, Some('a'))));
+        assert_eq!(store.read("y"), Some((2, Some('c'))));
+    }
+
+    #[test]
+    fn lists_changes_since_a_seq_in_the_order_they_were_written() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("b".into(), None, 'b');
+        store.write("a".into(), None, 'a');
+        let seen = store.seq;
+
+        store.write("c".into(), None, 'c');
+        store.write("a".into(), Some(1), 'x');
+        store.remove("b".into(), Some(1));
+        store.write("c".into(), None, 'y');
+
+        assert_eq!(
+            store.changes_since(seen),
+            [
+                ("c".to_string(), 1, Some('c')),
+                ("a".to_string(), 2, Some('x')),
+                ("b".to_string(), 2, None),
+            ]
+        );
+        assert_eq!(store.changes_since(0).len(), 3);
+        assert_eq!(store.changes_since(store.seq), []);
+    }
+
+    #[test]
+    fn lists_changes_made_by_guards_and_batches() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("a".into(), None, 'a');
+        store.write("b".into(), None, 'b');
+        let seen = store.seq;
+
+        for mut value in store.values_mut() {
+            if value.key() == "b" {
+                *value = 'x';
+            }
+        }
+        store.write_many(&[("d".into(), None, Some('d')), ("c".into(), None, Some('c'))]);
+
+        let keys: Vec<_> = store
+            .changes_since(seen)
+            .into_iter()
+            .map(|(key, _, _)| key)
+            .collect();
+
+        assert_eq!(keys, ["b", "c", "d"]);
+    }
+
+    #[test]
+    fn reads_values_as_of_an_earlier_seq() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.retain_versions(4);
+
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+        store.write("x".into(), Some(1), 'c');
+        store.remove("y".into(), Some(1));
+
+        assert_eq!(store.read_at("x", 0), Ok(None));
+        assert_eq!(store.read_at("x", 2), Ok(Some((1, Some('a')))));
+        assert_eq!(store.read_at("x", 3), Ok(Some((2, Some('c')))));
+        assert_eq!(store.read_at("y", 3), Ok(Some((1, Some('b')))));
+        assert_eq!(store.read_at("y", 4), Ok(Some((2, None))));
+        assert_eq!(store.read_at("z", 4), Ok(None));
+    }
+
+    #[test]
+    fn reads_only_unchanged_keys_at_an_earlier_seq_without_versions() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+        store.write("x".into(), Some(1), 'c');
+
+        assert!(store.read_at("x", 2).is_err());
+        assert_eq!(store.read_at("y", 2), Ok(Some((1, Some('b')))));
+    }
+
+    #[test]
+    fn keeps_at_most_the_retained_number_of_versions() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.retain_versions(1);
+
+        store.write("x".into(), None, 'a');
+        store.write("x".into(), Some(1), 'b');
+        store.write("x".into(), Some(2), 'c');
+
+        assert!(store.read_at("x", 1).is_err());
+        assert_eq!(store.read_at("x", 2), Ok(Some((2, Some('b')))));
+    }
+
+    #[test]
+    fn keeps_versions_replaced_through_guards() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.retain_versions(4);
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+
+        for mut value in store.values_mut() {
+            *value = 'z';
+        }
+        let mut pair = store.get_disjoint_mut(&["x"]).unwrap();
+        pair[0] = 'w';
+        drop(pair);
+
+        assert_eq!(store.read_at("x", 2), Ok(Some((1, Some('a')))));
+        assert_eq!(store.read_at("y", 2), Ok(Some((1, Some('b')))));
+        assert_eq!(store.read_at("x", 4), Ok(Some((2, Some('z')))));
+        assert_eq!(store.read_at("x", 5), Ok(Some((3, Some('w')))));
+    }
+
+    #[test]
+    fn rolls_every_key_back_to_how_it_read_at_an_earlier_seq() {
+        let mut store: Store<String, _> = Store::new(Config::new().store(Cas::Strict));
+        store.retain_versions(8);
+
+        store.write("a".into(), None, 'a');
+        store.write("b".into(), None, 'b');
+        store.write("a".into(), Some(1), 'c');
+        store.remove("b".into(), Some(1));
+        let seq = store.seq;
+
+        store.write("a".into(), Some(2), 'd');
+        store.write("b".into(), Some(2), 'e');
+        store.write("c".into(), None, 'f');
+        store.write("c".into(), Some(1), 'g');
+
+        let keys = ["a", "b", "c"];
+        let expected: Vec<_> = keys.iter().map(|key| store.read_at(*key, seq)).collect();
+
+        assert_eq!(store.rollback_to(seq), Ok(()));
+        assert_eq!(store.seq, seq);
+
+        let restored: Vec<_> = keys.iter().map(|key| Ok(store.read(*key))).collect();
+        assert_eq!(restored, expected);
+        assert_eq!(store.keys().collect::<Vec<_>>(), ["a", "b"]);
+
+        assert_eq!(store.write("a".into(), Some(3), 'h'), None);
+        assert_eq!(store.write("a".into(), Some(2), 'h'), Some(3));
+        assert_eq!(store.read_at("a", 1), Ok(Some((1, Some('a')))));
+    }
+
+    #[test]
+    fn leaves_the_store_unchanged_when_a_rollback_needs_a_dropped_version() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.retain_versions(1);
+
+        store.write("a".into(), None, 'a');
+        store.write("a".into(), Some(1), 'b');
+        store.write("a".into(), Some(2), 'c');
+        store.write("b".into(), None, 'd');
+
+        assert!(store.rollback_to(1).is_err());
+        assert!(store.rollback_to(5).is_err());
+        assert_eq!(store.seq, 4);
+        assert_eq!(store.read("a"), Some((3, Some('c'))));
+        assert_eq!(store.read("b"), Some((1, Some('d'))));
+
+        assert_eq!(store.rollback_to(2), Ok(()));
+        assert_eq!(store.read("a"), Some((2, Some('b'))));
+        assert_eq!(store.read("b"), None);
+    }
+
+    #[test]
+    fn compacts_versions_older_than_the_oldest_snapshot() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.retain_versions(8);
+
+        store.write("x".into(), None, 'a');
+        store.write("x".into(), Some(1), 'b');
+        let seq = store.open_snapshot();
+        store.write("x".into(), Some(2), 'c');
+        store.write("x".into(), Some(3), 'd');
+
+        assert_eq!(store.compact(), 1);
+        assert!(store.read_at("x", 1).is_err());
+        assert_eq!(store.read_at("x", seq), Ok(Some((2, Some('b')))));
+
+        store.close_snapshot(seq);
+
+        assert_eq!(store.compact(), 2);
+        assert!(store.read_at("x", seq).is_err());
+        assert_eq!(store.read_at("x", store.seq), Ok(Some((4, Some('d')))));
+    }
+
+    #[test]
+    fn reads_a_snapshot_unaffected_by_later_writes() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("/a".into(), None, 'a');
+        store.write("/b".into(), None, 'b');
+        store.write("/c".into(), None, 'c');
+
+        let view = store.snapshot_read();
+
+        store.write("/a".into(), Some(1), 'x');
+        store.remove("/b".into(), Some(1));
+        store.write("/bb".into(), None, 'y');
+
+        assert_eq!(view.seq(), 3);
+        assert_eq!(view.get("/a"), Some(&'a'));
+        assert_eq!(view.rev("/a"), Some(1));
+        assert_eq!(view.get("/b"), Some(&'b'));
+        assert_eq!(view.get("/bb"), None);
+
+        let range: Vec<_> = view
+            .range(String::from("/b")..String::from("/d"))
+            .map(|(key, value)| (key.as_str(), *value))
+            .collect();
+        assert_eq!(range, [("/b", 'b'), ("/c", 'c')]);
+
+        assert_eq!(store.get("/a"), Some(&'x'));
+        assert_eq!(store.get("/b"), None);
+    }
+
+    fn replicas() -> (Store<String, char>, Store<String, char>) {
+        let mut local = Store::new(Config::new());
+        local.write("/a".into(), None, 'a');
+        local.write("/b".into(), None, 'b');
+        local.write("/c".into(), None, 'c');
+        local.remove("/c".into(), Some(1));
+
+        let mut remote = Store::new(Config::new());
+        remote.write("/a".into(), None, 'x');
+        remote.write("/a".into(), Some(1), 'y');
+        remote.write("/b".into(), None, 'z');
+        remote.remove("/b".into(), Some(1));
+        remote.write("/c".into(), None, 'w');
+        remote.write("/d".into(), None, 'd');
+
+        (local, remote)
+    }
+
+    #[test]
+    fn imports_missing_keys_but_keeps_own_entries_when_preferring_self() {
+        let (mut local, remote) = replicas();
+
+        assert_eq!(local.import_from(&remote, MergePolicy::PreferSelf), Ok(1));
+        assert_eq!(local.seq, 5);
+        assert_eq!(local.peek("/a"), Some((1, Some(&'a'))));
+        assert_eq!(local.peek("/b"), Some((1, Some(&'b'))));
+        assert_eq!(local.peek("/c"), Some((2, None)));
+        assert_eq!(local.peek("/d"), Some((1, Some(&'d'))));
+    }
+
+    #[test]
+    fn replaces_values_and_tombstones_when_preferring_other() {
+        let (mut local, remote) = replicas();
+
+        assert_eq!(local.import_from(&remote, MergePolicy::PreferOther), Ok(4));
+        assert_eq!(local.seq, 8);
+        assert_eq!(local.peek("/a"), Some((2, Some(&'y'))));
+        assert_eq!(local.peek("/b"), Some((2, None)));
+        assert_eq!(local.peek("/c"), Some((3, Some(&'w'))));
+        assert_eq!(local.peek("/d"), Some((1, Some(&'d'))));
+
+        assert_eq!(local.import_from(&remote, MergePolicy::PreferOther), Ok(0));
+        assert_eq!(local.seq, 8);
+        assert!(local.contents_eq(&remote));
+    }
+
+    #[test]
+    fn never_moves_a_rev_back_when_importing_an_older_record() {
+        let mut local: Store<String, _> = Store::new(Config::new());
+        local.write("x".into(), None, 'a');
+        local.write("x".into(), Some(1), 'b');
+        local.write("x".into(), Some(2), 'c');
+
+        let mut remote = Store::new(Config::new());
+        remote.write("x".into(), None, 'z');
+
+        assert_eq!(local.import_from(&remote, MergePolicy::PreferOther), Ok(1));
+        assert_eq!(local.peek("x"), Some((4, Some(&'z'))));
+
+        assert_eq!(local.write("x".into(), Some(1), 'd'), None);
+        assert_eq!(local.write("x".into(), Some(3), 'd'), None);
+        assert_eq!(local.write("x".into(), Some(4), 'd'), Some(5));
+    }
+
+    #[test]
+    fn keeps_the_record_with_the_higher_rev() {
+        let (mut local, remote) = replicas();
+
+        assert_eq!(local.import_from(&remote, MergePolicy::HigherRev), Ok(3));
+        assert_eq!(local.seq, 7);
+        assert_eq!(local.peek("/a"), Some((2, Some(&'y'))));
+        assert_eq!(local.peek("/b"), Some((2, None)));
+        assert_eq!(local.peek("/c"), Some((2, None)));
+        assert_eq!(local.peek("/d"), Some((1, Some(&'d'))));
+    }
+
+    #[test]
+    fn imports_nothing_if_any_imported_key_fails_validation() {
+        let mut local: Store<String, _> = Store::new(Config::new());
+        local.set_key_validator(path_validator);
+        local.write("/a".into(), None, 'a');
+
+        let mut remote = Store::new(Config::new());
+        remote.write("/b".into(), None, 'b');
+        remote.write("c".into(), None, 'c');
+
+        assert_eq!(
+            local.import_from(&remote, MergePolicy::PreferOther),
+            Err(String::from("key 'c' does not begin with '/'"))
+        );
+        assert_eq!(local.seq, 1);
+        assert_eq!(local.read("/b"), None);
+        assert_eq!(local.read("c"), None);
+    }
+
+    #[test]
+    fn refuses_duplicate_or_missing_keys_for_disjoint_mutation() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+        store.remove("y".into(), Some(1));
+
+        assert!(store.get_disjoint_mut(&["x", "x"]).is_none());
+        assert!(store.get_disjoint_mut(&["x", "y"]).is_none());
+        assert!(store.get_disjoint_mut(&["x", "z"]).is_none());
+        assert!(store.get_disjoint_mut(&["x"]).is_some());
+    }
+
+    #[test]
+    fn returns_all_the_keys_in_the_store() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+
+        store.write("/".into(), None, 'a');
+        store.write("/path/".into(), None, 'b');
+        store.write("/z/doc.json".into(), None, 'c');
 
-        #[test]
-        fn does_not_allow_write_with_no_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+        let keys: Vec<_> = store.keys().collect();
+        assert_eq!(keys, ["/", "/path/", "/z/doc.json"]);
+    }
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.write("x".into(), None, 'b'), None);
-            assert_eq!(store.read("x"), Some((2, None)));
-        }
+    #[test]
+    fn compares_live_contents_ignoring_revs_and_tombstones() {
+        let mut a: Store<String, _> = Store::new(Config::new());
+        a.write("x".into(), None, 'a');
+        a.write("y".into(), None, 'b');
+
+        let mut b: Store<String, _> = Store::new(Config::new());
+        b.write("x".into(), None, 'z');
+        b.write("x".into(), Some(1), 'a');
+        b.write("y".into(), None, 'b');
+        b.write("z".into(), None, 'c');
+        b.remove("z".into(), Some(1));
+
+        assert_ne!(a.seq, b.seq);
+        assert_ne!(a.read("x"), b.read("x"));
+        assert_ne!(a.read("z"), b.read("z"));
+        assert!(a.contents_eq(&b));
+        assert!(b.contents_eq(&a));
+    }
 
-        #[test]
-        fn allows_write_with_matching_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+    #[test]
+    fn compares_live_contents_that_differ() {
+        let mut a: Store<String, _> = Store::new(Config::new());
+        a.write("x".into(), None, 'a');
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.write("x".into(), Some(2), 'b'), Some(3));
-            assert_eq!(store.read("x"), Some((3, Some('b'))));
-        }
+        let mut b: Store<String, _> = Store::new(Config::new());
+        b.write("x".into(), None, 'b');
 
-        #[test]
-        fn does_not_allow_write_with_non_matching_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+        let mut c: Store<String, _> = Store::new(Config::new());
+        c.write("x".into(), None, 'a');
+        c.write("y".into(), None, 'b');
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.write("x".into(), Some(99), 'b'), None);
-            assert_eq!(store.read("x"), Some((2, None)));
-        }
+        assert!(!a.contents_eq(&b));
+        assert!(!a.contents_eq(&c));
+        assert!(!c.contents_eq(&a));
     }
 
-    mod match_rev_mode {
-        use super::*;
+    #[test]
+    fn finds_the_live_entries_with_the_extreme_values() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("a".into(), None, 3);
+        store.write("b".into(), None, 7);
+        store.write("c".into(), None, 1);
+        store.write("d".into(), None, 9);
+        store.remove("d".into(), Some(1));
+
+        assert_eq!(store.max_by(Ord::cmp), Some((&"b".into(), &7)));
+        assert_eq!(store.min_by(Ord::cmp), Some((&"c".into(), &1)));
+    }
 
-        const MODE: Cas = Cas::MatchRev;
+    #[test]
+    fn breaks_ties_between_extreme_values_by_key_order() {
+        let mut store: Store<String, _> = Store::new(Config::new());
+        store.write("c".into(), None, 5);
+        store.write("a".into(), None, 5);
+        store.write("b".into(), None, 5);
 
-        #[test]
-        fn returns_a_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+        assert_eq!(store.max_by(Ord::cmp), Some((&"a".into(), &5)));
+        assert_eq!(store.min_by(Ord::cmp), Some((&"a".into(), &5)));
+    }
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.read("x"), Some((2, None)));
-        }
+    #[test]
+    fn finds_no_extreme_values_in_an_empty_store() {
+        let mut store: Store<String, usize> = Store::new(Config::new());
+        assert_eq!(store.max_by(Ord::cmp), None);
 
-        #[test]
-        fn allows_write_with_no_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+        store.write("a".into(), None, 1);
+        store.remove("a".into(), Some(1));
+        assert_eq!(store.min_by(Ord::cmp), None);
+    }
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.write("x".into(), None, 'b'), Some(3));
-            assert_eq!(store.read("x"), Some((3, Some('b'))));
-        }
+    #[test]
+    fn reads_a_consistent_snapshot_while_the_store_changes() {
+        let store = RefCell::new(Store::new(Config::new()));
+        store.borrow_mut().retain_versions(4);
+        store.borrow_mut().write("x".to_string(), None, 'a');
+        store.borrow_mut().write("y".to_string(), None, 'b');
 
-        #[test]
-        fn allows_write_with_matching_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+        let mut cache: Cache<String, _> = Cache::snapshot(&store);
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.write("x".into(), Some(2), 'b'), Some(3));
-            assert_eq!(store.read("x"), Some((3, Some('b'))));
-        }
+        store.borrow_mut().write("x".into(), Some(1), 'c');
+        store.borrow_mut().remove("y".into(), Some(1));
+        store.borrow_mut().write("z".into(), None, 'd');
 
-        #[test]
-        fn does_not_allow_write_with_non_matching_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+        assert_eq!(cache.read("x"), Some('a'));
+        assert_eq!(cache.read("y"), Some('b'));
+        assert_eq!(cache.read("z"), None);
+        assert!(!cache.write(&"x".into(), 'e'));
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.write("x".into(), Some(99), 'b'), None);
-            assert_eq!(store.read("x"), Some((2, None)));
-        }
+        assert_eq!(store.borrow_mut().compact(), 0);
+        cache.close();
+        assert_eq!(store.borrow_mut().compact(), 2);
     }
 
-    mod no_rev_mode {
-        use super::*;
-
-        const MODE: Cas = Cas::NoRev;
+    #[test]
+    fn writes_a_key_deleted_before_a_snapshot_was_opened() {
+        let store = RefCell::new(Store::new(Config::new()));
+        store.borrow_mut().write("x".to_string(), None, 'a');
+        store.borrow_mut().remove("x".into(), Some(1));
 
-        #[test]
-        fn does_not_return_a_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+        let mut cache: Cache<String, _> = Cache::snapshot(&store);
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.read("x"), None);
-        }
+        assert_eq!(cache.read("x"), None);
+        assert!(cache.write(&"x".into(), 'b'));
+        assert_eq!(store.borrow().read("x"), Some((3, Some('b'))));
+    }
 
-        #[test]
-        fn allows_write_with_no_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+    #[test]
+    fn hides_tombstones_at_an_earlier_seq_where_read_does() {
+        let config = Config::new().store(Cas::NoRev);
+        let mut store: Store<String, _> = Store::new(config);
+        store.retain_versions(4);
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.write("x".into(), None, 'b'), Some(3));
-            assert_eq!(store.read("x"), Some((3, Some('b'))));
-        }
+        store.write("x".into(), None, 'a');
+        store.remove("x".into(), Some(1));
+        store.write("x".into(), None, 'b');
 
-        #[test]
-        fn does_not_allow_write_with_matching_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+        assert_eq!(store.read_at("x", 2), Ok(None));
+        assert_eq!(store.read_at("x", 3), Ok(Some((3, Some('b')))));
+    }
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.write("x".into(), Some(2), 'b'), None);
-            assert_eq!(store.read("x"), None);
-        }
+    #[test]
+    fn keeps_the_versions_an_open_snapshot_reads_without_retention() {
+        let store = make_pair();
+        let mut cache: Cache<String, _> = Cache::snapshot(&store);
 
-        #[test]
-        fn does_not_allow_write_with_non_matching_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+        store.borrow_mut().write("x".into(), Some(1), 'c');
+        store.borrow_mut().write("x".into(), Some(2), 'd');
+        assert_eq!(cache.read("x"), Some('a'));
+        cache.close();
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.write("x".into(), Some(99), 'b'), None);
-            assert_eq!(store.read("x"), None);
-        }
+        assert_eq!(store.borrow_mut().compact(), 1);
+        assert!(store.borrow().read_at("x", 2).is_err());
     }
 
-    mod lax_mode {
-        use super::*;
+    #[test]
+    fn reads_a_key_created_after_an_open_snapshot_as_missing() {
+        let store: RefCell<Store<String, char>> = RefCell::new(Store::new(Config::new()));
+        let mut cache: Cache<String, _> = Cache::snapshot(&store);
 
-        const MODE: Cas = Cas::Lax;
+        store.borrow_mut().write("x".into(), None, 'a');
+        store.borrow_mut().write("x".into(), Some(1), 'b');
+        assert_eq!(cache.read_snapshot("x"), Ok(None));
+        cache.close();
 
-        #[test]
-        fn does_not_return_a_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+        assert_eq!(store.borrow().read_at("x", 0), Ok(None));
+    }
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.read("x"), None);
-        }
+    #[test]
+    fn reads_a_version_lost_to_a_rollback_as_an_error_from_a_snapshot() {
+        let store = make_pair();
+        store.borrow_mut().retain_versions(1);
+        store.borrow_mut().write("x".into(), Some(1), 'c');
+        let mut cache: Cache<String, _> = Cache::snapshot(&store);
+
+        assert_eq!(store.borrow_mut().rollback_to(2), Ok(()));
+        store.borrow_mut().retain_versions(0);
+        store.borrow_mut().write("x".into(), Some(1), 'd');
+        store.borrow_mut().write("x".into(), Some(2), 'e');
+
+        assert!(cache.read_snapshot("x").is_err());
+        assert_eq!(cache.read("x"), None);
+        assert_eq!(cache.read("y"), Some('b'));
+    }
 
-        #[test]
-        fn allows_write_with_no_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+    fn make_pair() -> RefCell<Store<String, char>> {
+        let mut store = Store::new(Config::new());
+        store.write("x".into(), None, 'a');
+        store.write("y".into(), None, 'b');
+        RefCell::new(store)
+    }
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.write("x".into(), None, 'b'), Some(3));
-            assert_eq!(store.read("x"), Some((3, Some('b'))));
-        }
+    #[test]
+    fn commits_the_changes_staged_in_a_transaction_together() {
+        let store = make_pair();
+        let mut cache = Cache::new(&store);
 
-        #[test]
-        fn allows_write_with_matching_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+        cache.begin();
+        cache.read("x");
+        cache.read("y");
+        assert!(cache.write(&"x".into(), 'c'));
+        assert!(cache.remove(&"y".into()));
+        assert!(cache.write(&"z".into(), 'd'));
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.write("x".into(), Some(2), 'b'), Some(3));
-            assert_eq!(store.read("x"), Some((3, Some('b'))));
-        }
+        assert_eq!(cache.read("x"), Some('c'));
+        assert_eq!(cache.read("y"), None);
+        assert_eq!(store.borrow().get("x"), Some(&'a'));
+        assert_eq!(store.borrow().seq, 2);
 
-        #[test]
-        fn allows_write_with_non_matching_rev_after_remove() {
-            let mut store: Store<String, _> = Store::new(Config::new().store(MODE));
-            let rev = store.write("x".into(), None, 'a');
+        assert!(cache.commit());
 
-            assert_eq!(store.remove("x".into(), rev), Some(2));
-            assert_eq!(store.write("x".into(), Some(99), 'b'), Some(3));
-            assert_eq!(store.read("x"), Some((3, Some('b'))));
-        }
+        let store = store.borrow();
+        assert_eq!(store.read("x"), Some((2, Some('c'))));
+        assert_eq!(store.read("y"), Some((2, None)));
+        assert_eq!(store.read("z"), Some((1, Some('d'))));
+        assert_eq!(store.seq, 3);
     }
 
     #[test]
-    fn updates_a_different_key() {
-        let mut store: Store<String, _> = Store::new(Config::new());
-        store.write("x".into(), None, 'a').unwrap();
+    fn fails_a_commit_if_a_key_read_by_the_transaction_changed() {
+        let store = make_pair();
+        let mut cache = Cache::new(&store);
 
-        assert_eq!(store.write("y".into(), None, 'z'), Some(1));
-        assert_eq!(store.seq, 2);
-        assert_eq!(store.read("x"), Some((1, Some('a'))));
-        assert_eq!(store.read("y"), Some((1, Some('z'))));
+        cache.begin();
+        cache.read("x");
+        cache.read("y");
+        cache.write(&"y".into(), 'c');
+
+        store.borrow_mut().write("x".into(), Some(1), 'e');
+
+        assert!(!cache.commit());
+        assert_eq!(store.borrow().read("y"), Some((1, Some('b'))));
+        assert_eq!(store.borrow().seq, 3);
+        assert_eq!(cache.read("x"), Some('e'));
+        assert_eq!(cache.read("y"), Some('b'));
     }
 
     #[test]
-    fn returns_a_copy_of_the_stored_value() {
-        let mut store: Store<String, _> = Store::new(Config::new());
-        store.write("x".into(), None, vec![4, 5, 6]);
+    fn fails_a_commit_if_a_key_written_by_the_transaction_changed() {
+        let store = make_pair();
+        let mut cache = Cache::new(&store);
 
-        let mut a = store.read("x").unwrap().1.unwrap();
-        a.push(7);
+        cache.begin();
+        cache.read("x");
+        cache.read("y");
+        cache.write(&"x".into(), 'c');
+        cache.write(&"y".into(), 'd');
 
-        assert_eq!(store.read("x"), Some((1, Some(vec![4, 5, 6]))));
+        store.borrow_mut().write("y".into(), Some(1), 'e');
+
+        assert!(!cache.commit());
+        assert_eq!(store.borrow().read("x"), Some((1, Some('a'))));
+        assert_eq!(store.borrow().read("y"), Some((2, Some('e'))));
     }
 
     #[test]
-    fn returns_all_the_keys_in_the_store() {
-        let mut store: Store<String, _> = Store::new(Config::new());
+    fn discards_the_staged_changes_on_rollback() {
+        let store = make_pair();
+        let mut cache = Cache::new(&store);
 
-        store.write("/".into(), None, 'a');
-        store.write("/path/".into(), None, 'b');
-        store.write("/z/doc.json".into(), None, 'c');
+        cache.begin();
+        cache.read("x");
+        cache.write(&"x".into(), 'c');
+        cache.rollback();
 
-        let keys: Vec<_> = store.keys().collect();
-        assert_eq!(keys, ["/", "/path/", "/z/doc.json"]);
+        assert_eq!(cache.read("x"), Some('a'));
+        assert_eq!(store.borrow().seq, 2);
+
+        assert!(cache.write(&"x".into(), 'd'));
+        assert_eq!(store.borrow().read("x"), Some((2, Some('d'))));
     }
 
     #[test]
@@ -475,6 +3472,50 @@ mod tests {
         assert_eq!(cache.read("x"), None);
     }
 
+    #[test]
+    fn serves_a_cached_value_within_the_staleness_bound() {
+        let store = RefCell::new(Store::new(Config::new()));
+        let mut cache: Cache<String, _> = Cache::new(&store);
+
+        store.borrow_mut().write("x".into(), None, 'a');
+        assert_eq!(cache.read("x"), Some('a'));
+
+        store.borrow_mut().write("x".into(), Some(1), 'b');
+        store.borrow_mut().write("x".into(), Some(2), 'c');
+
+        assert_eq!(store.borrow().rev("x"), Some(3));
+        assert_eq!(cache.read_fresh("x", 2), Some('a'));
+        assert_eq!(cache.read("x"), Some('a'));
+    }
+
+    #[test]
+    fn refreshes_a_cached_value_beyond_the_staleness_bound() {
+        let store = RefCell::new(Store::new(Config::new()));
+        let mut cache: Cache<String, _> = Cache::new(&store);
+
+        store.borrow_mut().write("x".into(), None, 'a');
+        assert_eq!(cache.read("x"), Some('a'));
+
+        store.borrow_mut().write("x".into(), Some(1), 'b');
+        store.borrow_mut().write("x".into(), Some(2), 'c');
+
+        assert_eq!(cache.read_fresh("x", 1), Some('c'));
+        assert_eq!(cache.read("x"), Some('c'));
+        assert!(cache.write(&"x".into(), 'd'));
+    }
+
+    #[test]
+    fn refreshes_a_cached_miss_once_the_key_is_written() {
+        let store = RefCell::new(Store::new(Config::new()));
+        let mut cache: Cache<String, _> = Cache::new(&store);
+
+        assert_eq!(cache.read("x"), None);
+        store.borrow_mut().write("x".into(), None, 'a');
+
+        assert_eq!(cache.read_fresh("x", 1), None);
+        assert_eq!(cache.read_fresh("x", 0), Some('a'));
+    }
+
     #[test]
     fn writes_a_value_to_the_store() {
         let store = RefCell::new(Store::new(Config::new()));
@@ -566,6 +3607,89 @@ mod tests {
         assert_eq!(cache.read("x"), Some('b'));
     }
 
+    #[test]
+    fn merges_a_conflicting_write_with_the_current_value() {
+        let store: RefCell<Store<String, i32>> = RefCell::new(Store::new(Config::new()));
+        let mut cache: Cache<String, _> =
+            Cache::with_merge(&store, |current, attempt| current + attempt);
+
+        assert!(cache.write(&"x".into(), 1));
+        assert_eq!(store.borrow_mut().write("x".into(), Some(1), 10), Some(2));
+
+        assert!(cache.write(&"x".into(), 5));
+        assert_eq!(store.borrow().read("x"), Some((3, Some(15))));
+        assert_eq!(cache.read("x"), Some(15));
+    }
+
+    #[test]
+    fn takes_a_dropped_write_as_written_without_reaching_the_store() {
+        let store: RefCell<Store<String, _>> = RefCell::new(Store::new(Config::new()));
+        let mut cache = Cache::new(&store);
+
+        cache.inject(Some(Fault::Drop));
+        assert_eq!(cache.write(&"x".into(), 'a'), true);
+        assert_eq!(cache.write_many(&[("y".into(), Some('b'))]), true);
+        cache.inject(None);
+
+        assert_eq!(cache.read("x"), Some('a'));
+        assert_eq!(cache.read("y"), Some('b'));
+        assert_eq!(store.borrow().read("x"), None);
+        assert_eq!(store.borrow().seq, 0);
+    }
+
+    #[test]
+    fn acks_a_dropped_write_with_the_next_rev_of_its_key() {
+        let store = RefCell::new(Store::new(Config::new()));
+        store.borrow_mut().write("x".into(), None, 'a');
+        store.borrow_mut().write("x".into(), Some(1), 'b');
+        store.borrow_mut().write("y".into(), None, 'c');
+
+        let mut cache: Cache<String, _> = Cache::new(&store);
+        cache.read("x");
+        cache.inject(Some(Fault::Drop));
+        assert_eq!(cache.write(&"x".into(), 'd'), true);
+        cache.inject(None);
+
+        assert_eq!(store.borrow().read("x"), Some((2, Some('b'))));
+        assert_eq!(store.borrow_mut().write("x".into(), Some(2), 'e'), Some(3));
+
+        assert_eq!(cache.write(&"x".into(), 'f'), true);
+        assert_eq!(store.borrow().read("x"), Some((4, Some('f'))));
+    }
+
+    #[test]
+    fn applies_a_fault_to_the_merged_retry_of_a_write() {
+        let store: RefCell<Store<String, i32>> = RefCell::new(Store::new(Config::new()));
+        let mut cache = Cache::with_merge(&store, |current, attempt| current + attempt);
+
+        cache.write(&"x".into(), 1);
+        store.borrow_mut().write("x".into(), Some(1), 10);
+
+        cache.inject(Some(Fault::Duplicate));
+        assert!(cache.write(&"x".into(), 5));
+
+        assert_eq!(store.borrow().read("x"), Some((3, Some(15))));
+        assert_eq!(store.borrow().conflict_count(), 2);
+    }
+
+    #[test]
+    fn resends_a_duplicated_change_with_its_original_rev() {
+        let replay = |mode: Cas| {
+            let store = RefCell::new(Store::new(Config::new().store(mode)));
+            let mut cache: Cache<String, _> = Cache::new(&store);
+
+            cache.write(&"x".into(), 'a');
+            cache.inject(Some(Fault::Duplicate));
+            assert_eq!(cache.remove(&"x".into()), true);
+
+            drop(cache);
+            store.into_inner().seq
+        };
+
+        assert_eq!(replay(Cas::Strict), 2);
+        assert_eq!(replay(Cas::Lax), 3);
+    }
+
     #[test]
     fn allows_multiple_clients_to_mutate_the_store() {
         let store = RefCell::new(Store::new(Config::new()));
@@ -0,0 +1,577 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+// An ordered map whose nodes are shared between clones, so that cloning is
+// O(1) and a write only copies the nodes on the path down to the key it
+// changes. It is an AVL tree, so that path is never longer than about
+// 1.44 * log2(len) nodes. Each node also counts the keys below it, so that
+// a tree split in two knows the len of each half.
+pub struct Tree<K, V> {
+    root: Link<K, V>,
+}
+
+type Link<K, V> = Option<Arc<Node<K, V>>>;
+
+#[derive(Clone)]
+struct Node<K, V> {
+    key: K,
+    value: V,
+    height: usize,
+    len: usize,
+    left: Link<K, V>,
+    right: Link<K, V>,
+}
+
+impl<K, V> Tree<K, V> {
+    pub fn new() -> Tree<K, V> {
+        Tree { root: None }
+    }
+
+    pub fn len(&self) -> usize {
+        len(&self.root)
+    }
+
+    pub fn clear(&mut self) {
+        self.root = None;
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get_key_value(key).map(|(_, value)| value)
+    }
+
+    pub fn get_key_value<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut link = &self.root;
+
+        while let Some(node) = link {
+            match key.cmp(node.key.borrow()) {
+                Ordering::Less => link = &node.left,
+                Ordering::Greater => link = &node.right,
+                Ordering::Equal => return Some((&node.key, &node.value)),
+            }
+        }
+        None
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        let mut iter = Iter { stack: Vec::new() };
+        iter.push_left(&self.root);
+        iter
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    pub fn range<Q, R>(&self, range: R) -> impl Iterator<Item = (&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let mut iter = Iter { stack: Vec::new() };
+        let mut link = &self.root;
+
+        while let Some(node) = link {
+            let after_start = match range.start_bound() {
+                Bound::Included(start) => node.key.borrow() >= start,
+                Bound::Excluded(start) => node.key.borrow() > start,
+                Bound::Unbounded => true,
+            };
+            if after_start {
+                iter.stack.push(node);
+                link = &node.left;
+            } else {
+                link = &node.right;
+            }
+        }
+
+        iter.take_while(move |(key, _)| match range.end_bound() {
+            Bound::Included(end) => (*key).borrow() <= end,
+            Bound::Excluded(end) => (*key).borrow() < end,
+            Bound::Unbounded => true,
+        })
+    }
+}
+
+impl<K, V> Tree<K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    pub fn insert(&mut self, key: K, value: V) {
+        insert(&mut self.root, key, || value, true);
+    }
+
+    // Stands in for BTreeMap::entry(key).or_insert_with(f), handing back the
+    // stored key alongside the value. Inserting copies the path down to the
+    // key, so the walk back down to hand out the value copies nothing; it is
+    // still needed because rebalancing may have moved the node.
+    pub fn get_or_insert_with<F>(&mut self, key: K, f: F) -> (&K, &mut V)
+    where
+        F: FnOnce() -> V,
+    {
+        insert(&mut self.root, key.clone(), f, false);
+        let mut link = &mut self.root;
+
+        loop {
+            let node = Arc::make_mut(link.as_mut().unwrap());
+
+            match key.cmp(&node.key) {
+                Ordering::Less => link = &mut node.left,
+                Ordering::Greater => link = &mut node.right,
+                Ordering::Equal => return (&node.key, &mut node.value),
+            }
+        }
+    }
+
+    // Replaces each value for which `f` returns a new one, in key order. Only
+    // the nodes holding a replaced value, and those on the path down to
+    // them, are copied from a clone; the rest stay shared.
+    pub fn update_values<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &V) -> Option<V>,
+    {
+        update_values(&mut self.root, &mut f);
+    }
+
+    pub fn split_off<Q>(&mut self, key: &Q) -> Tree<K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (kept, split) = split(self.root.take(), key);
+        self.root = kept;
+        Tree { root: split }
+    }
+}
+
+impl<K, V> Clone for Tree<K, V> {
+    fn clone(&self) -> Tree<K, V> {
+        Tree {
+            root: self.root.clone(),
+        }
+    }
+}
+
+impl<K, V> Default for Tree<K, V> {
+    fn default() -> Tree<K, V> {
+        Tree::new()
+    }
+}
+
+impl<K, V> FromIterator<(K, V)> for Tree<K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    fn from_iter<I>(iter: I) -> Tree<K, V>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut tree = Tree::new();
+
+        for (key, value) in iter {
+            tree.insert(key, value);
+        }
+        tree
+    }
+}
+
+pub struct Iter<'a, K, V> {
+    stack: Vec<&'a Node<K, V>>,
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    fn push_left(&mut self, mut link: &'a Link<K, V>) {
+        while let Some(node) = link {
+            self.stack.push(node);
+            link = &node.left;
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        let node = self.stack.pop()?;
+        self.push_left(&node.right);
+        Some((&node.key, &node.value))
+    }
+}
+
+fn height<K, V>(link: &Link<K, V>) -> usize {
+    link.as_ref().map_or(0, |node| node.height)
+}
+
+fn len<K, V>(link: &Link<K, V>) -> usize {
+    link.as_ref().map_or(0, |node| node.len)
+}
+
+fn new_node<K, V>(key: K, value: V, left: Link<K, V>, right: Link<K, V>) -> Link<K, V> {
+    let mut node = Node {
+        key,
+        value,
+        height: 0,
+        len: 0,
+        left,
+        right,
+    };
+    recount(&mut node);
+    Some(Arc::new(node))
+}
+
+fn recount<K, V>(node: &mut Node<K, V>) {
+    node.height = height(&node.left).max(height(&node.right)) + 1;
+    node.len = len(&node.left) + len(&node.right) + 1;
+}
+
+// Inserts the value made by `value`, replacing one already held under the
+// key only if `replace` is set.
+fn insert<K, V, F>(link: &mut Link<K, V>, key: K, value: F, replace: bool)
+where
+    K: Clone + Ord,
+    V: Clone,
+    F: FnOnce() -> V,
+{
+    let Some(node) = link else {
+        *link = new_node(key, value(), None, None);
+        return;
+    };
+    let node = Arc::make_mut(node);
+
+    match key.cmp(&node.key) {
+        Ordering::Less => insert(&mut node.left, key, value, replace),
+        Ordering::Greater => insert(&mut node.right, key, value, replace),
+        Ordering::Equal => {
+            if replace {
+                node.value = value();
+            }
+            return;
+        }
+    }
+    rebalance(link);
+}
+
+// Splits off the keys from `key` on, joining the subtrees left on each side
+// of the path down to it back together, so only that path is copied.
+fn split<K, V, Q>(link: Link<K, V>, key: &Q) -> (Link<K, V>, Link<K, V>)
+where
+    K: Borrow<Q> + Clone,
+    V: Clone,
+    Q: Ord + ?Sized,
+{
+    let Some(node) = link else {
+        return (None, None);
+    };
+    let node = Arc::unwrap_or_clone(node);
+
+    if node.key.borrow() < key {
+        let (below, rest) = split(node.right, key);
+        (join(node.left, node.key, node.value, below), rest)
+    } else {
+        let (below, rest) = split(node.left, key);
+        (below, join(rest, node.key, node.value, node.right))
+    }
+}
+
+// Joins two trees with a key that sorts after every key of `left` and before
+// every key of `right`. The key is put in place of the subtree down the edge
+// of the taller tree that is as tall as the other one, in time proportional
+// to the difference in their heights.
+fn join<K, V>(left: Link<K, V>, key: K, value: V, right: Link<K, V>) -> Link<K, V>
+where
+    K: Clone,
+    V: Clone,
+{
+    let mut link = if height(&left) > height(&right) + 1 {
+        let mut node = Arc::unwrap_or_clone(left.unwrap());
+        node.right = join(node.right.take(), key, value, right);
+        Some(Arc::new(node))
+    } else if height(&right) > height(&left) + 1 {
+        let mut node = Arc::unwrap_or_clone(right.unwrap());
+        node.left = join(left, key, value, node.left.take());
+        Some(Arc::new(node))
+    } else {
+        return new_node(key, value, left, right);
+    };
+    rebalance(&mut link);
+    link
+}
+
+// Nodes shared with a clone are only copied once a value below them has
+// been replaced, so a subtree whose values all stay is left shared.
+fn update_values<K, V, F>(link: &mut Link<K, V>, f: &mut F) -> bool
+where
+    K: Clone,
+    V: Clone,
+    F: FnMut(&K, &V) -> Option<V>,
+{
+    let Some(node) = link else {
+        return false;
+    };
+
+    if let Some(node) = Arc::get_mut(node) {
+        let left = update_values(&mut node.left, f);
+        let value = f(&node.key, &node.value);
+        let right = update_values(&mut node.right, f);
+
+        let changed = value.is_some();
+        if let Some(value) = value {
+            node.value = value;
+        }
+        return left || changed || right;
+    }
+
+    let (mut left, mut right) = (node.left.clone(), node.right.clone());
+    let left_changed = update_values(&mut left, f);
+    let value = f(&node.key, &node.value);
+    let right_changed = update_values(&mut right, f);
+
+    if !left_changed && value.is_none() && !right_changed {
+        return false;
+    }
+    let node = Arc::make_mut(node);
+    node.left = left;
+    node.right = right;
+
+    if let Some(value) = value {
+        node.value = value;
+    }
+    true
+}
+
+fn rebalance<K, V>(link: &mut Link<K, V>)
+where
+    K: Clone,
+    V: Clone,
+{
+    let node = Arc::make_mut(link.as_mut().unwrap());
+    let (left, right) = (height(&node.left), height(&node.right));
+
+    if left > right + 1 {
+        let child = node.left.as_ref().unwrap();
+
+        if height(&child.right) > height(&child.left) {
+            rotate_left(&mut node.left);
+        }
+        rotate_right(link);
+    } else if right > left + 1 {
+        let child = node.right.as_ref().unwrap();
+
+        if height(&child.left) > height(&child.right) {
+            rotate_right(&mut node.right);
+        }
+        rotate_left(link);
+    } else {
+        recount(node);
+    }
+}
+
+fn rotate_right<K, V>(link: &mut Link<K, V>)
+where
+    K: Clone,
+    V: Clone,
+{
+    let mut root = Arc::unwrap_or_clone(link.take().unwrap());
+    let mut left = Arc::unwrap_or_clone(root.left.take().unwrap());
+
+    root.left = left.right.take();
+    recount(&mut root);
+    left.right = Some(Arc::new(root));
+    recount(&mut left);
+
+    *link = Some(Arc::new(left));
+}
+
+fn rotate_left<K, V>(link: &mut Link<K, V>)
+where
+    K: Clone,
+    V: Clone,
+{
+    let mut root = Arc::unwrap_or_clone(link.take().unwrap());
+    let mut right = Arc::unwrap_or_clone(root.right.take().unwrap());
+
+    root.right = right.left.take();
+    recount(&mut root);
+    right.left = Some(Arc::new(root));
+    recount(&mut right);
+
+    *link = Some(Arc::new(right));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn make_tree(n: usize) -> Tree<usize, usize> {
+        (0..n).map(|i| (i, i * 10)).collect()
+    }
+
+    // Counts the nodes of `a` that are not shared with `b`.
+    fn unshared<K, V>(a: &Link<K, V>, b: &Link<K, V>) -> usize {
+        match (a, b) {
+            (Some(x), Some(y)) if Arc::ptr_eq(x, y) => 0,
+            (Some(x), Some(y)) => 1 + unshared(&x.left, &y.left) + unshared(&x.right, &y.right),
+            (Some(x), None) => 1 + unshared(&x.left, &None) + unshared(&x.right, &None),
+            (None, _) => 0,
+        }
+    }
+
+    // Counts the nodes of `a` found nowhere in `b`, wherever they now sit.
+    fn copied<K, V>(a: &Link<K, V>, b: &Link<K, V>) -> usize {
+        fn nodes<K, V>(link: &Link<K, V>, found: &mut HashSet<*const Node<K, V>>) {
+            if let Some(node) = link {
+                found.insert(Arc::as_ptr(node));
+                nodes(&node.left, found);
+                nodes(&node.right, found);
+            }
+        }
+        let (mut found, mut shared) = (HashSet::new(), HashSet::new());
+        nodes(a, &mut found);
+        nodes(b, &mut shared);
+        found.difference(&shared).count()
+    }
+
+    // Checks that every node holds the height and len of its subtree, and that
+    // the heights of its two subtrees differ by at most one.
+    fn assert_balanced<K, V>(link: &Link<K, V>) {
+        if let Some(node) = link {
+            assert_balanced(&node.left);
+            assert_balanced(&node.right);
+
+            let (left, right) = (height(&node.left), height(&node.right));
+            assert!(left.abs_diff(right) <= 1);
+            assert_eq!(node.height, left.max(right) + 1);
+            assert_eq!(node.len, len(&node.left) + len(&node.right) + 1);
+        }
+    }
+
+    #[test]
+    fn keeps_keys_in_order_and_balanced() {
+        let tree: Tree<_, _> = [5, 3, 9, 1, 4, 8, 2, 7, 6, 0]
+            .into_iter()
+            .map(|i| (i, i))
+            .collect();
+
+        assert_eq!(tree.len(), 10);
+        assert!(tree.keys().copied().eq(0..10));
+        assert_eq!(tree.get(&4), Some(&4));
+        assert_eq!(tree.get(&10), None);
+
+        let tree = make_tree(1 << 12);
+        assert!(height(&tree.root) <= 14);
+    }
+
+    #[test]
+    fn iterates_over_a_range_of_keys() {
+        let tree = make_tree(10);
+
+        let keys = |range: (Bound<&usize>, Bound<&usize>)| -> Vec<usize> {
+            tree.range(range).map(|(key, _)| *key).collect()
+        };
+
+        assert_eq!(keys((Bound::Included(&3), Bound::Excluded(&6))), [3, 4, 5]);
+        assert_eq!(keys((Bound::Excluded(&7), Bound::Unbounded)), [8, 9]);
+        assert_eq!(keys((Bound::Unbounded, Bound::Included(&1))), [0, 1]);
+        assert!(keys((Bound::Included(&12), Bound::Unbounded)).is_empty());
+    }
+
+    #[test]
+    fn copies_only_the_path_to_a_changed_key() {
+        let tree = make_tree(10_000);
+        let mut copy = tree.clone();
+
+        *copy.get_or_insert_with(1234, || 0).1 = 0;
+        assert!(unshared(&copy.root, &tree.root) <= height(&tree.root));
+
+        copy.get_or_insert_with(10_000, || 0);
+        assert!(unshared(&copy.root, &tree.root) <= 3 * height(&tree.root));
+
+        assert_eq!(tree.get(&1234), Some(&12340));
+        assert_eq!(tree.get(&10_000), None);
+        assert_eq!(copy.get(&1234), Some(&0));
+        assert_eq!(copy.len(), 10_001);
+    }
+
+    #[test]
+    fn splits_off_the_keys_from_a_given_one() {
+        let mut tree = make_tree(10);
+        let split = tree.split_off(&6);
+
+        assert!(tree.keys().copied().eq(0..6));
+        assert!(split.keys().copied().eq(6..10));
+        assert_eq!(split.len(), 4);
+    }
+
+    #[test]
+    fn splits_a_shared_tree_copying_only_the_path_to_the_key() {
+        let tree = make_tree(10_000);
+
+        for key in [0, 1, 1234, 5000, 9999, 10_000] {
+            let mut copy = tree.clone();
+            let split = copy.split_off(&key);
+
+            assert_balanced(&copy.root);
+            assert_balanced(&split.root);
+            assert!(copy.keys().copied().eq(0..key));
+            assert!(split.keys().copied().eq(key..10_000));
+            assert_eq!(copy.len(), key);
+            assert_eq!(split.len(), 10_000 - key);
+
+            let copied = copied(&copy.root, &tree.root) + copied(&split.root, &tree.root);
+            assert!(copied <= 4 * height(&tree.root));
+        }
+        assert_eq!(tree.len(), 10_000);
+    }
+
+    #[test]
+    fn updates_values_copying_only_the_paths_to_those_replaced() {
+        let tree = make_tree(10_000);
+        let mut copy = tree.clone();
+
+        copy.update_values(|key, _| (*key % 5000 == 1234).then_some(0));
+        assert!(unshared(&copy.root, &tree.root) <= 2 * height(&tree.root));
+        assert_eq!(copy.get(&1234), Some(&0));
+        assert_eq!(copy.get(&6234), Some(&0));
+        assert_eq!(tree.get(&1234), Some(&12340));
+
+        copy.update_values(|_, _| None);
+        assert!(unshared(&copy.root, &tree.root) <= 2 * height(&tree.root));
+
+        copy.update_values(|_, value| Some(value + 1));
+        assert_eq!(copy.get(&0), Some(&1));
+        assert_eq!(tree.get(&0), Some(&0));
+    }
+
+    #[test]
+    fn inserts_a_key_at_most_once() {
+        let mut tree = make_tree(100);
+
+        tree.insert(5, 0);
+        assert_eq!(tree.len(), 100);
+        assert_eq!(tree.get(&5), Some(&0));
+
+        let (_, value) = tree.get_or_insert_with(7, || unreachable!());
+        assert_eq!(*value, 70);
+        tree.get_or_insert_with(100, || 1000);
+        assert_eq!(tree.len(), 101);
+        assert_balanced(&tree.root);
+    }
+}